@@ -2,10 +2,14 @@
 extern crate criterion;
 
 use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
-use kvs::{KvStore, KvsEngine, SledEngine};
+use kvs::{Codec, CompactionStrategy, DurabilityMode, KvStore, KvStoreConfig, KvsEngine, SledConfig, SledEngine};
 use rand::prelude::*;
 
 use std::iter;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -88,5 +92,425 @@ fn get_bench(c: &mut Criterion) {
     c.bench("get_bench", bench);
 }
 
-criterion_group!(benches, set_bench, get_bench);
-criterion_main!(benches);
\ No newline at end of file
+fn contains_key_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let value = "v".repeat(4096);
+    for key_i in 1..(1 << 12) {
+        store.set(format!("key{}", key_i), value.clone()).unwrap();
+    }
+
+    let get_store = store.clone();
+    let bench = ParameterizedBenchmark::new(
+        "get",
+        move |b, _| {
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                get_store
+                    .get(format!("key{}", rng.gen_range(1, 1 << 12)))
+                    .unwrap();
+            })
+        },
+        iter::once(()),
+    )
+        .sample_size(10)
+        .with_function("contains_key", move |b, _| {
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .contains_key(&format!("key{}", rng.gen_range(1, 1 << 12)))
+                    .unwrap();
+            })
+        });
+    c.bench("contains_key_bench", bench);
+}
+
+fn cache_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, cache_capacity| {
+            let config = KvStoreConfig {
+                cache_capacity: *cache_capacity,
+                ..KvStoreConfig::default()
+            };
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            for key_i in 0..10000 {
+                store.set(format!("key{}", key_i), "v".repeat(256)).unwrap();
+            }
+            let zipf = zipf_cdf(10000, 1.0);
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store.get(format!("key{}", sample_zipf(&zipf, &mut rng))).unwrap();
+            });
+        },
+        vec![None, Some(100), Some(1000)],
+    )
+        .sample_size(10);
+    c.bench("cache_bench", bench);
+}
+
+/// Get the cumulative distribution of Zipf's law with exponent `s` over ranks `0..n`.
+fn zipf_cdf(n: usize, s: f64) -> Vec<f64> {
+    let weights = (1..=n).map(|rank| 1.0 / (rank as f64).powf(s)).collect::<Vec<_>>();
+    let total: f64 = weights.iter().sum();
+    weights
+        .iter()
+        .scan(0.0, |acc, weight| {
+            *acc += weight / total;
+            Some(*acc)
+        })
+        .collect()
+}
+
+/// Sample the rank by the cumulative distribution, the rank 0 is the most frequent.
+fn sample_zipf(cdf: &[f64], rng: &mut impl Rng) -> usize {
+    let p: f64 = rng.gen();
+    match cdf.binary_search_by(|probe| probe.partial_cmp(&p).unwrap()) {
+        Ok(rank) | Err(rank) => rank.min(cdf.len() - 1),
+    }
+}
+
+fn codec_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, codec| {
+            let config = KvStoreConfig {
+                codec: Some(*codec),
+                ..KvStoreConfig::default()
+            };
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |temp_dir| fill_store(temp_dir.path(), config.clone()),
+                BatchSize::SmallInput,
+            )
+        },
+        vec![Codec::Json, Codec::Bincode],
+    )
+        .sample_size(10);
+    c.bench("codec_bench", bench);
+}
+
+fn point_read_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, codec| {
+            let config = KvStoreConfig {
+                codec: Some(*codec),
+                ..KvStoreConfig::default()
+            };
+            let temp_dir = TempDir::new().unwrap();
+            fill_store(temp_dir.path(), config.clone());
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store.get(format!("key{}", rng.gen_range(0, 10000))).unwrap();
+            })
+        },
+        vec![Codec::Json, Codec::Bincode],
+    )
+        .sample_size(10);
+    c.bench("point_read_bench", bench);
+}
+
+fn fill_store(path: &Path, config: KvStoreConfig) {
+    let mut store = KvStore::open_with_config(path, config).unwrap();
+    for i in 0..10000 {
+        store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+}
+
+fn compaction_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, compaction| {
+            let config = KvStoreConfig {
+                records_limit: 1000,
+                max_active_bytes: Some(64 * 1024),
+                compaction: *compaction,
+                ..KvStoreConfig::default()
+            };
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |temp_dir| write_load(temp_dir.path(), config.clone()),
+                BatchSize::SmallInput,
+            )
+        },
+        vec![
+            CompactionStrategy::Full,
+            CompactionStrategy::Tiered {
+                min_files: 4,
+                max_file_bytes: 256 * 1024,
+            },
+        ],
+    )
+        .sample_size(10);
+    c.bench("compaction_bench", bench);
+}
+
+/// Write a long sequence of new keys and overwrites of recent ones.
+/// Returns the number of bytes rewritten by compactions.
+fn write_load(path: &Path, config: KvStoreConfig) -> u64 {
+    let store = KvStore::open_with_config(path, config).unwrap();
+    for i in 0..50000 {
+        let key = if i % 2 == 0 { i } else { i - i % 100 };
+        store.set(format!("key{}", key), format!("value{}", i)).unwrap();
+    }
+    store.stats().compacted_bytes
+}
+
+fn concurrent_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "sled",
+        |b, threads| {
+            let temp_dir = TempDir::new().unwrap();
+            let db = SledEngine::open(temp_dir.path()).unwrap();
+            b.iter(|| {
+                let db = db.clone();
+                mixed_load(*threads, move |key, is_set| {
+                    if is_set {
+                        db.set(key, "value".to_string()).unwrap();
+                    } else {
+                        db.get(key).unwrap();
+                    }
+                })
+            })
+        },
+        vec![8],
+    )
+        .sample_size(10)
+        .with_function("sled_serialized", |b, threads| {
+            let temp_dir = TempDir::new().unwrap();
+            let db = Arc::new(Mutex::new(SledEngine::open(temp_dir.path()).unwrap()));
+            b.iter(|| {
+                let db = Arc::clone(&db);
+                mixed_load(*threads, move |key, is_set| {
+                    let db = db.lock().unwrap();
+                    if is_set {
+                        db.set(key, "value".to_string()).unwrap();
+                    } else {
+                        db.get(key).unwrap();
+                    }
+                })
+            })
+        });
+    c.bench("concurrent_bench", bench);
+}
+
+fn concurrent_set_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, threads| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = KvStoreConfig {
+                records_limit: 10000,
+                ..KvStoreConfig::default()
+            };
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            b.iter(|| {
+                // Every thread overwrites its own keys, so only the counter of unused records is shared
+                let handles = (0..*threads)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            for i in 0..1000 {
+                                store.set(format!("key{}_{}", thread_id, i % 100), "value".to_string()).unwrap();
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        },
+        vec![1, 2, 4, 8],
+    )
+        .sample_size(10);
+    c.bench("concurrent_set_bench", bench);
+}
+
+fn index_shards_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, index_shards| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = KvStoreConfig {
+                records_limit: 100000,
+                index_shards: *index_shards,
+                ..KvStoreConfig::default()
+            };
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            b.iter(|| {
+                // Every thread writes and reads its own distinct keys
+                let handles = (0..8)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            for i in 0..1000 {
+                                let key = format!("key{}_{}", thread_id, i);
+                                store.set(key.clone(), "value".to_string()).unwrap();
+                                store.get(key).unwrap();
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        },
+        // The single map against the default sharding
+        vec![1, 16],
+    )
+        .sample_size(10);
+    c.bench("index_shards_bench", bench);
+}
+
+fn group_commit_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, group_commit| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = KvStoreConfig {
+                records_limit: 10000,
+                durability: DurabilityMode::FsyncEveryWrite,
+                group_commit: *group_commit,
+                ..KvStoreConfig::default()
+            };
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            b.iter(|| {
+                let handles = (0..8)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            for i in 0..100 {
+                                store.set(format!("key{}_{}", thread_id, i), "value".to_string()).unwrap();
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        },
+        // Sync per write against syncs shared by 8 writers
+        vec![None, Some(Duration::from_micros(200))],
+    )
+        .sample_size(10);
+    c.bench("group_commit_bench", bench);
+}
+
+fn bulk_load_bench(c: &mut Criterion) {
+    const KEYS: usize = 1_000_000;
+    let open_store = || {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        (temp_dir, store)
+    };
+    let bench = ParameterizedBenchmark::new(
+        "set",
+        move |b, _| {
+            b.iter_batched(
+                open_store,
+                |(temp_dir, store)| {
+                    for i in 0..KEYS {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                    // Returned to be dropped outside of the measurement, dropping compacts the log
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        },
+        iter::once(()),
+    )
+        .with_function("bulk_load", move |b, _| {
+            b.iter_batched(
+                open_store,
+                |(temp_dir, store)| {
+                    let pairs = (0..KEYS)
+                        .map(|i| (format!("key{}", i), "value".to_string()))
+                        .collect::<Vec<_>>();
+                    store.bulk_load(pairs).unwrap();
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        })
+        .sample_size(10);
+    c.bench("bulk_load_bench", bench);
+}
+
+fn sled_flush_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "sled",
+        |b, config: &SledConfig| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let engine = SledEngine::open_with(temp_dir.path(), config.clone()).unwrap();
+                    (temp_dir, engine)
+                },
+                |(temp_dir, engine)| {
+                    for i in 0..10000 {
+                        engine.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                    (temp_dir, engine)
+                },
+                BatchSize::PerIteration,
+            )
+        },
+        vec![
+            // Flush on every write
+            SledConfig::default(),
+            // Periodic flushes in the background
+            SledConfig {
+                durability: DurabilityMode::NoSync,
+                flush_every_ms: Some(500),
+                ..SledConfig::default()
+            },
+        ],
+    )
+        .sample_size(10);
+    c.bench("sled_flush_bench", bench);
+}
+
+/// Run `threads` threads doing interleaved sets and gets by `op(key, is_set)`.
+fn mixed_load<F>(threads: usize, op: F)
+where
+    F: Fn(String, bool) + Send + Sync + 'static,
+{
+    let op = Arc::new(op);
+    let handles = (0..threads)
+        .map(|thread_id| {
+            let op = Arc::clone(&op);
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    op(format!("key{}_{}", thread_id, i % 100), i % 2 == 0);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    contains_key_bench,
+    cache_bench,
+    codec_bench,
+    point_read_bench,
+    compaction_bench,
+    concurrent_bench,
+    concurrent_set_bench,
+    index_shards_bench,
+    group_commit_bench,
+    bulk_load_bench,
+    sled_flush_bench
+);
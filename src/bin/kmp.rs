@@ -6,8 +6,8 @@ use simplelog::*;
 use structopt::StructOpt;
 use std::str::FromStr;
 
-use kvs::protocol::{ProtocolError, Response};
-use kvs::{Client, KvError};
+use kvs::protocol::{ProtocolError, Response, ResponseError};
+use kvs::{Client, KvError, Session};
 
 const DEFAULT_SERVER_ADDRESS: &'static str = "127.0.0.1:4000";
 
@@ -53,12 +53,13 @@ fn get(client: Client, key: String) -> Result<(), ProtocolError> {
             error!("{}", e);
             exit(-1);
         }
+        unexpected => return Err(format!("Unexpected response: {:?}", unexpected).into()),
     }
     Ok(())
 }
 
-fn set(client: &Client, key: String, value: String) -> Result<(), ProtocolError> {
-    let response = client.set(key, value)?;
+fn set(session: &mut Session, key: String, value: String) -> Result<(), ProtocolError> {
+    let response = session.set(key, value)?;
     debug!("Response: {:?}", response);
     if let Response::Err(e) = response {
         error!("{}", e);
@@ -72,25 +73,32 @@ fn rm(client: Client, key: String) -> Result<(), ProtocolError> {
     debug!("Response: {:?}", response);
     match response {
         Response::Ok(_) => Ok(()),
+        Response::NotFound | Response::Err(ResponseError::KeyNotFound) => {
+            error!("{}", KvError::KeyNotFound);
+            eprintln!("{}", KvError::KeyNotFound);
+            exit(1);
+        }
         Response::Err(what) => {
-            if what == format!("{}", KvError::KeyNotFound) {
-                error!("{}", KvError::KeyNotFound);
-                eprintln!("{}", KvError::KeyNotFound);
-                exit(1);
-            } else {
-                error!("{}", what);
-                exit(-3);
-            }
+            error!("{}", what);
+            exit(-3);
         }
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
     }
 }
 
 fn main() {
     let server_addr = SocketAddr::from_str(DEFAULT_SERVER_ADDRESS).unwrap();
     let client = Client::new(server_addr);
+    let mut session = match client.connect() {
+        Ok(session) => session,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
     for i in 0..=1024 {
-        if let Err(e) = set(&client, "foo".to_string(), "bar".to_string()) {
+        if let Err(e) = set(&mut session, "foo".to_string(), "bar".to_string()) {
             println!("#{}: {}", i, e);
             break;
         }
@@ -1,12 +1,17 @@
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Instant;
 
 use log::{debug, error};
+use serde::Serialize;
 use simplelog::*;
+use structopt::clap::arg_enum;
 use structopt::StructOpt;
 
-use kvs::protocol::{ProtocolError, Response};
-use kvs::{Client, KvError};
+use kvs::protocol::{Address, AdminOp, ProtocolError, Response, ResponseError};
+use kvs::{Client, KvError, KvStats};
 
 const DEFAULT_SERVER_ADDRESS: &'static str = "127.0.0.1:4000";
 
@@ -21,6 +26,14 @@ struct ClientArgs {
         parse(try_from_str))]
     addr: SocketAddr,
 
+    /// Path of UNIX domain socket of the server, overrides the address
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        global = true,
+        parse(from_os_str))]
+    socket: Option<PathBuf>,
+
     #[structopt(
         short,
         long,
@@ -29,59 +42,399 @@ struct ClientArgs {
         parse(try_from_str))]
     logging: LevelFilter,
 
+    /// Format of printed results, `json` prints one object per command
+    #[structopt(
+        short,
+        long,
+        global = true,
+        default_value = "text",
+        possible_values = &Output::variants(),
+        case_insensitive = true)]
+    output: Output,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
 
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Output {
+        Text,
+        Json,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum AdminCommand {
+        Compact,
+        Rotate,
+        Stats,
+        Clear,
+    }
+}
+
+impl From<AdminCommand> for AdminOp {
+    fn from(command: AdminCommand) -> AdminOp {
+        match command {
+            AdminCommand::Compact => AdminOp::Compact,
+            AdminCommand::Rotate => AdminOp::Rotate,
+            AdminCommand::Stats => AdminOp::Stats,
+            AdminCommand::Clear => AdminOp::Clear,
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 enum Command {
     Get { key: String },
+    Mget { keys: Vec<String> },
     Set { key: String, value: String },
+    Setnx { key: String, value: String },
     Rm { key: String },
+    Scan {
+        #[structopt(long)]
+        prefix: Option<String>,
+        #[structopt(long)]
+        limit: Option<usize>,
+    },
+    Compact,
+    Stats,
+    /// Check that every live key refers to the record of its value
+    Verify,
+    Metrics,
+    Ping,
+    /// Apply the admin operation authorized by the secret token of the server
+    Admin {
+        #[structopt(possible_values = &AdminCommand::variants(), case_insensitive = true)]
+        op: AdminCommand,
+        /// File with the secret token of the server, it is never passed on the command line
+        #[structopt(long, parse(from_os_str))]
+        token_file: PathBuf,
+    },
 }
 
-fn get(client: Client, key: String) -> Result<(), ProtocolError> {
+/// Result of the command printed in the JSON output mode.
+/// Errors are typed, e.g. `"KeyNotFound"`, so a stored value is never confused with a miss.
+#[derive(Serialize, Debug)]
+struct JsonOutput<T: Serialize> {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+}
+
+fn print_json<T: Serialize>(json_output: JsonOutput<T>) {
+    match serde_json::to_string(&json_output) {
+        Ok(line) => println!("{}", line),
+        Err(e) => error!("Error while serializing output: {}", e),
+    }
+}
+
+/// Print the successful result in the JSON output mode, `value` is omitted if it is `None`.
+fn print_json_value<T: Serialize>(value: Option<T>) {
+    print_json(JsonOutput { ok: true, value, error: None })
+}
+
+/// Exit codes of the client, they are stable, so scripts can rely on them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ClientExit {
+    /// The key to remove is not found.
+    KeyNotFound = 1,
+    /// The server is unreachable or the connection is broken.
+    ConnectionError = 2,
+    /// The response is malformed or unexpected, or the request is rejected as invalid or unauthorized.
+    ProtocolError = 3,
+    /// The engine of the server failed to apply the command.
+    EngineError = 4,
+}
+
+impl ClientExit {
+    /// Name of the failure in the error line printed to stderr.
+    fn name(self) -> &'static str {
+        match self {
+            ClientExit::KeyNotFound => "key_not_found",
+            ClientExit::ConnectionError => "connection_error",
+            ClientExit::ProtocolError => "protocol_error",
+            ClientExit::EngineError => "engine_error",
+        }
+    }
+}
+
+impl From<&ResponseError> for ClientExit {
+    fn from(error: &ResponseError) -> ClientExit {
+        match error {
+            ResponseError::KeyNotFound => ClientExit::KeyNotFound,
+            ResponseError::InvalidRequest(_) | ResponseError::Unauthorized => ClientExit::ProtocolError,
+            ResponseError::Other(_) => ClientExit::EngineError,
+        }
+    }
+}
+
+impl From<&ProtocolError> for ClientExit {
+    fn from(error: &ProtocolError) -> ClientExit {
+        match error {
+            ProtocolError::IoError(_)
+            | ProtocolError::Timeout
+            | ProtocolError::Disconnected
+            | ProtocolError::ConnectFailed { .. } => ClientExit::ConnectionError,
+            _ => ClientExit::ProtocolError,
+        }
+    }
+}
+
+/// Exit with the code of the failure `exit_code` after printing `error` to stderr
+/// as one line `error: <name>: <message>`, it is also printed in the JSON output mode.
+fn fail(output: Output, exit_code: ClientExit, error: ResponseError) -> ! {
+    eprintln!("error: {}: {}", exit_code.name(), error);
+    if output == Output::Json {
+        print_json::<()>(JsonOutput { ok: false, value: None, error: Some(error) });
+    }
+    exit(exit_code as i32)
+}
+
+/// Exit on the error response of the server.
+fn fail_response(output: Output, error: ResponseError) -> ! {
+    fail(output, ClientExit::from(&error), error)
+}
+
+fn get(client: Client, output: Output, key: String) -> Result<(), ProtocolError> {
     let response = client.get(key)?;
     debug!("Response: {:?}", response);
     match response {
-        Response::Ok(option_value) => match option_value {
-            Some(value) => println!("{}", value),
-            None => println!("{}", KvError::KeyNotFound),
+        Response::Ok(option_value) => match (output, option_value) {
+            (Output::Json, Some(value)) => print_json_value(Some(value)),
+            (Output::Json, None) => print_json::<()>(JsonOutput {
+                ok: false,
+                value: None,
+                error: Some(ResponseError::KeyNotFound),
+            }),
+            (Output::Text, Some(value)) => println!("{}", value),
+            (Output::Text, None) => println!("{}", KvError::KeyNotFound),
         },
-        Response::Err(e) => {
-            error!("{}", e);
-            exit(-1);
-        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => return Err(format!("Unexpected response: {:?}", unexpected).into()),
     }
     Ok(())
 }
 
-fn set(client: Client, key: String, value: String) -> Result<(), ProtocolError> {
+fn mget(client: Client, output: Output, keys: Vec<String>) -> Result<(), ProtocolError> {
+    let response = client.mget(keys)?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Values(values) => {
+            if output == Output::Json {
+                print_json_value(Some(values));
+                return Ok(());
+            }
+            for value in values {
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("{}", KvError::KeyNotFound),
+                }
+            }
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn set(client: Client, output: Output, key: String, value: String) -> Result<(), ProtocolError> {
     let response = client.set(key, value)?;
     debug!("Response: {:?}", response);
     if let Response::Err(e) = response {
-        error!("{}", e);
-        exit(-2);
+        fail_response(output, e);
+    }
+    if output == Output::Json {
+        print_json_value::<()>(None);
     }
     Ok(())
 }
 
-fn rm(client: Client, key: String) -> Result<(), ProtocolError> {
+fn setnx(client: Client, output: Output, key: String, value: String) -> Result<(), ProtocolError> {
+    let response = client.setnx(key, value)?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Bool(is_set) => {
+            match output {
+                Output::Json => print_json_value(Some(is_set)),
+                Output::Text => println!("{}", is_set),
+            }
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn rm(client: Client, output: Output, key: String) -> Result<(), ProtocolError> {
     let response = client.rm(key)?;
     debug!("Response: {:?}", response);
     match response {
-        Response::Ok(_) => Ok(()),
-        Response::Err(what) => {
-            if what == format!("{}", KvError::KeyNotFound) {
-                error!("{}", KvError::KeyNotFound);
-                eprintln!("{}", KvError::KeyNotFound);
-                exit(1);
-            } else {
-                error!("{}", what);
-                exit(-3);
+        Response::Ok(_) => {
+            if output == Output::Json {
+                print_json_value::<()>(None);
+            }
+            Ok(())
+        }
+        Response::NotFound => fail_response(output, ResponseError::KeyNotFound),
+        Response::Err(what) => fail_response(output, what),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn scan(client: Client, output: Output, prefix: Option<String>, limit: Option<usize>) -> Result<(), ProtocolError> {
+    let response = client.scan(prefix, limit)?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Keys(keys) => {
+            if output == Output::Json {
+                print_json_value(Some(keys));
+                return Ok(());
+            }
+            for key in keys {
+                println!("{}", key);
+            }
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn compact(client: Client, output: Output) -> Result<(), ProtocolError> {
+    let response = client.compact()?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Ok(_) => {
+            if output == Output::Json {
+                print_json_value::<()>(None);
             }
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn stats(client: Client, output: Output) -> Result<(), ProtocolError> {
+    let response = client.stats()?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Stats(stats) => {
+            print_stats(output, stats);
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn print_stats(output: Output, stats: KvStats) {
+    if output == Output::Json {
+        print_json_value(Some(stats));
+        return;
+    }
+    println!("Live keys: {}", stats.live_keys);
+    println!("Records: {}", stats.records);
+    println!("Unused records: {}", stats.unused_records);
+    println!("Passive files: {}", stats.passive_files);
+    println!("Compactions: {}", stats.compactions);
+    println!("Compacted bytes: {}", stats.compacted_bytes);
+    println!("Syncs: {}", stats.syncs);
+    println!("Reclaimable bytes: {}", stats.reclaimable_bytes);
+    println!("Cache hits: {}", stats.cache_hits);
+    println!("Cache misses: {}", stats.cache_misses);
+}
+
+fn verify(client: Client, output: Output) -> Result<(), ProtocolError> {
+    let response = client.verify()?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Verify(report) => {
+            if output == Output::Json {
+                print_json_value(Some(report));
+                return Ok(());
+            }
+            println!("Datafiles: {}", report.datafiles);
+            println!("Records: {}", report.records);
+            println!("Keys: {}", report.keys);
+            for path in &report.corrupted {
+                println!("Corrupted datafile: {}", path.display());
+            }
+            for key in &report.inconsistent {
+                println!("Inconsistent key: {}", key);
+            }
+            println!("{}", if report.is_ok() { "OK" } else { "FAILED" });
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn metrics(client: Client, output: Output) -> Result<(), ProtocolError> {
+    let response = client.metrics()?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Text(text) => {
+            match output {
+                Output::Json => print_json_value(Some(text)),
+                Output::Text => print!("{}", text),
+            }
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+fn ping(client: Client, output: Output) -> Result<(), ProtocolError> {
+    let start = Instant::now();
+    client.ping()?;
+    match output {
+        // Round-trip time in milliseconds
+        Output::Json => print_json_value(Some(start.elapsed().as_secs_f64() * 1000.0)),
+        Output::Text => println!("Pong in {:?}", start.elapsed()),
+    }
+    Ok(())
+}
+
+fn admin(client: Client, output: Output, op: AdminCommand, token_file: PathBuf) -> Result<(), ProtocolError> {
+    // The token is trimmed like the server does with its token file
+    let token = fs::read_to_string(&token_file)
+        .map_err(|e| format!("Unable to read token file {}: {}", token_file.display(), e))?
+        .trim()
+        .to_owned();
+    let response = client.admin(token, op.into())?;
+    debug!("Response: {:?}", response);
+    match response {
+        Response::Ok(_) => {
+            if output == Output::Json {
+                print_json_value::<()>(None);
+            }
+            Ok(())
+        }
+        Response::Stats(stats) => {
+            print_stats(output, stats);
+            Ok(())
+        }
+        Response::Err(e) => fail_response(output, e),
+        unexpected => Err(format!("Unexpected response: {:?}", unexpected).into()),
+    }
+}
+
+/// Get the address of the server, the UNIX domain socket is preferred.
+fn server_address(args: &ClientArgs) -> Address {
+    #[cfg(unix)]
+    {
+        if let Some(path) = &args.socket {
+            return Address::Unix(path.clone());
         }
     }
+    Address::Tcp(args.addr)
 }
 
 fn main() {
@@ -89,18 +442,27 @@ fn main() {
     TermLogger::init(log_filter, Config::default(), TerminalMode::Stderr)
         .expect("Error while initializing of TermLogger");
 
-    let server_addr = ClientArgs::from_args().addr;
+    let server_addr = server_address(&ClientArgs::from_args());
     let client = Client::new(server_addr);
 
+    let output = ClientArgs::from_args().output;
     let cmd = ClientArgs::from_args().cmd;
     let res = match cmd {
-        Command::Get { key } => get(client, key),
-        Command::Set { key, value } => set(client, key, value),
-        Command::Rm { key } => rm(client, key),
+        Command::Get { key } => get(client, output, key),
+        Command::Mget { keys } => mget(client, output, keys),
+        Command::Set { key, value } => set(client, output, key, value),
+        Command::Setnx { key, value } => setnx(client, output, key, value),
+        Command::Rm { key } => rm(client, output, key),
+        Command::Scan { prefix, limit } => scan(client, output, prefix, limit),
+        Command::Compact => compact(client, output),
+        Command::Stats => stats(client, output),
+        Command::Verify => verify(client, output),
+        Command::Metrics => metrics(client, output),
+        Command::Ping => ping(client, output),
+        Command::Admin { op, token_file } => admin(client, output, op, token_file),
     };
 
     if let Err(e) = res {
-        error!("{}", e);
-        exit(-4);
+        fail(output, ClientExit::from(&e), ResponseError::Other(e.to_string()));
     }
 }
@@ -1,29 +1,46 @@
 use std::env;
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use simplelog::*;
-use structopt::clap::arg_enum;
 use structopt::StructOpt;
 
-use kvs::Server;
-use kvs::{KvStore, KvsEngine, SledEngine};
-use kvs::thread_pool::{ThreadPool, QueueThreadPool, RayonThreadPool};
-
-const DEFAULT_ADDRESS: &'static str = "127.0.0.1:4000";
-const ENGINE_PATH: &'static str = "engine";
+use kvs::protocol::Address;
+use kvs::{Server, ServerConfig};
+use kvs::{
+    migrate, reconcile_engine_file, DurabilityMode, EngineKind, KvError, KvStore, KvStoreConfig, KvsEngine, SledEngine,
+};
+#[cfg(feature = "memory")]
+use kvs::MemoryEngine;
+use kvs::thread_pool::{ThreadPool, ThreadPoolKind, NaiveThreadPool, QueueThreadPool, RayonThreadPool};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-server")]
 struct ServerArgs {
+    /// TOML file with the configuration, flags override its settings
+    #[structopt(
+        long,
+        parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, repeat the flag to listen on several addresses [default: 127.0.0.1:4000]
     #[structopt(
         short,
         long,
-        default_value = DEFAULT_ADDRESS,
+        number_of_values = 1,
         parse(try_from_str))]
-    addr: SocketAddr,
+    addr: Vec<SocketAddr>,
+
+    /// Path of UNIX domain socket to listen on instead of the address
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        parse(from_os_str))]
+    socket: Option<PathBuf>,
 
     #[structopt(
         short,
@@ -32,66 +49,123 @@ struct ServerArgs {
         parse(try_from_str))]
     logging: LevelFilter,
 
+    /// Storage engine [default: kvs]
     #[structopt(
         short,
         long,
-        default_value = "kvs",
-        possible_values = &Engine::variants(),
+        possible_values = EngineKind::VARIANTS,
         case_insensitive = true)]
-    engine: Engine,
+    engine: Option<EngineKind>,
+
+    /// Thread pool serving clients [default: rayon]
+    #[structopt(
+        short,
+        long,
+        possible_values = ThreadPoolKind::VARIANTS,
+        case_insensitive = true)]
+    thread_pool: Option<ThreadPoolKind>,
+
+    /// Number of threads serving clients [default: 8]
+    #[structopt(long)]
+    threads: Option<u32>,
+
+    /// When records are synced to disk: no-sync, every-write or every-N [default: mode of the engine]
+    #[structopt(long)]
+    durability: Option<DurabilityMode>,
+
+    /// Max number of unused records of kvs engine before compaction
+    #[structopt(long)]
+    records_limit: Option<u64>,
+
+    /// Max number of connections served at once, further ones wait until served ones are closed [default: unlimited]
+    #[structopt(long)]
+    max_connections: Option<usize>,
+
+    /// File with the secret authorizing admin requests [default: admin requests are rejected]
+    #[structopt(
+        long,
+        parse(from_os_str))]
+    admin_token_file: Option<PathBuf>,
+
+    /// Compact the storage on start if it has reclaimable records, e.g. after the unclean shutdown
+    #[structopt(long)]
+    compact_on_start: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Copy all keys of the storage to the fresh storage powered by another engine instead of serving
+    Migrate {
+        /// Engine of the source storage
+        #[structopt(
+            long,
+            possible_values = EngineKind::VARIANTS,
+            case_insensitive = true)]
+        from: EngineKind,
+
+        /// Engine of the destination storage
+        #[structopt(
+            long,
+            possible_values = EngineKind::VARIANTS,
+            case_insensitive = true)]
+        to: EngineKind,
+
+        /// Directory of the source storage [default: current directory]
+        #[structopt(
+            long,
+            parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// Directory of the destination storage, it must be empty or absent.
+        /// TTLs of keys are not migrated, such keys never expire in the destination storage
+        #[structopt(
+            long,
+            parse(from_os_str))]
+        dest: PathBuf,
+    },
 }
 
-arg_enum! {
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    enum Engine {
-        Kvs,
-        Sled,
+impl ServerArgs {
+    /// Get the configuration set by flags, unset flags are `None`.
+    fn flags_config(&self) -> ServerConfig {
+        ServerConfig {
+            addr: if self.addr.is_empty() { None } else { Some(self.addr.clone()) },
+            engine: self.engine,
+            thread_pool: self.thread_pool,
+            threads: self.threads,
+            durability: self.durability,
+            records_limit: self.records_limit,
+            max_connections: self.max_connections,
+            admin_token_file: self.admin_token_file.clone(),
+        }
     }
 }
 
-/// Read current engine from engine_file
-fn current_engine<T>(engine_file: T) -> Option<Engine>
-where
-    T: Into<std::path::PathBuf>,
-{
-    let engine_file = engine_file.into();
-    if !engine_file.exists() {
-        return None;
-    }
-
-    Some(
-        std::fs::read_to_string(engine_file)
-            .expect("Error reading from engine file")
-            .parse()
-            .expect("The content of engine file is invalid"),
-    )
+/// Check if the engine powers the storage directory, the in-memory engine leaves nothing in it.
+fn is_persistent(engine: EngineKind) -> bool {
+    #[cfg(feature = "memory")]
+    {
+        engine != EngineKind::Memory
+    }
+    #[cfg(not(feature = "memory"))]
+    {
+        let _ = engine;
+        true
+    }
 }
 
-/// Compare chosen engine with engine in engine_file.
-/// Exit with error if they are differ.
-/// Write chosen engine to engine_file if there no engine_file.
-fn process_engine_file<T>(dir_path: T, chosen_engine: Engine)
-where
-    T: Into<std::path::PathBuf>,
-{
-    let engine_file = dir_path.into().join(ENGINE_PATH);
-    match current_engine(&engine_file) {
-        Some(engine) => {
-            if engine != chosen_engine {
-                error!(
-                    "Storage directory is already powered by other engine: {}, new one: {}",
-                    engine, chosen_engine
-                );
-                exit(-1);
-            }
-            debug!("Engine file: {}", engine);
-        }
-        None => {
-            debug!("Set new engine: {}", chosen_engine);
-            std::fs::write(&engine_file, format!("{}", chosen_engine))
-                .expect("Error writing to engine file");
+/// Get addresses to listen on, the UNIX domain socket is preferred.
+fn listen_addresses(args: &ServerArgs, config: &ServerConfig) -> Vec<Address> {
+    #[cfg(unix)]
+    {
+        if let Some(path) = &args.socket {
+            return vec![Address::Unix(path.clone())];
         }
     }
+    config.addrs().into_iter().map(Address::Tcp).collect()
 }
 
 fn main() {
@@ -100,31 +174,187 @@ fn main() {
     TermLogger::init(args.logging, Config::default(), TerminalMode::Stderr)
         .expect("Error while initializing of TermLogger");
 
-    debug!("Conf: {:?}", args);
+    if let Some(Command::Migrate { from, to, dir, dest }) = &args.command {
+        let dir = dir.clone().unwrap_or_else(|| env::current_dir().expect("Can not get current directory"));
+        match migrate_storage(*from, *to, &dir, dest) {
+            Ok(migrated) => info!("Migrated {} keys from {} to {}", migrated, from, to),
+            Err(e) => {
+                error!("Can not migrate storage: {}", e);
+                exit(-1);
+            }
+        }
+        return;
+    }
+
+    let file_config = match &args.config {
+        Some(path) => match ServerConfig::from_file(path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Can not read config {}: {}", path.display(), e);
+                exit(-1);
+            }
+        },
+        None => ServerConfig::default(),
+    };
+    let config = file_config.overridden_by(args.flags_config());
+
+    debug!("Args: {:?}", args);
+    debug!("Conf: {:?}", config);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Storage engine: {}", args.engine);
-    info!("Listening on {}", args.addr);
+    info!("Storage engine: {}", config.engine());
+    info!("Thread pool: {}, threads: {}", config.thread_pool(), config.threads());
+    if let Some(durability) = config.durability {
+        info!("Durability: {}", durability);
+    }
+    let addrs = listen_addresses(&args, &config);
+    for addr in &addrs {
+        info!("Listening on {}", addr);
+    }
 
     let current_dir = env::current_dir()
         .expect("Can not get current directory");
 
-    process_engine_file(&current_dir, args.engine);
+    let engine = config.engine();
+    if is_persistent(engine) {
+        if let Err(e) = reconcile_engine_file(&current_dir, engine) {
+            error!("{}", e);
+            exit(-1);
+        }
+    }
+
+    if args.compact_on_start && engine != EngineKind::Kvs {
+        info!("Engine {} compacts itself, compaction on start is skipped", engine);
+    }
+
+    match engine {
+        EngineKind::Kvs => {
+            let mut kv_config = KvStoreConfig {
+                compact_on_open: args.compact_on_start,
+                ..KvStoreConfig::default()
+            };
+            if let Some(durability) = config.durability {
+                kv_config.durability = durability;
+            }
+            if let Some(records_limit) = config.records_limit {
+                kv_config.records_limit = records_limit;
+            }
+            run_with_pool(addrs, &config, move || KvStore::open_with_config(current_dir, kv_config))
+        }
+        EngineKind::Sled => {
+            let durability = config.durability;
+            run_with_pool(addrs, &config, move || match durability {
+                Some(durability) => SledEngine::open_with_durability(current_dir, durability),
+                None => SledEngine::open(current_dir),
+            })
+        }
+        #[cfg(feature = "memory")]
+        EngineKind::Memory => run_with_pool(addrs, &config, move || MemoryEngine::open(current_dir)),
+    }
+}
+
+/// Copy all keys of the storage powered by `from` in `dir` to the storage powered by `to` in `dest`.
+/// The destination directory is removed on error, the empty one is left in place.
+/// # Error
+/// It returns `KvError::NotEmpty` if `dest` has any files.
+fn migrate_storage(from: EngineKind, to: EngineKind, dir: &Path, dest: &Path) -> kvs::Result<usize> {
+    if !is_persistent(from) || !is_persistent(to) {
+        return Err(KvError::InvalidConfig("in-memory engine has no storage to migrate".to_owned()));
+    }
+    reconcile_engine_file(dir, from)?;
+    let dest_exists = dest.exists();
+    if dest_exists && fs::read_dir(dest)?.next().is_some() {
+        return Err(KvError::NotEmpty);
+    }
+    fs::create_dir_all(dest)?;
+    let result = reconcile_engine_file(dest, to).and_then(|_| match from {
+        EngineKind::Kvs => migrate_to(&KvStore::open(dir)?, to, dest),
+        EngineKind::Sled => migrate_to(&SledEngine::open(dir)?, to, dest),
+        #[cfg(feature = "memory")]
+        EngineKind::Memory => unreachable!(),
+    });
+    if result.is_err() {
+        if let Err(e) = clean_dest(dest, dest_exists) {
+            warn!("Can not clean up the destination directory {:?}: {}", dest, e);
+        }
+    }
+    result
+}
+
+/// Remove the partially migrated storage in `dest`, the directory itself is kept if it `existed` before.
+fn clean_dest(dest: &Path, existed: bool) -> io::Result<()> {
+    fs::remove_dir_all(dest)?;
+    if existed {
+        fs::create_dir(dest)?;
+    }
+    Ok(())
+}
 
-    match args.engine {
-        Engine::Kvs => run::<KvStore, RayonThreadPool>(args.addr, current_dir),
-        Engine::Sled => run::<SledEngine, RayonThreadPool>(args.addr, current_dir),
+fn migrate_to<T: KvsEngine>(src: &T, to: EngineKind, dest: &Path) -> kvs::Result<usize> {
+    match to {
+        EngineKind::Kvs => migrate(src, &KvStore::open(dest)?),
+        EngineKind::Sled => migrate(src, &SledEngine::open(dest)?),
+        #[cfg(feature = "memory")]
+        EngineKind::Memory => unreachable!(),
     }
 }
 
-fn run<T: KvsEngine, P: ThreadPool>(addr: SocketAddr, dir_path: PathBuf) {
-    const CORES_NUM : u32 = 8;
-    let thread_pool = P::new(CORES_NUM);
-    let engine = T::open(dir_path)
+fn run_with_pool<T: KvsEngine>(addrs: Vec<Address>, config: &ServerConfig, open: impl FnOnce() -> kvs::Result<T>) {
+    match config.thread_pool() {
+        ThreadPoolKind::Naive => run::<T, NaiveThreadPool>(addrs, open, config),
+        ThreadPoolKind::Queue => run::<T, QueueThreadPool>(addrs, open, config),
+        ThreadPoolKind::Rayon => run::<T, RayonThreadPool>(addrs, open, config),
+    }
+}
+fn run<T: KvsEngine, P: ThreadPool>(addrs: Vec<Address>, open: impl FnOnce() -> kvs::Result<T>, config: &ServerConfig) {
+    let thread_pool = P::new(config.threads());
+    let engine = open()
         .expect("Can not open chosen engine");
 
-    let server = Server::new(addr, thread_pool, engine);
+    #[cfg(unix)]
+    handle_sighup(engine.clone());
+
+    let admin_token = match config.admin_token() {
+        Ok(admin_token) => admin_token,
+        Err(e) => {
+            error!("Can not read admin token: {}", e);
+            exit(-1);
+        }
+    };
+
+    let mut server = Server::with_addrs(addrs, thread_pool, engine);
+    if let Some(admin_token) = admin_token {
+        info!("Admin requests are enabled");
+        server = server.with_admin_token(admin_token);
+    }
+    if let Some(max_connections) = config.max_connections {
+        info!("Max connections: {}", max_connections);
+        server = server.with_max_connections(max_connections);
+    }
+    let shutdown_handle = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        debug!("SIGINT");
+        shutdown_handle.shutdown();
+    })
+    .expect("Error setting SIGINT handler");
+
     if let Err(e) = server.run() {
         error!("{}", e);
         exit(-1);
     }
-}
\ No newline at end of file
+}
+
+/// Rotate the log of `engine` on SIGHUP, e.g. for log management tools.
+#[cfg(unix)]
+fn handle_sighup<T: KvsEngine>(engine: T) {
+    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGHUP])
+        .expect("Error setting SIGHUP handler");
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            debug!("SIGHUP");
+            match engine.rotate() {
+                Ok(()) => info!("Log is rotated"),
+                Err(e) => error!("Unable to rotate log: {}", e),
+            }
+        }
+    });
+}
@@ -1,30 +1,212 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
-use log::debug;
+use log::{debug, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use crate::protocol::{ProtocolError, Request, Response};
+use crate::protocol::{
+    read_frame, write_chunk, write_frame, write_frame_with, Address, AdminOp, ProtocolError, Request, Response, Stream,
+    CHUNK_SIZE,
+};
+
+/// Entry of the trace log of `Client`: sent request and received response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TraceEntry {
+    pub request: Request,
+    pub response: Response,
+}
 
 pub struct Client {
-    server_addr: SocketAddr,
+    server_addr: Address,
+    trace_log: Option<Mutex<BufWriter<File>>>,
+    connect_timeout: Option<Duration>,
+    io_timeout: Option<Duration>,
+    compression: bool,
+}
+
+/// Builder of `Client` with optional features.
+pub struct ClientBuilder {
+    server_addr: Address,
+    trace_log: Option<PathBuf>,
+    compression: bool,
+}
+
+impl ClientBuilder {
+    pub fn new(server_addr: impl Into<Address>) -> ClientBuilder {
+        ClientBuilder {
+            server_addr: server_addr.into(),
+            trace_log: None,
+            compression: false,
+        }
+    }
+
+    /// Record every sent request and the received response to the file at `path`
+    /// as newline-delimited JSON of `TraceEntry`. Writes are buffered.
+    pub fn with_trace_log(mut self, path: impl Into<PathBuf>) -> ClientBuilder {
+        self.trace_log = Some(path.into());
+        self
+    }
+
+    /// Request compression of frames when connecting, e.g. for large values over slow links.
+    /// Frames are compressed only if the server agrees, streamed values are never compressed.
+    /// It costs one more round trip per connection.
+    pub fn with_compression(mut self) -> ClientBuilder {
+        self.compression = true;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, ProtocolError> {
+        let trace_log = match self.trace_log {
+            Some(path) => {
+                debug!("Trace log: {:?}", path);
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Mutex::new(BufWriter::new(file)))
+            }
+            None => None,
+        };
+
+        Ok(Client {
+            server_addr: self.server_addr,
+            trace_log,
+            connect_timeout: None,
+            io_timeout: None,
+            compression: self.compression,
+        })
+    }
 }
 
 impl Client {
-    pub fn new(server_addr: SocketAddr) -> Client {
-        Client { server_addr }
+    /// Create the client of the server at TCP socket address or at UNIX domain socket.
+    pub fn new(server_addr: impl Into<Address>) -> Client {
+        Client {
+            server_addr: server_addr.into(),
+            trace_log: None,
+            connect_timeout: None,
+            io_timeout: None,
+            compression: false,
+        }
+    }
+
+    /// Create the client failing with `ProtocolError::Timeout` if connecting takes longer than
+    /// `connect_timeout` or a single read or write of the connection takes longer than `read_timeout`.
+    pub fn with_timeout(
+        server_addr: impl Into<Address>,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> Client {
+        Client {
+            server_addr: server_addr.into(),
+            trace_log: None,
+            connect_timeout: Some(connect_timeout),
+            io_timeout: Some(read_timeout),
+            compression: false,
+        }
     }
 
     pub fn send(&self, req: Request) -> Result<Response, ProtocolError> {
         debug!("Request: {:?}", req);
+        self.connect()?.send(req)
+    }
+
+    /// Send `req` reconnecting up to `max_retries` times on connection-level failures,
+    /// the delay before the next attempt starts from `backoff` and is doubled every attempt.
+    /// Error responses of the server are returned as is without retrying.
+    /// Note that the failed request may have been applied, so only idempotent requests
+    /// are safe to retry.
+    pub fn send_with_retries(
+        &self,
+        req: Request,
+        max_retries: u32,
+        backoff: Duration,
+    ) -> Result<Response, ProtocolError> {
+        let mut delay = backoff;
+        for attempt in 0..max_retries {
+            match self.send(req.clone()) {
+                Err(e) if is_connection_error(&e) => {
+                    warn!("Attempt {} of request failed: {}, retry in {:?}", attempt + 1, e, delay);
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                res => return res,
+            }
+        }
+        self.send(req)
+    }
+
+    /// Open the connection to the server for sending multiple requests without reconnecting.
+    /// Compression is negotiated first if it is enabled.
+    pub fn connect(&self) -> Result<Session<'_>, ProtocolError> {
+        let stream = self.open_stream()?;
+        let mut session = Session {
+            client: self,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            compression: false,
+        };
+        if self.compression {
+            session.compression = session.negotiate_compression()?;
+        }
+        Ok(session)
+    }
+
+    /// Open the connection like `connect`, but retry refused and timed out connecting up to `max_attempts`
+    /// attempts in total, e.g. while the server is restarting. The delay after the failed attempt starts
+    /// from `base_delay` and is doubled every attempt, the random jitter shortens it up to a half,
+    /// so clients restarted together don't reconnect in lockstep.
+    /// # Error
+    /// It returns `ProtocolError::ConnectFailed` with the last error if all attempts fail.
+    pub fn connect_with_backoff(&self, max_attempts: u32, base_delay: Duration) -> Result<Session<'_>, ProtocolError> {
+        let mut delay = base_delay;
+        let mut attempt = 1;
+        loop {
+            match self.connect() {
+                Err(e) if is_connection_error(&e) => {
+                    if attempt >= max_attempts {
+                        return Err(ProtocolError::ConnectFailed {
+                            attempts: attempt,
+                            last: Box::new(e),
+                        });
+                    }
+                    let jittered = delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.0));
+                    warn!("Attempt {} of connecting failed: {}, retry in {:?}", attempt, e, jittered);
+                    thread::sleep(jittered);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Connect to the server and set timeouts of the stream.
+    fn open_stream(&self) -> Result<Stream, ProtocolError> {
         debug!("Trying to connect to server at {}", self.server_addr);
-        let stream = TcpStream::connect(self.server_addr)?;
-        let reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
+        let stream = Stream::connect(&self.server_addr, self.connect_timeout)?;
+        stream.set_read_timeout(self.io_timeout)?;
+        stream.set_write_timeout(self.io_timeout)?;
         debug!("Client started at {}", stream.local_addr()?);
-        debug!("Send request: {:?}", req);
-        serde_json::to_writer(&mut writer, &req)?;
-        writer.flush()?;
-        Ok(serde_json::from_reader(reader)?)
+        Ok(stream)
+    }
+
+    /// Write request and response to the trace log if it is enabled.
+    fn trace(&self, request: Request, response: Response) -> Result<Response, ProtocolError> {
+        match &self.trace_log {
+            Some(trace_log) => {
+                let entry = TraceEntry { request, response };
+                let mut writer = trace_log.lock().unwrap();
+                serde_json::to_writer(&mut *writer, &entry)?;
+                writer.write_all(b"\n")?;
+                Ok(entry.response)
+            }
+            None => Ok(response),
+        }
     }
 
     pub fn get(&self, key: String) -> Result<Response, ProtocolError> {
@@ -32,13 +214,271 @@ impl Client {
         self.send(req)
     }
 
+    /// Get values of `keys` by one request, the answer is `Response::Values` in the same order.
+    pub fn mget(&self, keys: Vec<String>) -> Result<Response, ProtocolError> {
+        self.send(Request::MGet(keys))
+    }
+
+    pub fn exists(&self, key: String) -> Result<Response, ProtocolError> {
+        let req = Request::Exists { key };
+        self.send(req)
+    }
+
     pub fn set(&self, key: String, value: String) -> Result<Response, ProtocolError> {
         let req = Request::Set { key, value };
         self.send(req)
     }
 
+    /// Set the value only if `key` is absent, the answer is `Response::Bool` telling if it is set.
+    pub fn setnx(&self, key: String, value: String) -> Result<Response, ProtocolError> {
+        self.send(Request::SetNx { key, value })
+    }
+
+    /// Set the value of `len` bytes read from `reader`.
+    /// The value is streamed to the server in chunks, so it is never buffered entirely.
+    /// If reading fails or `reader` ends before `len` bytes, the transfer is aborted
+    /// and nothing is stored.
+    pub fn set_stream(&self, key: String, mut reader: impl Read, len: u64) -> Result<Response, ProtocolError> {
+        let req = Request::SetStream { key, len };
+        debug!("Request: {:?}", req);
+        let stream = self.open_stream()?;
+        let tcp_reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        debug!("Send request: {:?}", req);
+        write_frame(&mut writer, &req)?;
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut sent = 0;
+        while sent < len {
+            let max_read = std::cmp::min(CHUNK_SIZE as u64, len - sent) as usize;
+            let read = reader.read(&mut buf[..max_read])?;
+            if read == 0 {
+                return Err(ProtocolError::UnknownError(format!(
+                    "Value is over after {} bytes of {}",
+                    sent, len
+                )));
+            }
+            write_chunk(&mut writer, &buf[..read])?;
+            sent += read as u64;
+        }
+        write_chunk(&mut writer, &[])?;
+        writer.flush()?;
+
+        let response = read_response(tcp_reader)?;
+        self.trace(req, response)
+    }
+
+    pub fn incr(&self, key: String, delta: i64) -> Result<Response, ProtocolError> {
+        let req = Request::Incr { key, delta };
+        self.send(req)
+    }
+
+    /// List keys starting with `prefix`, at most `limit` keys if it is set.
+    pub fn scan(&self, prefix: Option<String>, limit: Option<usize>) -> Result<Response, ProtocolError> {
+        self.send(Request::Scan { prefix, limit })
+    }
+
+    /// Compact the storage, the server having the admin token rejects it as unauthorized, see `admin`.
+    pub fn compact(&self) -> Result<Response, ProtocolError> {
+        self.send(Request::Compact)
+    }
+
+    /// Get stats of the storage, the server having the admin token rejects it as unauthorized, see `admin`.
+    pub fn stats(&self) -> Result<Response, ProtocolError> {
+        self.send(Request::Stats)
+    }
+
+    /// Check that every live key of the storage refers to the record of its value.
+    pub fn verify(&self) -> Result<Response, ProtocolError> {
+        self.send(Request::Verify)
+    }
+
+    /// Apply the admin operation authorized by `token`.
+    pub fn admin(&self, token: String, op: AdminOp) -> Result<Response, ProtocolError> {
+        self.send(Request::Admin { token, op })
+    }
+
+    /// Get metrics of the server in the Prometheus text format.
+    pub fn metrics(&self) -> Result<Response, ProtocolError> {
+        self.send(Request::Metrics)
+    }
+
+    /// Remove `key`, the answer is `Response::NotFound` if it is absent.
     pub fn rm(&self, key: String) -> Result<Response, ProtocolError> {
         let req = Request::Rm { key };
         self.send(req)
     }
+
+    /// Check if the server is up, the storage of the server is not touched.
+    pub fn ping(&self) -> Result<(), ProtocolError> {
+        match self.send(Request::Ping)? {
+            Response::Pong => Ok(()),
+            response => Err(ProtocolError::UnknownError(format!(
+                "Unexpected response to ping: {:?}",
+                response
+            ))),
+        }
+    }
+
+    /// Send `requests` tagged by their ids over one connection and get their responses by ids,
+    /// see `Session::pipeline`.
+    pub fn pipeline(&self, requests: Vec<(u64, Request)>) -> Result<HashMap<u64, Response>, ProtocolError> {
+        self.connect()?.pipeline(requests)
+    }
+
+    /// Send `requests` at once and get their responses in the same order.
+    pub fn batch(&self, requests: Vec<Request>) -> Result<Vec<Response>, ProtocolError> {
+        match self.send(Request::Batch(requests))? {
+            Response::Batch(responses) => Ok(responses),
+            response => Err(ProtocolError::UnknownError(format!(
+                "Unexpected response to batch: {:?}",
+                response
+            ))),
+        }
+    }
+}
+
+/// Connection to the server opened by `Client::connect`.
+/// Requests are sent one by one over the same connection, it is closed on dropping.
+pub struct Session<'a> {
+    client: &'a Client,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    /// Frames are compressed as negotiated by `Request::Hello`.
+    compression: bool,
+}
+
+impl<'a> Session<'a> {
+    pub fn send(&mut self, req: Request) -> Result<Response, ProtocolError> {
+        debug!("Send request: {:?}", req);
+        write_frame_with(&mut self.writer, &req, self.compression)?;
+        self.writer.flush()?;
+        let response = read_response(&mut self.reader)?;
+        self.client.trace(req, response)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Response, ProtocolError> {
+        self.send(Request::Get { key })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<Response, ProtocolError> {
+        self.send(Request::Set { key, value })
+    }
+
+    pub fn rm(&mut self, key: String) -> Result<Response, ProtocolError> {
+        self.send(Request::Rm { key })
+    }
+
+    /// Send `requests` tagged by their ids at once and get their responses by ids.
+    /// Responses are matched by ids, so they may arrive in any order.
+    /// Responses are read while requests are written by another thread, so neither the client
+    /// nor the server blocks on full socket buffers. The connection is shut down on error.
+    /// # Error
+    /// It fails if ids of `requests` are not unique, or a response is untagged or has an unknown id.
+    pub fn pipeline(&mut self, requests: Vec<(u64, Request)>) -> Result<HashMap<u64, Response>, ProtocolError> {
+        let mut ids = HashSet::with_capacity(requests.len());
+        if let Some((id, _)) = requests.iter().find(|(id, _)| !ids.insert(*id)) {
+            return Err(ProtocolError::UnknownError(format!("Duplicate id {} of pipelined requests", id)));
+        }
+
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+        let compression = self.compression;
+        let (sent, received) = thread::scope(|scope| {
+            // The failed side shuts the connection down, so the other one doesn't wait forever
+            let sending = scope.spawn(move || {
+                let sent = send_tagged(writer, requests, compression);
+                if sent.is_err() {
+                    let _ = writer.get_ref().shutdown(Shutdown::Both);
+                }
+                sent
+            });
+            let received = receive_tagged(reader, &ids);
+            if received.is_err() {
+                let _ = reader.get_ref().shutdown(Shutdown::Both);
+            }
+            (sending.join().expect("Sending thread of the pipeline panicked"), received)
+        });
+        let sent = sent?;
+        let mut received = received?;
+
+        let mut responses = HashMap::with_capacity(sent.len());
+        for (id, request) in sent {
+            let response = received.remove(&id).expect("Every sent request is received");
+            responses.insert(id, self.client.trace(request, response)?);
+        }
+        Ok(responses)
+    }
+
+    /// Check if frames of the session are compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.compression
+    }
+
+    /// Request compression of frames, returns if the server agreed.
+    /// The handshake is not traced, it is not a request to the storage.
+    fn negotiate_compression(&mut self) -> Result<bool, ProtocolError> {
+        debug!("Negotiate compression");
+        write_frame(&mut self.writer, &Request::Hello { compression: true })?;
+        self.writer.flush()?;
+        match read_response(&mut self.reader)? {
+            Response::Bool(compression) => Ok(compression),
+            response => Err(ProtocolError::UnknownError(format!(
+                "Unexpected response to hello: {:?}",
+                response
+            ))),
+        }
+    }
+}
+
+/// Check if `error` is caused by the connection rather than by the content of messages.
+fn is_connection_error(error: &ProtocolError) -> bool {
+    match error {
+        ProtocolError::IoError(_) | ProtocolError::Timeout | ProtocolError::Disconnected => true,
+        _ => false,
+    }
+}
+
+/// Write `requests` tagged by their ids to `writer`, returns untagged requests for tracing.
+fn send_tagged(
+    writer: &mut BufWriter<Stream>,
+    requests: Vec<(u64, Request)>,
+    compression: bool,
+) -> Result<Vec<(u64, Request)>, ProtocolError> {
+    let mut sent = Vec::with_capacity(requests.len());
+    for (id, request) in requests {
+        let tagged = Request::Tagged { id, request: Box::new(request) };
+        debug!("Send request: {:?}", tagged);
+        write_frame_with(&mut *writer, &tagged, compression)?;
+        sent.push((id, tagged.untag().1));
+    }
+    writer.flush()?;
+    Ok(sent)
+}
+
+/// Read tagged responses to requests with `ids` from `reader`, responses may arrive in any order.
+fn receive_tagged(reader: &mut BufReader<Stream>, ids: &HashSet<u64>) -> Result<HashMap<u64, Response>, ProtocolError> {
+    let mut responses = HashMap::with_capacity(ids.len());
+    while responses.len() < ids.len() {
+        let (id, response) = match read_response(&mut *reader)? {
+            Response::Tagged { id, response } => (id, *response),
+            response => {
+                return Err(ProtocolError::UnknownError(format!(
+                    "Untagged response to pipelined request: {:?}",
+                    response
+                )))
+            }
+        };
+        if !ids.contains(&id) || responses.contains_key(&id) {
+            return Err(ProtocolError::UnknownError(format!("Response to unknown request {}", id)));
+        }
+        responses.insert(id, response);
+    }
+    Ok(responses)
+}
+
+/// Read one framed `Response`.
+/// The rest of the stream is not read, the connection may be used for the next requests.
+fn read_response<R: Read>(reader: R) -> Result<Response, ProtocolError> {
+    read_frame(reader)?.ok_or(ProtocolError::Disconnected)
 }
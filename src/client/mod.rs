@@ -1,3 +1,3 @@
-pub use client::Client;
+pub use client::{Client, ClientBuilder, Session, TraceEntry};
 
 mod client;
@@ -0,0 +1,117 @@
+use std::future::Future;
+
+use super::error::{KvError, Result};
+use super::kv_store::VerifyReport;
+use super::kvs_engine::{KvsEngine, WriteOp};
+use super::stats::KvStats;
+
+/// Asynchronous counterpart of `KvsEngine` for embedding into the tokio runtime.
+/// It is implemented for every `KvsEngine` by offloading blocking operations
+/// onto the blocking thread pool of tokio, so the runtime is never blocked by disk I/O.
+pub trait AsyncKvsEngine: Send + Sync + Clone + 'static {
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+    fn contains_key(&self, key: String) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Get values of `keys` in the same order, `None` for absent keys.
+    fn get_batch(&self, keys: Vec<String>) -> impl Future<Output = Result<Vec<Option<String>>>> + Send;
+
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+
+    /// Atomically set the value of `key` only if the key is absent.
+    fn set_if_absent(&self, key: String, value: String) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Atomically add `delta` to the integer value of `key` and return the new value.
+    fn increment(&self, key: String, delta: i64) -> impl Future<Output = Result<i64>> + Send;
+
+    /// Check sizes of the key and the value against limits of the engine without blocking.
+    fn check_size(&self, key_len: usize, value_len: u64) -> Result<()>;
+
+    /// Get keys starting with `prefix` in ascending order, at most `limit` keys if it is set.
+    fn scan_keys(&self, prefix: String, limit: Option<usize>) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// Apply `ops` atomically in their order: either all of them become visible or none.
+    fn write_batch(&self, ops: Vec<WriteOp>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Force all acknowledged writes to disk.
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Compact the storage immediately.
+    fn compact(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Get statistics of the engine.
+    fn stats(&self) -> impl Future<Output = Result<KvStats>> + Send;
+
+    /// Check that every live key refers to the readable record of its value.
+    fn verify(&self) -> impl Future<Output = Result<VerifyReport>> + Send;
+}
+
+impl<E: KvsEngine + Sync> AsyncKvsEngine for E {
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        spawn_blocking(self.clone(), move |engine| engine.get(key))
+    }
+
+    fn contains_key(&self, key: String) -> impl Future<Output = Result<bool>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::contains_key(&engine, &key))
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> impl Future<Output = Result<Vec<Option<String>>>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::get_batch(&engine, keys))
+    }
+
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        spawn_blocking(self.clone(), move |engine| engine.set(key, value))
+    }
+
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        spawn_blocking(self.clone(), move |engine| engine.remove(key))
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> impl Future<Output = Result<bool>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::set_if_absent(&engine, key, value))
+    }
+
+    fn increment(&self, key: String, delta: i64) -> impl Future<Output = Result<i64>> + Send {
+        spawn_blocking(self.clone(), move |engine| engine.increment(key, delta))
+    }
+
+    fn scan_keys(&self, prefix: String, limit: Option<usize>) -> impl Future<Output = Result<Vec<String>>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::scan_keys(&engine, &prefix, limit))
+    }
+
+    fn check_size(&self, key_len: usize, value_len: u64) -> Result<()> {
+        KvsEngine::check_size(self, key_len, value_len)
+    }
+
+    fn write_batch(&self, ops: Vec<WriteOp>) -> impl Future<Output = Result<()>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::write_batch(&engine, ops))
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::flush(&engine))
+    }
+
+    fn compact(&self) -> impl Future<Output = Result<()>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::compact(&engine))
+    }
+
+    fn stats(&self) -> impl Future<Output = Result<KvStats>> + Send {
+        spawn_blocking(self.clone(), move |engine| Ok(KvsEngine::stats(&engine)))
+    }
+
+    fn verify(&self) -> impl Future<Output = Result<VerifyReport>> + Send {
+        spawn_blocking(self.clone(), move |engine| KvsEngine::verify(&engine))
+    }
+}
+
+/// Run blocking `operation` over `engine` on the blocking thread pool of tokio.
+async fn spawn_blocking<E, T, F>(engine: E, operation: F) -> Result<T>
+where
+    E: KvsEngine,
+    T: Send + 'static,
+    F: FnOnce(E) -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || operation(engine))
+        .await
+        .map_err(|e| KvError::UnknownError(format!("Blocking task failed: {}", e)))?
+}
@@ -0,0 +1,78 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Deserialize;
+
+/// When written data is forced to disk by engines.
+///
+/// Acknowledged writes survive a crash of the process in every mode, because they are
+/// passed to the OS before returning. Only syncing protects them against a power loss,
+/// but a sync costs a disk round trip, so it limits the throughput of writes.
+///
+/// Modes are parsed from `no-sync`, `every-write` and `every-N`, e.g. `every-100`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DurabilityMode {
+    /// Never sync explicitly, the OS writes data to disk when it decides to.
+    /// The fastest mode, a power loss may lose recent writes unless they are synced by `KvsEngine::flush`.
+    NoSync,
+    /// Sync after every write. No acknowledged write is lost, but writes are the slowest.
+    FsyncEveryWrite,
+    /// Sync after every `n` writes. A power loss may lose at most `n - 1` acknowledged writes.
+    FsyncEveryN(u64),
+}
+
+impl DurabilityMode {
+    /// Count the new write in `unsynced` writes and check if the data must be synced now.
+    /// The counter is reset if the sync is required.
+    pub fn needs_sync(self, unsynced: &AtomicU64) -> bool {
+        match self {
+            DurabilityMode::NoSync => false,
+            DurabilityMode::FsyncEveryWrite => true,
+            DurabilityMode::FsyncEveryN(n) => {
+                if unsynced.fetch_add(1, Ordering::SeqCst) + 1 >= n {
+                    unsynced.store(0, Ordering::SeqCst);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for DurabilityMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<DurabilityMode, String> {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            "no-sync" => Ok(DurabilityMode::NoSync),
+            "every-write" => Ok(DurabilityMode::FsyncEveryWrite),
+            _ => match name.strip_prefix("every-").map(str::parse) {
+                Some(Ok(n)) if n > 0 => Ok(DurabilityMode::FsyncEveryN(n)),
+                _ => Err(format!("Unknown durability mode: {}", name)),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for DurabilityMode {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<DurabilityMode, String> {
+        name.parse()
+    }
+}
+
+impl fmt::Display for DurabilityMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DurabilityMode::NoSync => write!(f, "no-sync"),
+            DurabilityMode::FsyncEveryWrite => write!(f, "every-write"),
+            DurabilityMode::FsyncEveryN(n) => write!(f, "every-{}", n),
+        }
+    }
+}
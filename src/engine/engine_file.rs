@@ -0,0 +1,133 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use super::error::{KvError, Result};
+
+/// Name of the file which records the engine powering the storage directory.
+pub const ENGINE_FILE_NAME: &str = "engine";
+
+/// Version of the format of the engine file written by this version.
+const ENGINE_FILE_VERSION: u32 = 1;
+
+/// Storage engine recorded in the engine file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum EngineKind {
+    Kvs,
+    Sled,
+    /// In-memory engine, it never powers a storage directory.
+    #[cfg(feature = "memory")]
+    Memory,
+}
+
+impl EngineKind {
+    /// Names of all engines, they are parsed case-insensitively.
+    #[cfg(not(feature = "memory"))]
+    pub const VARIANTS: &'static [&'static str] = &["kvs", "sled"];
+    /// Names of all engines, they are parsed case-insensitively.
+    #[cfg(feature = "memory")]
+    pub const VARIANTS: &'static [&'static str] = &["kvs", "sled", "memory"];
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<EngineKind, String> {
+        match name.to_lowercase().as_str() {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            #[cfg(feature = "memory")]
+            "memory" => Ok(EngineKind::Memory),
+            _ => Err(format!("Unknown engine: {}", name)),
+        }
+    }
+}
+
+impl TryFrom<String> for EngineKind {
+    type Error = String;
+
+    fn try_from(name: String) -> std::result::Result<EngineKind, String> {
+        name.parse()
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => write!(f, "kvs"),
+            EngineKind::Sled => write!(f, "sled"),
+            #[cfg(feature = "memory")]
+            EngineKind::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// Content of the engine file. Unknown fields written by newer versions are ignored.
+#[derive(Serialize, Deserialize, Debug)]
+struct EngineFile {
+    version: u32,
+    engine: String,
+}
+
+/// Compare `requested` engine with the engine recorded in the engine file of `dir_path`.
+/// Returns `KvError::EngineMismatch` if they differ, the engine file is written if it is absent.
+pub fn reconcile_engine_file(dir_path: &Path, requested: EngineKind) -> Result<()> {
+    let engine_file = dir_path.join(ENGINE_FILE_NAME);
+    match current_engine(&engine_file)? {
+        Some(existing) if existing != requested => Err(KvError::EngineMismatch { existing, requested }),
+        Some(existing) => {
+            debug!("Engine file: {}", existing);
+            Ok(())
+        }
+        None => {
+            debug!("Set new engine: {}", requested);
+            write_engine_file(&engine_file, requested)
+        }
+    }
+}
+
+/// Parse the content of the engine file.
+/// The old format is the bare engine name, it is returned with `version` 0.
+fn parse_engine_file(content: &str) -> Result<(u32, EngineKind)> {
+    let content = content.trim();
+    let (version, name) = if content.starts_with('{') {
+        let engine_file: EngineFile =
+            serde_json::from_str(content).map_err(|e| KvError::InvalidEngineFile(e.to_string()))?;
+        (engine_file.version, engine_file.engine)
+    } else {
+        (0, content.to_owned())
+    };
+    let engine = name.parse().map_err(KvError::InvalidEngineFile)?;
+    Ok((version, engine))
+}
+
+fn write_engine_file(engine_file: &Path, engine: EngineKind) -> Result<()> {
+    let content = serde_json::to_string(&EngineFile {
+        version: ENGINE_FILE_VERSION,
+        engine: engine.to_string(),
+    })?;
+    fs::write(engine_file, content)?;
+    Ok(())
+}
+
+/// Read the current engine from `engine_file`.
+/// The engine file of the old format is rewritten in the current one.
+fn current_engine(engine_file: &Path) -> Result<Option<EngineKind>> {
+    if !engine_file.exists() {
+        return Ok(None);
+    }
+
+    let (version, engine) = parse_engine_file(&fs::read_to_string(engine_file)?)?;
+    debug!("Engine file version: {}", version);
+    if version == 0 {
+        info!("Migrate engine file to version {}", ENGINE_FILE_VERSION);
+        write_engine_file(engine_file, engine)?;
+    }
+    Ok(Some(engine))
+}
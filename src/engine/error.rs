@@ -1,38 +1,91 @@
+use std::path::PathBuf;
 use std::result;
 use std::string::FromUtf8Error;
 
-use failure::Fail;
+use thiserror::Error;
 use log::error;
 use serde_json;
+use bincode;
 use sled;
 
-#[derive(Fail, Debug)]
+use super::engine_file::EngineKind;
+
+#[derive(Error, Debug)]
 pub enum KvError {
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound, // Use in case of removing key, otherwise use Option::None
 
-    #[fail(display = "Storage File Error: {}", _0)]
-    StorageFileError(#[cause] std::io::Error),
+    #[error("Storage File Error: {0}")]
+    StorageFileError(#[source] std::io::Error),
+
+    #[error("Serde Error: {0}")]
+    SerdeError(#[source] serde_json::Error),
 
-    #[fail(display = "Serde Error: {}", _0)]
-    SerdeError(#[cause] serde_json::Error),
+    #[error("Bincode Error: {0}")]
+    BincodeError(#[source] bincode::Error),
 
-    #[fail(display = "Unexpected command")]
+    #[error("Unexpected command")]
     UnexpectedCommand,
 
-    #[fail(display = "Invalid name of datafile")]
+    #[error("Index of key {key} refers to the removal record")]
+    IndexCorruption { key: String },
+
+    #[error("Invalid name of datafile")]
     InvalidDatafileName,
 
-    #[fail(display = "Sled error: {}", _0)]
-    SledError(#[cause] sled::Error),
+    #[error("Corrupt record in {file:?} at offset {offset}")]
+    CorruptRecord { file: PathBuf, offset: u64 },
+
+    #[error("Records of {file:?} are written in the unsupported version {version}")]
+    UnsupportedRecordVersion { file: PathBuf, version: u8 },
+
+    #[error("Invalid name of tree: {0}")]
+    InvalidTreeName(String),
+
+    #[error("Value is not an integer or overflows")]
+    NotAnInteger,
+
+    #[error("Key of {len} bytes exceeds the limit of {max} bytes")]
+    KeyTooLarge { len: usize, max: usize },
+
+    #[error("Value of {len} bytes exceeds the limit of {max} bytes")]
+    ValueTooLarge { len: u64, max: usize },
+
+    #[error("Storage is opened for reading only")]
+    ReadOnly,
+
+    #[error("Storage is not empty")]
+    NotEmpty,
+
+    #[error("Migrated {migrated} keys, but the destination has {stored} keys")]
+    MigrationMismatch { migrated: usize, stored: usize },
 
-    #[fail(display = "Encoding error: {}", _0)]
-    EncodingError(#[cause] FromUtf8Error),
+    #[error("Incompatible manifest: {0}")]
+    IncompatibleManifest(String),
 
-    #[fail(display = "Unknown Error: {}", _0)]
+    #[error("Option {field} of the config is {config}, but the manifest has {manifest}")]
+    ManifestMismatch { field: String, manifest: String, config: String },
+
+    #[error("Storage directory is already powered by other engine: {existing}, new one: {requested}")]
+    EngineMismatch { existing: EngineKind, requested: EngineKind },
+
+    #[error("Invalid engine file: {0}")]
+    InvalidEngineFile(String),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("Sled error: {0}")]
+    SledError(#[source] sled::Error),
+
+    #[error("Encoding error: {0}")]
+    EncodingError(#[source] FromUtf8Error),
+
+    #[error("Unknown Error: {0}")]
     UnknownError(String),
 }
 
+// Conversions are implemented manually instead of `#[from]` to log errors where they arise
 impl From<std::io::Error> for KvError {
     fn from(err: std::io::Error) -> KvError {
         let res = KvError::StorageFileError(err);
@@ -49,6 +102,14 @@ impl From<serde_json::Error> for KvError {
     }
 }
 
+impl From<bincode::Error> for KvError {
+    fn from(err: bincode::Error) -> KvError {
+        let res = KvError::BincodeError(err);
+        error!("{}", res);
+        res
+    }
+}
+
 impl From<sled::Error> for KvError {
     fn from(err: sled::Error) -> KvError {
         let res = KvError::SledError(err);
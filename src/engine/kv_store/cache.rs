@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Bounded cache of recently read values, the least recently used value is evicted first.
+/// Values are cached by `get` and invalidated by writes of their keys.
+///
+/// Every invalidation starts a new epoch of the cache. The value read from disk is inserted
+/// only if no invalidation happened since the read started, so the value overwritten
+/// concurrently with reading is never cached.
+#[derive(Debug)]
+pub struct ValueCache {
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Cached values with the tick of their last use.
+    values: HashMap<String, (String, u64)>,
+    /// Keys by the tick of their last use, the first one is evicted.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    epoch: u64,
+}
+
+impl ValueCache {
+    /// Create the cache of at most `capacity` values, the cache of zero capacity is disabled.
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            state: Mutex::new(State::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Get the cached value of `key` and mark it as recently used.
+    pub fn get(&self, key: &str) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.tick += 1;
+        let value = match state.values.get_mut(key) {
+            Some((value, tick)) => {
+                state.recency.remove(tick);
+                *tick = state.tick;
+                state.recency.insert(state.tick, key.to_owned());
+                Some(value.clone())
+            }
+            None => None,
+        };
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::SeqCst);
+        value
+    }
+
+    /// Get the current epoch, it must be taken before reading the value which is inserted then.
+    pub fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Cache the value of `key` read in `epoch`, evicting the least recently used value if the cache is full.
+    /// Nothing is cached if any key is invalidated since `epoch`.
+    pub fn insert(&self, key: String, value: String, epoch: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some((_, prev_tick)) = state.values.insert(key.clone(), (value, tick)) {
+            state.recency.remove(&prev_tick);
+        }
+        state.recency.insert(tick, key);
+        while state.values.len() > self.capacity {
+            let (&oldest, _) = state.recency.iter().next().unwrap();
+            let evicted = state.recency.remove(&oldest).unwrap();
+            state.values.remove(&evicted);
+        }
+    }
+
+    /// Drop the cached value of `key`, it must be called after the `Index` is updated.
+    pub fn invalidate(&self, key: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        if let Some((_, tick)) = state.values.remove(key) {
+            state.recency.remove(&tick);
+        }
+    }
+
+    /// Drop all cached values.
+    pub fn clear(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.values.clear();
+        state.recency.clear();
+    }
+
+    /// Get the number of `get` calls which found the cached value.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of `get` calls which didn't find the cached value.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,316 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use super::kv_store::Record;
+use super::manifest::Compression;
+use crate::engine::{KvError, Result};
+
+/// Length of the header of datafiles.
+pub const HEADER_LEN: u64 = 1;
+
+/// Flag of the header set if records of the datafile are framed with checksums.
+const CHECKSUMS_FLAG: u8 = 0x80;
+
+/// Flag of the header set if records of the datafile are compressed by gzip.
+const GZIP_FLAG: u8 = 0x40;
+
+/// Bits of the header storing the format version of records.
+const VERSION_MASK: u8 = 0x30;
+const VERSION_SHIFT: u8 = 4;
+
+/// Format version of records written by this version.
+/// Version 0 is the format of datafiles written before versions were introduced, with or without header.
+pub const RECORD_VERSION: u8 = 1;
+
+/// Serialization format of records in datafiles.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Bincode,
+}
+
+impl Codec {
+    /// Get the identifier of the codec written in headers of datafiles.
+    fn id(self) -> u8 {
+        match self {
+            Codec::Json => 1,
+            Codec::Bincode => 2,
+        }
+    }
+
+    /// Get the codec by its identifier.
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            1 => Some(Codec::Json),
+            2 => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Get the implementation of the codec.
+    pub fn record_codec(self) -> &'static dyn RecordCodec {
+        match self {
+            Codec::Json => &JsonCodec,
+            Codec::Bincode => &BincodeCodec,
+        }
+    }
+}
+
+impl Compression {
+    /// Get the implementation of the compression, `None` if records are not compressed.
+    pub fn value_codec(self) -> Option<&'static dyn ValueCodec> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(&GzipValueCodec),
+        }
+    }
+}
+
+/// The one-byte header of datafile describing the format of its records.
+/// Datafiles written before headers were introduced have no header
+/// and contain JSON records without checksums.
+/// Headers never collide with the first byte of a JSON record.
+/// Compressed records are always framed, frames delimit them in the datafile.
+/// The format version of records lets readers dispatch on it, headers of newer versions are parsed,
+/// but their datafiles are rejected by readers, see `is_supported`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatafileHeader {
+    pub codec: Codec,
+    pub checksums: bool,
+    pub compression: Compression,
+    pub version: u8,
+}
+
+impl DatafileHeader {
+    /// Format of datafiles written without header.
+    pub const LEGACY: DatafileHeader = DatafileHeader {
+        codec: Codec::Json,
+        checksums: false,
+        compression: Compression::None,
+        version: 0,
+    };
+
+    /// Check if records of the datafile can be read by this version.
+    pub fn is_supported(self) -> bool {
+        self.version <= RECORD_VERSION
+    }
+
+    /// Get the codec reading and writing records of the datafile.
+    /// Records of version 0 have the same layout as the current ones, so they are read by the same codec.
+    /// Panics on unsupported versions, datafiles are checked by `is_supported` on reading their headers.
+    pub fn record_codec(self) -> &'static dyn RecordCodec {
+        match self.version {
+            0 | RECORD_VERSION => self.codec.record_codec(),
+            version => panic!("Unsupported version of records: {}", version),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        let mut byte = self.codec.id() | (self.version << VERSION_SHIFT) & VERSION_MASK;
+        if self.checksums {
+            byte |= CHECKSUMS_FLAG;
+        }
+        if self.compression == Compression::Gzip {
+            byte |= GZIP_FLAG;
+        }
+        byte
+    }
+
+    /// Parse the first byte of datafile.
+    /// Returns `None` if the datafile has no header.
+    pub fn from_byte(byte: u8) -> Option<DatafileHeader> {
+        Codec::from_id(byte & !(CHECKSUMS_FLAG | GZIP_FLAG | VERSION_MASK)).map(|codec| DatafileHeader {
+            codec,
+            checksums: byte & CHECKSUMS_FLAG != 0,
+            compression: if byte & GZIP_FLAG != 0 {
+                Compression::Gzip
+            } else {
+                Compression::None
+            },
+            version: (byte & VERSION_MASK) >> VERSION_SHIFT,
+        })
+    }
+}
+
+/// Decoded records with their offsets from the beginning of the stream.
+pub type RecordStream<'a> = Box<dyn Iterator<Item = Result<(u64, Record)>> + 'a>;
+
+/// Serialization of records in datafiles.
+pub trait RecordCodec: Send + Sync {
+    /// Write `record` to `writer`.
+    fn encode(&self, writer: &mut dyn Write, record: &Record) -> Result<()>;
+
+    /// Read records from `reader` until the end of the stream.
+    fn decode_stream<'a>(&self, reader: Box<dyn Read + 'a>) -> RecordStream<'a>;
+
+    /// Read the single record at the beginning of `reader`, nothing after it is read.
+    /// Returns `None` if the record is truncated or can't be decoded, only I/O errors are returned.
+    fn decode_record(&self, reader: &mut dyn Read) -> Result<Option<Record>>;
+
+    /// Get the end of whole records of the datafile read by `reader` starting from `offset`,
+    /// i.e. the offset of the last record which is cut off or the end of the datafile.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if the record can't be decoded, but is not cut off,
+    /// so records following the corrupted one are never treated as the cut-off tail.
+    fn valid_len<'a>(&self, reader: Box<dyn Read + 'a>, datafile_path: &Path, offset: u64) -> Result<u64>;
+}
+
+/// Records are JSON values following one another without separators.
+pub struct JsonCodec;
+
+impl RecordCodec for JsonCodec {
+    fn encode(&self, writer: &mut dyn Write, record: &Record) -> Result<()> {
+        Ok(serde_json::to_writer(writer, record)?)
+    }
+
+    fn decode_stream<'a>(&self, reader: Box<dyn Read + 'a>) -> RecordStream<'a> {
+        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Record>();
+        let mut offset = 0;
+        Box::new(std::iter::from_fn(move || {
+            let item = stream.next()?;
+            let record_offset = offset;
+            offset = stream.byte_offset() as u64;
+            Some(item.map(|record| (record_offset, record)).map_err(KvError::from))
+        }))
+    }
+
+    fn decode_record(&self, reader: &mut dyn Read) -> Result<Option<Record>> {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        match Record::deserialize(&mut deserializer) {
+            Ok(record) => Ok(Some(record)),
+            Err(e) if e.is_io() => Err(e.into()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn valid_len<'a>(&self, reader: Box<dyn Read + 'a>, datafile_path: &Path, offset: u64) -> Result<u64> {
+        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Record>();
+        let mut len = 0;
+        loop {
+            match stream.next() {
+                Some(Ok(_)) => len = stream.byte_offset() as u64,
+                Some(Err(e)) if e.is_io() => return Err(e.into()),
+                // The last record is cut off
+                Some(Err(e)) if e.is_eof() => return Ok(offset + len),
+                Some(Err(_)) => {
+                    return Err(KvError::CorruptRecord {
+                        file: datafile_path.to_path_buf(),
+                        offset: offset + len,
+                    })
+                }
+                // Trailing whitespace is counted as valid
+                None => return Ok(offset + stream.byte_offset() as u64),
+            }
+        }
+    }
+}
+
+/// Records are encoded by bincode, it is roughly twice as compact as JSON.
+pub struct BincodeCodec;
+
+impl RecordCodec for BincodeCodec {
+    fn encode(&self, writer: &mut dyn Write, record: &Record) -> Result<()> {
+        Ok(bincode::serialize_into(writer, record)?)
+    }
+
+    fn decode_stream<'a>(&self, reader: Box<dyn Read + 'a>) -> RecordStream<'a> {
+        let mut reader = CountingReader { inner: reader, count: 0 };
+        Box::new(std::iter::from_fn(move || {
+            let offset = reader.count;
+            match bincode::deserialize_from::<_, Record>(&mut reader) {
+                Ok(record) => Some(Ok((offset, record))),
+                Err(e) => {
+                    // The stream is over if nothing of the next record is read
+                    let is_end = match *e {
+                        bincode::ErrorKind::Io(ref io_error) => {
+                            io_error.kind() == io::ErrorKind::UnexpectedEof
+                                && reader.count == offset
+                        }
+                        _ => false,
+                    };
+                    if is_end {
+                        None
+                    } else {
+                        Some(Err(KvError::from(e)))
+                    }
+                }
+            }
+        }))
+    }
+
+    fn decode_record(&self, reader: &mut dyn Read) -> Result<Option<Record>> {
+        match bincode::deserialize_from::<_, Record>(reader) {
+            Ok(record) => Ok(Some(record)),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                bincode::ErrorKind::Io(_) => Err(KvError::from(e)),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    fn valid_len<'a>(&self, reader: Box<dyn Read + 'a>, datafile_path: &Path, offset: u64) -> Result<u64> {
+        let mut reader = CountingReader { inner: reader, count: 0 };
+        loop {
+            let len = reader.count;
+            if let Err(e) = bincode::deserialize_from::<_, Record>(&mut reader) {
+                return match *e {
+                    // The last record is cut off or the stream is over
+                    bincode::ErrorKind::Io(ref io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => {
+                        Ok(offset + len)
+                    }
+                    bincode::ErrorKind::Io(_) => Err(KvError::from(e)),
+                    _ => Err(KvError::CorruptRecord {
+                        file: datafile_path.to_path_buf(),
+                        offset: offset + len,
+                    }),
+                };
+            }
+        }
+    }
+}
+
+/// Reader counting the number of bytes read from the inner reader.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Transformation of stored bytes of records applied after encoding and before decoding,
+/// e.g. compression of values.
+pub trait ValueCodec: Send + Sync {
+    /// Transform `bytes` before writing.
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>>;
+
+    /// Restore bytes transformed by `encode` after reading.
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Bytes are compressed by gzip with the default level.
+pub struct GzipValueCodec;
+
+impl ValueCodec for GzipValueCodec {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use super::codec::Codec;
+use super::manifest::Compression;
+use crate::engine::DurabilityMode;
+use super::utils::{ACTIVE_FILE_NAME, HINT_EXT, INDEX_SHARDS, MANIFEST_FILE_NAME, PASSIVE_EXT, RECORDS_LIMIT};
+
+/// Configuration of `KvStore`.
+///
+/// # Example:
+/// ```rust
+/// use kvs::{KvStore, KvStoreConfig};
+/// let config = KvStoreConfig {
+///     records_limit: 4,
+///     ..KvStoreConfig::default()
+/// };
+/// let storage = KvStore::open_with_config(std::env::current_dir().unwrap(), config).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    /// Max number of unused records in the log.
+    /// Compaction will be triggered after exceeding.
+    pub records_limit: u64,
+
+    /// Max number of records in one compacted passive datafile.
+    /// `None` keeps the value recorded in the manifest of the existing storage.
+    pub records_in_compacted: Option<usize>,
+
+    /// Serialization format of new records.
+    /// `None` keeps the codec recorded in the manifest of the existing storage, JSON by default.
+    /// Datafiles written by another codec are still readable.
+    pub codec: Option<Codec>,
+
+    /// Compression of new records.
+    /// `None` keeps the compression recorded in the manifest of the existing storage,
+    /// no compression by default. Datafiles written with another compression are still readable.
+    pub compression: Option<Compression>,
+
+    /// Number of subdirectories `passive/NN/` passive datafiles are sharded into by serial number,
+    /// 0 for the flat layout. The active datafile is always kept in the root directory.
+    /// `None` keeps the layout recorded in the manifest of the existing storage, flat by default.
+    /// Passive datafiles are moved on opening if the layout is changed.
+    pub passive_shards: Option<u64>,
+
+    /// Index datafiles on the first access instead of opening.
+    /// Opening is near-instant, but the first access of keys in not indexed datafiles is slower.
+    pub lazy_indexing: bool,
+
+    /// Max size of the active datafile in bytes.
+    /// The active datafile is dumped to the passive one after exceeding,
+    /// independently of the compaction triggered by `records_limit`.
+    /// `None` means the active datafile is dumped by compaction only.
+    pub max_active_bytes: Option<u64>,
+
+    /// When records are synced to disk, see `DurabilityMode` for the tradeoff.
+    /// Records are not synced by default.
+    pub durability: DurabilityMode,
+
+    /// Share syncs required by `durability` between concurrent writers: the first writer waits
+    /// for the given window, then one sync covers all writes made so far and wakes their writers.
+    /// Every write is still synced before returning, but syncs are amortized under concurrent writes
+    /// at the cost of the window added to the latency of writes.
+    /// `None` syncs every write separately under the lock of the writer.
+    pub group_commit: Option<Duration>,
+
+    /// Max number of backups kept in the backups directory.
+    /// The oldest backups are removed after creating a new one, `None` keeps all of them.
+    pub max_backups: Option<usize>,
+
+    /// Max size of keys in bytes, larger keys are rejected by `KvError::KeyTooLarge`.
+    /// `None` means keys are unlimited.
+    pub max_key_bytes: Option<usize>,
+
+    /// Max size of values in bytes, larger values are rejected by `KvError::ValueTooLarge`.
+    /// `None` means values are unlimited.
+    pub max_value_bytes: Option<usize>,
+
+    /// How the `Log` is compacted, all records are rewritten by default.
+    pub compaction: CompactionStrategy,
+
+    /// Number of shards the in-memory index of keys is partitioned into by hashes of keys.
+    /// Writers of distinct keys contend less with more shards, and compaction reads records
+    /// of shards in parallel. 1 keeps all keys in the single map.
+    pub index_shards: usize,
+
+    /// Max number of recently read values cached in memory, e.g. for hot keys.
+    /// `None` disables the cache, every `get` reads the value from disk.
+    pub cache_capacity: Option<usize>,
+
+    /// Run the full compaction right after indexing on opening if there are reclaimable records,
+    /// e.g. left by the unclean shutdown which skipped the compaction on dropping.
+    pub compact_on_open: bool,
+
+    /// Cut off the undecodable tail of the active datafile without checksums on opening,
+    /// e.g. the record partially written before a crash, instead of failing to open.
+    /// Only the cut-off last record is removed, opening fails on the corrupted record followed by others.
+    /// The active datafile with checksums is always repaired while indexing.
+    pub repair_active: bool,
+
+    /// Dump the active datafile to the passive one before every compaction, so records of all datafiles
+    /// are compacted. Otherwise only passive datafiles are rewritten and the active datafile keeps
+    /// receiving writes, so write-heavy loads don't leave many small passive datafiles behind.
+    /// The active datafile is dumped then only after exceeding `max_active_bytes`, by rotation
+    /// or before the backup. Its dead records stay counted in unused records until then.
+    /// Disabled by default, but ignored without `max_active_bytes`, since the active datafile
+    /// would never be compacted otherwise.
+    pub dump_on_compaction: bool,
+
+    /// Names of files of the `Log` in the storage directory.
+    pub log: LogConfig,
+}
+
+/// Names of files of the `Log` in the storage directory, the default ones are used by existing storages.
+/// Storages with distinct names of all these files can share one directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Name of the active datafile.
+    pub active_file_name: String,
+
+    /// Extension of passive datafiles, they are named by their serial numbers, e.g. `1.passive`.
+    pub passive_ext: String,
+
+    /// Extension of hints of passive datafiles, e.g. `1.hint`.
+    pub hint_ext: String,
+
+    /// Name of the manifest file.
+    pub manifest_file_name: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            active_file_name: ACTIVE_FILE_NAME.to_owned(),
+            passive_ext: PASSIVE_EXT.to_owned(),
+            hint_ext: HINT_EXT.to_owned(),
+            manifest_file_name: MANIFEST_FILE_NAME.to_owned(),
+        }
+    }
+}
+
+/// Strategy of compaction of the `Log`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    /// Rewrite all actual records to new passive datafiles of `records_in_compacted` records.
+    Full,
+    /// Merge the newest passive datafiles smaller than `max_file_bytes` into one datafile
+    /// once there are at least `min_files` of them. Larger datafiles are left untouched,
+    /// so only recently written records are rewritten until their datafile grows large.
+    Tiered { min_files: usize, max_file_bytes: u64 },
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            records_limit: RECORDS_LIMIT,
+            records_in_compacted: None,
+            codec: None,
+            compression: None,
+            passive_shards: None,
+            lazy_indexing: false,
+            max_active_bytes: None,
+            durability: DurabilityMode::NoSync,
+            group_commit: None,
+            max_backups: None,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            compaction: CompactionStrategy::Full,
+            index_shards: INDEX_SHARDS,
+            cache_capacity: None,
+            compact_on_open: false,
+            repair_active: false,
+            dump_on_compaction: false,
+            log: LogConfig::default(),
+        }
+    }
+}
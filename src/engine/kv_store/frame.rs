@@ -0,0 +1,181 @@
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use super::codec::{DatafileHeader, RecordStream};
+use super::kv_store::Record;
+use crate::engine::{KvError, Result};
+
+/// Length of the frame prefix: length of the payload and its CRC32, both are little-endian `u32`.
+const PREFIX_LEN: usize = 8;
+
+/// Length of the CRC32 following raw bytes of the streamed value, it is little-endian `u32`.
+pub const RAW_CHECKSUM_LEN: u64 = 4;
+
+/// Size of chunks raw bytes of streamed values are copied by.
+const RAW_CHUNK_LEN: usize = 64 * 1024;
+
+/// Write `record` encoded and compressed as specified by `header` to `writer` as a frame.
+/// Frame is the prefix followed by the payload, so partially written records can be detected.
+/// Returns the length of the frame.
+pub fn encode_frame(writer: &mut dyn Write, header: DatafileHeader, record: &Record) -> Result<u64> {
+    let mut payload = Vec::new();
+    header.record_codec().encode(&mut payload, record)?;
+    if let Some(value_codec) = header.compression.value_codec() {
+        payload = value_codec.encode(&payload)?;
+    }
+
+    let mut frame = Vec::with_capacity(PREFIX_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    Ok(frame.len() as u64)
+}
+
+/// Read frames from `reader` positioned at `offset` of the datafile `file`.
+/// Records are returned with their offsets in the datafile.
+/// Truncated frames and checksum mismatches are reported as `KvError::CorruptRecord`
+/// and stop the stream.
+pub fn decode_frames<'a>(
+    mut reader: Box<dyn Read + 'a>,
+    header: DatafileHeader,
+    file: PathBuf,
+    mut offset: u64,
+) -> RecordStream<'a> {
+    let mut is_corrupted = false;
+    Box::new(std::iter::from_fn(move || {
+        if is_corrupted {
+            return None;
+        }
+        let frame_offset = offset;
+        let result = decode_frame(&mut reader, header).transpose()?;
+        let result = match result {
+            // Raw bytes of the streamed value follow its frame, they are verified without buffering
+            Ok((frame_len, Some(Record::SetRaw { key, len }))) => {
+                match decode_raw(&mut reader, &mut io::sink(), len) {
+                    Ok(true) => {
+                        offset += frame_len + len + RAW_CHECKSUM_LEN;
+                        Ok((frame_offset, Record::SetRaw { key, len }))
+                    }
+                    Ok(false) => {
+                        is_corrupted = true;
+                        Err(KvError::CorruptRecord {
+                            file: file.clone(),
+                            offset: frame_offset,
+                        })
+                    }
+                    Err(e) => {
+                        is_corrupted = true;
+                        Err(e)
+                    }
+                }
+            }
+            Ok((frame_len, Some(record))) => {
+                offset += frame_len;
+                Ok((frame_offset, record))
+            }
+            Ok((_, None)) => {
+                is_corrupted = true;
+                Err(KvError::CorruptRecord {
+                    file: file.clone(),
+                    offset: frame_offset,
+                })
+            }
+            Err(e) => {
+                is_corrupted = true;
+                Err(e)
+            }
+        };
+        Some(result)
+    }))
+}
+
+/// Copy `len` raw bytes of the streamed value from `reader` to `writer` followed by their CRC32.
+/// Returns the number of copied bytes of the value, it is less than `len` if `reader` is over.
+/// The checksum is written only after the whole value.
+pub fn encode_raw(reader: &mut dyn Read, writer: &mut dyn Write, len: u64) -> Result<u64> {
+    let (copied, checksum) = copy_hashed(reader, writer, len)?;
+    if copied == len {
+        writer.write_all(&checksum.to_le_bytes())?;
+    }
+    Ok(copied)
+}
+
+/// Copy `len` raw bytes of the streamed value from `reader` to `writer` and verify their CRC32.
+/// Returns `false` if the value is truncated or its checksum mismatches,
+/// bytes copied to `writer` are not trustworthy then.
+pub fn decode_raw(reader: &mut dyn Read, writer: &mut dyn Write, len: u64) -> Result<bool> {
+    let (copied, checksum) = copy_hashed(reader, writer, len)?;
+    if copied < len {
+        return Ok(false);
+    }
+    let mut expected = [0; RAW_CHECKSUM_LEN as usize];
+    if read_full(reader, &mut expected)? < expected.len() {
+        return Ok(false);
+    }
+    Ok(u32::from_le_bytes(expected) == checksum)
+}
+
+/// Copy up to `len` bytes from `reader` to `writer` by chunks.
+/// Returns the number of copied bytes and their CRC32.
+fn copy_hashed(reader: &mut dyn Read, writer: &mut dyn Write, len: u64) -> Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; RAW_CHUNK_LEN];
+    let mut copied = 0;
+    while copied < len {
+        let chunk_len = (len - copied).min(RAW_CHUNK_LEN as u64) as usize;
+        let read = read_full(reader, &mut buf[..chunk_len])?;
+        hasher.update(&buf[..read]);
+        writer.write_all(&buf[..read])?;
+        copied += read as u64;
+        if read < chunk_len {
+            break;
+        }
+    }
+    Ok((copied, hasher.finalize()))
+}
+
+/// Read one frame from `reader`.
+/// Returns `None` at the end of the stream, otherwise the length of the frame
+/// and the record or `None` if the frame is corrupted.
+pub fn decode_frame(reader: &mut dyn Read, header: DatafileHeader) -> Result<Option<(u64, Option<Record>)>> {
+    let mut prefix = [0; PREFIX_LEN];
+    match read_full(reader, &mut prefix)? {
+        0 => return Ok(None),
+        read if read < PREFIX_LEN => return Ok(Some((read as u64, None))),
+        _ => {}
+    }
+    let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+    let checksum = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+
+    // Payload is not preallocated because the length of corrupted frame is arbitrary
+    let mut payload = Vec::new();
+    (&mut *reader).take(len as u64).read_to_end(&mut payload)?;
+    let frame_len = (PREFIX_LEN + payload.len()) as u64;
+    if payload.len() < len || crc32fast::hash(&payload) != checksum {
+        return Ok(Some((frame_len, None)));
+    }
+
+    if let Some(value_codec) = header.compression.value_codec() {
+        payload = match value_codec.decode(&payload) {
+            Ok(payload) => payload,
+            Err(_) => return Ok(Some((frame_len, None))),
+        };
+    }
+
+    let record = header.record_codec().decode_record(&mut &payload[..])?;
+    Ok(Some((frame_len, record)))
+}
+
+/// Read from `reader` until `buf` is filled or the stream is over.
+/// Returns the number of read bytes.
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,79 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::engine::Result;
+
+/// Syncs of the active datafile shared by concurrent writers.
+///
+/// Every write waiting for the sync gets a ticket, tickets are issued in the order of writes.
+/// The first waiting writer becomes the leader: it waits for `window` to let other writers join,
+/// then syncs once for all tickets issued so far and wakes the followers up.
+/// Writers arriving during the sync wait for the next leader.
+#[derive(Debug)]
+pub struct GroupCommit {
+    window: Duration,
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    /// The last issued ticket.
+    issued: u64,
+    /// The last ticket covered by a successful sync.
+    synced: u64,
+    /// Some writer is syncing now.
+    is_syncing: bool,
+}
+
+impl GroupCommit {
+    pub fn new(window: Duration) -> Self {
+        GroupCommit {
+            window,
+            state: Mutex::new(CommitState::default()),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Issue the ticket of the write which is already passed to the OS.
+    /// It must be called under the lock of the writer, so tickets follow the order of writes.
+    pub fn issue(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.issued += 1;
+        state.issued
+    }
+
+    /// Get the last issued ticket.
+    pub fn last_issued(&self) -> u64 {
+        self.state.lock().unwrap().issued
+    }
+
+    /// Wait until the write of `ticket` is synced.
+    /// If no sync is in progress, the caller leads the group and calls `sync`,
+    /// which returns the last ticket covered by it.
+    /// # Error
+    /// The error of `sync` is returned to the leader only, followers elect the next leader to retry.
+    pub fn wait(&self, ticket: u64, sync: impl FnOnce() -> Result<u64>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.is_syncing && state.synced < ticket {
+            state = self.synced.wait(state).unwrap();
+        }
+        if state.synced >= ticket {
+            return Ok(());
+        }
+
+        state.is_syncing = true;
+        drop(state);
+        thread::sleep(self.window);
+        let result = sync();
+
+        let mut state = self.state.lock().unwrap();
+        state.is_syncing = false;
+        if let Ok(synced) = result {
+            state.synced = state.synced.max(synced);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+}
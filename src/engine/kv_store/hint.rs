@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::PathBuf;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use super::kv_store::Record;
+use super::utils::now_millis;
+use crate::engine::Result;
+
+/// Hint of the compacted passive datafile, it is kept in the file with the same serial number.
+/// The hint maps keys of the datafile to offsets of their records,
+/// so the datafile is indexed without reading its records.
+/// Compacted datafiles contain only actual records and removals superseding older datafiles,
+/// thus the hint describes the datafile completely.
+/// The hint is stale if the length of the datafile differs from the recorded one.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Hint {
+    pub datafile_len: u64,
+    pub entries: Vec<HintEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HintEntry {
+    pub key: String,
+    pub offset: u64,
+    pub expires_at: Option<u64>,
+    /// The record is the removal of the key.
+    #[serde(default)]
+    pub is_removal: bool,
+}
+
+impl HintEntry {
+    pub fn new(record: &Record, offset: u64) -> HintEntry {
+        let expires_at = match record {
+            Record::SetWithExpiry { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        };
+        HintEntry {
+            key: record.key().clone(),
+            offset,
+            expires_at,
+            is_removal: matches!(record, Record::Remove { .. }),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now_millis())
+    }
+}
+
+impl Hint {
+    /// Get path of the hint of the datafile, it has extension `hint_ext`.
+    pub fn path(datafile_path: &PathBuf, hint_ext: &str) -> PathBuf {
+        datafile_path.with_extension(hint_ext)
+    }
+
+    /// Read the hint of the datafile.
+    /// Returns `None` if the hint is missing, unreadable or stale.
+    pub fn load(datafile_path: &PathBuf, hint_ext: &str) -> Result<Option<Hint>> {
+        let path = Hint::path(datafile_path, hint_ext);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let hint: Hint = match serde_json::from_reader(fs::File::open(&path)?) {
+            Ok(hint) => hint,
+            Err(e) => {
+                warn!("Unable to read hint {:?}: {}", path, e);
+                return Ok(None);
+            }
+        };
+        if hint.datafile_len != fs::metadata(datafile_path)?.len() {
+            warn!("Hint {:?} is stale", path);
+            return Ok(None);
+        }
+        debug!("Load hint {:?} of {} keys", path, hint.entries.len());
+        Ok(Some(hint))
+    }
+
+    /// Write the hint of the datafile.
+    /// The hint is written to the temporary file first and then renamed,
+    /// so a crash never leaves a partially written hint.
+    pub fn store(&self, datafile_path: &PathBuf, hint_ext: &str) -> Result<()> {
+        let path = Hint::path(datafile_path, hint_ext);
+        debug!("Store hint {:?} of {} keys", path, self.entries.len());
+        let tmp_path = path.with_extension(format!("{}.tmp", hint_ext));
+        serde_json::to_writer(fs::File::create(&tmp_path)?, self)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
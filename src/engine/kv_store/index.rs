@@ -0,0 +1,82 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use lockfree::map::{Map, ReadGuard, Removed};
+
+use super::location::Location;
+
+/// Location of the value of the key removed from the `Index` or replaced in it.
+pub type IndexEntry = Removed<String, Location>;
+
+/// Lock-free hashmaps that associate a Key with location (position on the disk) of its Value.
+/// Index is used to get values faster.
+///
+/// Keys are partitioned into shards by their hashes, every key belongs to exactly one shard.
+/// Shards are updated independently, so writers of distinct keys rarely touch the same map,
+/// and shards are traversed in parallel, e.g. by compaction.
+pub struct Index {
+    shards: Vec<Map<String, Location>>,
+}
+
+impl Index {
+    /// Create the empty `Index` of `shards` shards, at least one.
+    pub fn new(shards: usize) -> Self {
+        Index {
+            shards: (0..shards.max(1)).map(|_| Map::new()).collect(),
+        }
+    }
+
+    /// Get the shard of `key`.
+    fn shard(&self, key: &str) -> &Map<String, Location> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Get all shards, e.g. to process them in parallel.
+    pub fn shards(&self) -> &[Map<String, Location>] {
+        &self.shards
+    }
+
+    pub fn get(&self, key: &str) -> Option<ReadGuard<'_, String, Location>> {
+        self.shard(key).get(key)
+    }
+
+    /// Set the location of `key`, the replaced one is returned.
+    pub fn insert(&self, key: String, location: Location) -> Option<IndexEntry> {
+        self.shard(&key).insert(key, location)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<IndexEntry> {
+        self.shard(key).remove(key)
+    }
+
+    /// Remove `key` if `condition` holds for its entry.
+    pub fn remove_with<F>(&self, key: &str, condition: F) -> Option<IndexEntry>
+    where
+        F: FnOnce(&(String, Location)) -> bool,
+    {
+        self.shard(key).remove_with(key, condition)
+    }
+
+    /// Iterate over entries of all shards one by one.
+    pub fn iter(&self) -> impl Iterator<Item = ReadGuard<'_, String, Location>> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    /// Get the number of keys.
+    pub fn count(&self) -> usize {
+        self.shards.iter().map(|shard| shard.iter().count()).sum()
+    }
+
+    /// Remove all keys.
+    /// Keys are collected before removing to avoid mutating the map while iterating it.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let keys: Vec<String> = shard.iter().map(|pair| pair.key().clone()).collect();
+            for key in keys {
+                shard.remove(&key);
+            }
+        }
+    }
+}
@@ -1,49 +1,97 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::fs;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
-use std::time::UNIX_EPOCH;
-use std::sync::{Arc, atomic::{AtomicBool, AtomicU64}, atomic::Ordering, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, AtomicUsize}, atomic::Ordering, Mutex, MutexGuard};
+use std::thread;
 
-use lockfree;
-use log::{debug, warn};
+use log::{debug, error, warn};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use wait_group::{SmartWaitGroup, Doer};
 
 
+use super::cache::ValueCache;
+use super::config::{CompactionStrategy, KvStoreConfig};
+use super::index::{Index, IndexEntry};
+use super::lazy_index::LazyIndex;
 use super::log::Log;
 use super::location::*;
+use super::manifest::Manifest;
+use super::snapshot;
+use super::verify::VerifyReport;
 use crate::engine::{
+    KvError,
     KvError::KeyNotFound,
     KvError::UnexpectedCommand,
+    KvStats,
     KvsEngine,
-    Result
+    Result,
+    WriteOp,
 };
 
-use crate::engine::kv_store::utils::{PASSIVE_EXT, ACTIVE_FILE_NAME};
-use lockfree::map::Removed;
-
-/// Max number of records in one data file.
-/// Compaction will be triggered after exceeding.
-const RECORDS_LIMIT: u64 = 1024; //todo make configurable
+use crate::engine::kv_store::utils::{BACKUP_DIR_PREFIX, PASSIVE_EXT, ACTIVE_FILE_NAME, KEY_LOCK_STRIPES, TREES_DIR_NAME, Utf8Reader, now_millis};
+use crate::engine::kvs_engine::add_to_value;
 
 /// Record in storage
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Record {
     Set { key: String, value: String },
+    SetWithExpiry { key: String, value: String, expires_at: u64 },
     Remove { key: String },
+    /// Marker of the batch of `len` records following it, they are applied all together.
+    /// Markers are resolved while reading datafiles, so they are never indexed.
+    BatchBegin { len: u64 },
+    /// Value of `len` raw bytes streamed by `KvStore::set_reader`.
+    /// The bytes follow the frame of the record with their own CRC32, so they are never buffered.
+    SetRaw { key: String, len: u64 },
 }
 
-/// A lock-free hashmap that associates a Key with location (position on the disk) of its Value.
-/// Index is used to get values faster.
-pub type Index = lockfree::map::Map<String, Location>;
-type IndexEntry = Removed<String, Location>;
+/// Key of records without a key, i.e. batch markers.
+static NO_KEY: String = String::new();
 
+impl Record {
+    /// Get the key of the record, the empty key for batch markers.
+    pub fn key(&self) -> &String {
+        match self {
+            Record::Set { key, .. } => key,
+            Record::SetWithExpiry { key, .. } => key,
+            Record::Remove { key } => key,
+            Record::SetRaw { key, .. } => key,
+            Record::BatchBegin { .. } => &NO_KEY,
+        }
+    }
+
+    /// Check if the record is expired.
+    /// `expires_at` is the absolute time in milliseconds since UNIX epoch.
+    pub fn is_expired(&self) -> bool {
+        match self {
+            Record::SetWithExpiry { expires_at, .. } => *expires_at <= now_millis(),
+            _ => false,
+        }
+    }
+}
 
 /// `KvStore` is a log-based storage engine that stores a pairs Key/Value.
 /// The `Log` is a persistent sequence of records on disk, that represents commands to storage like `Set` or `Remove`.
 /// All records are written to the end of the log. After updating or removing value from storage,
 /// related to this value records are not removed from log. Instead, new records are written to the end of the log.
 ///
+/// # Consistency
+/// Clones share the index, the value cache and the `Log`, so a write completed on one clone is observed
+/// by reads started afterwards on any clone. Compaction, dumping and clearing exclude other commands,
+/// which wait for their end, so reads never observe the storage in the middle of them.
+/// `fence` waits for their end explicitly.
+///
+/// # Shutdown
+/// The last instance compacts the `Log` when it is closed by `close`, which returns the error of compaction.
+/// It is the recommended shutdown path: dropping the last instance compacts the `Log` as well,
+/// but errors are only logged then.
+///
 /// # Example:
 /// ```rust
 /// use kvs::KvStore;
@@ -53,96 +101,918 @@ type IndexEntry = Removed<String, Location>;
 /// ```
 pub struct KvStore {
     index: Arc<Index>,
+    lazy_index: Arc<LazyIndex>,
     log: Arc<Log>,
     unused_records: Arc<AtomicU64>,
+    /// Unused records left by the last compaction, e.g. in the active datafile which is not dumped,
+    /// compaction is triggered only by unused records made after them.
+    unreclaimed_records: Arc<AtomicU64>,
+    compactions: Arc<AtomicU64>,
+    /// Estimate of `reclaimable_bytes` made by its last call or by the last compaction,
+    /// so `stats` never reads datafiles.
+    reclaimable_bytes: Arc<AtomicU64>,
+    /// Number of live instances sharing the storage, the last dropped one compacts the `Log`.
+    instances: Arc<AtomicUsize>,
+    /// The instance is released by `close`, so dropping it does nothing.
+    is_closed: bool,
+    /// Trees opened by `open_tree`, every tree is opened once and its handles are clones.
+    trees: Arc<Mutex<HashMap<String, KvStore>>>,
+    /// The storage is a tree of another one, it is compacted by the parent storage instead of releasing.
+    is_tree: bool,
+    key_locks: Arc<Vec<Mutex<()>>>,
+    cache: Arc<ValueCache>,
     backups_dir: Option<PathBuf>,
     commands_wg: SmartWaitGroup,
     compaction_wg: SmartWaitGroup,
+    config: KvStoreConfig,
 }
 
 impl KvsEngine for KvStore {
-    /// Open a `KvStore` with the given path.
+    /// Open a `KvStore` with the given path and default configuration.
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let path = path.into();
-        debug!("Open KvStore, path: {:?}", path);
-
-        let log = Arc::new(Log::open(&path)?);
-        let index = Arc::new(log.index()?);
-
-        Ok(KvStore {
-            index,
-            log,
-            unused_records: Arc::new(AtomicU64::new(0)),
-            backups_dir: None,
-            commands_wg: SmartWaitGroup::new(),
-            compaction_wg: SmartWaitGroup::new(),
-        })
+        KvStore::open_with_config(path, KvStoreConfig::default())
     }
 
     /// Get the value of a given key.
-    /// Returns `None` if the given key does not exist.
+    /// Returns `None` if the given key does not exist or is expired.
+    /// Values are read from the value cache if it is enabled by `cache_capacity` of the config.
     fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.cache.get(&key) {
+            return Ok(Some(value));
+        }
         let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
         debug!("Get key: {}", key);
+        let epoch = self.cache.epoch();
+        self.lazy_index.resolve(&key, &self.log, &self.index)?;
         self.index
             .get(&key)
             .map_or(
                 Ok(None),
                 |pair| {
-                    match self.log.get_record(pair.val())? {
-                        Record::Set { value, .. } => Ok(Some(value)),
-                        Record::Remove { .. } => Err(UnexpectedCommand), //todo rly?
+                    let record = self.materialized_record(pair.val())?;
+                    if record.is_expired() {
+                        self.drop_expired(pair.val(), &key);
+                        return Ok(None);
+                    }
+                    match record {
+                        // Values with TTL are not cached, so they are never returned after expiration
+                        Record::Set { value, .. } => {
+                            self.cache.insert(key.clone(), value.clone(), epoch);
+                            Ok(Some(value))
+                        }
+                        Record::SetWithExpiry { value, .. } => Ok(Some(value)),
+                        Record::Remove { .. } | Record::BatchBegin { .. } | Record::SetRaw { .. } => {
+                            Err(index_corruption(&key))
+                        }
                     }
                 })
     }
 
+    /// Check if `key` is present in the `Index`, datafiles are not read.
+    /// The expired key is reported present until it is dropped by `get` or compaction.
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        debug!("Check key: {}", key);
+        self.lazy_index.resolve(key, &self.log, &self.index)?;
+        Ok(self.index.get(key).is_some())
+    }
+
     /// Set the key and value
     fn set(&self, key: String, value: String) -> Result<()> {
-        let mut prev_location = None;
-        {
-            let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
-            debug!("Set key: {}, value: {}", key, value);
-            let cmd = Record::Set { key: key.clone(), value };
-            let location = self.log.set_record(&cmd)?;
-            prev_location = self.index.insert(key, location);
-        }
-        self.check_and_compact_log(prev_location)
+        debug!("Set key: {}, value: {}", key, value);
+        let cmd = Record::Set { key: key.clone(), value };
+        let _key_lock = self.lock_key(&key);
+        self.set_record(key, cmd)
     }
 
     /// Remove a given key.
     /// # Error
     /// It returns `KvError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()> {
+        let _key_lock = self.lock_key(&key);
+        self.remove_record(key)
+    }
+
+    /// Compare and swap the value of `key` in one critical section with `set` and `remove`
+    /// of the same key, they are serialized by the striped lock of keys.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let _key_lock = self.lock_key(&key);
+        debug!("Compare and swap key: {}, expected: {:?}, new: {:?}", key, expected, new);
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                let cmd = Record::Set { key: key.clone(), value };
+                self.set_record(key, cmd)?;
+            }
+            None => {
+                if current.is_some() {
+                    self.remove_record(key)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Add `delta` to the integer value of `key` in one critical section with `set` and `remove`
+    /// of the same key, they are serialized by the striped lock of keys.
+    /// The key with TTL keeps its expiration time.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let _key_lock = self.lock_key(&key);
+        debug!("Increment key: {}, delta: {}", key, delta);
+        let (value, expires_at) = match self.live_record(&key)? {
+            Some(Record::Set { value, .. }) => (Some(value), None),
+            Some(Record::SetWithExpiry { value, expires_at, .. }) => (Some(value), Some(expires_at)),
+            Some(_) => return Err(index_corruption(&key)),
+            None => (None, None),
+        };
+        let value = add_to_value(value.as_ref(), delta)?;
+        let cmd = match expires_at {
+            Some(expires_at) => Record::SetWithExpiry { key: key.clone(), value: value.to_string(), expires_at },
+            None => Record::Set { key: key.clone(), value: value.to_string() },
+        };
+        self.set_record(key, cmd)?;
+        Ok(value)
+    }
+
+    /// Write records of `ops` as one batch and update the `Index` while other commands are excluded
+    /// like `clear` does, so concurrent readers observe the storage either before or after the batch.
+    /// The batch cut off by a crash is rolled back while reopening the storage.
+    /// # Error
+    /// It returns `KvError::KeyTooLarge` or `KvError::ValueTooLarge` if any operation exceeds limits
+    /// of the config, nothing is written then.
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<()> {
+        for op in &ops {
+            if let WriteOp::Set { key, value } = op {
+                self.check_size(key.len(), value.len() as u64)?;
+            }
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let keys = ops.iter().map(WriteOp::key).collect::<Vec<_>>();
+        let _key_locks = self.lock_keys(&keys);
+
+        let mut prev_locations = Vec::new();
+        {
+            let _batch_doer = self.wait_unique();
+            debug!("Write batch of {} operations", ops.len());
+            // Previous records of keys are counted as unused only if they are indexed
+            for key in &keys {
+                self.lazy_index.resolve(key, &self.log, &self.index)?;
+            }
+            let records = ops
+                .into_iter()
+                .map(|op| match op {
+                    WriteOp::Set { key, value } => Record::Set { key, value },
+                    WriteOp::Remove { key } => Record::Remove { key },
+                })
+                .collect::<Vec<_>>();
+            let locations = self.log.set_batch(&records)?;
+            for (record, location) in records.into_iter().zip(locations) {
+                match record {
+                    Record::Remove { key } => {
+                        // The removal is kept as a tombstone for not indexed datafiles
+                        if self.lazy_index.update(Some(&key), || self.index.remove(&key)).is_some() {
+                            self.unused_records.fetch_add(1, Ordering::SeqCst);
+                        }
+                        self.cache.invalidate(&key);
+                    }
+                    record => {
+                        let key = record.key().clone();
+                        prev_locations.push(self.lazy_index.update(None, || self.index.insert(key, location)));
+                        self.cache.invalidate(record.key());
+                    }
+                }
+            }
+        }
+        for prev_location in prev_locations {
+            self.check_and_compact_log(prev_location)?;
+        }
+        self.check_and_rotate_log()
+    }
+
+    fn max_key_bytes(&self) -> Option<usize> {
+        self.config.max_key_bytes
+    }
+
+    fn max_value_bytes(&self) -> Option<usize> {
+        self.config.max_value_bytes
+    }
+
+    /// Get the number of keys in the `Index`.
+    /// Removed keys are absent in the `Index`, so their records in the `Log` don't inflate the count.
+    /// Expired keys are counted until they are dropped by `get` or compaction.
+    fn len(&self) -> usize {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        if let Err(e) = self.lazy_index.resolve_all(&self.log, &self.index) {
+            warn!("Unable to index all datafiles: {}", e);
+        }
+        self.index.count()
+    }
+
+    /// Get keys starting with `prefix` like `scan` does, keys are sorted before applying `limit`.
+    fn scan_keys(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self.scan(prefix).collect();
+        keys.sort();
+        if let Some(limit) = limit {
+            keys.truncate(limit);
+        }
+        Ok(keys)
+    }
+
+    /// Sync the active datafile to disk, passive datafiles are synced when they are created.
+    fn flush(&self) -> Result<()> {
+        debug!("Flush KvStore");
+        self.log.sync()
+    }
+
+    /// Compact the `Log` regardless of the number of unused records.
+    /// Does nothing if compaction is already in progress.
+    fn compact(&self) -> Result<()> {
+        if let Some(compact_doer) = self.compaction_wg.switch_unique(&self.commands_wg) {
+            debug!("Manual compaction triggered");
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    /// Dump the active datafile to the new passive one, waits for the end of the running compaction.
+    /// Other commands are excluded while dumping, so concurrent reads and writes are not affected.
+    fn rotate(&self) -> Result<()> {
+        let _rotation_doer = self.wait_unique();
+        debug!("Rotation triggered");
+        self.lazy_index.resolve_all(&self.log, &self.index)?;
+        self.dump_log()
+    }
+
+    /// Remove all keys of the storage, see `KvStore::clear`.
+    fn clear(&self) -> Result<()> {
+        KvStore::clear(self)
+    }
+
+    /// Read the record of every live `Location` and check that it sets the value of its key.
+    /// All datafiles are indexed first if the storage is indexed lazily.
+    /// Only referred records are read, use `verify_backup` to check whole datafiles.
+    fn verify(&self) -> Result<VerifyReport> {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        debug!("Verify KvStore");
+        self.lazy_index.resolve_all(&self.log, &self.index)?;
+        let mut report = VerifyReport::default();
+        report.datafiles = self.log.datafiles().len();
+        report.records = self.log.verify_index(&self.index, &mut report);
+        debug!("Verify report: {:?}", report);
+        Ok(report)
+    }
+
+    /// Get statistics of the storage.
+    /// In the lazy indexing mode only records of indexed datafiles are counted.
+    /// Reclaimable bytes are estimated by the last compaction or call of `reclaimable_bytes`,
+    /// they are not estimated again, as it reads all datafiles.
+    fn stats(&self) -> KvStats {
+        KvStats {
+            live_keys: self.len() as u64,
+            records: self.log.records.load(Ordering::SeqCst),
+            unused_records: self.unused_records.load(Ordering::SeqCst),
+            passive_files: self.log.last_serial_number.load(Ordering::SeqCst),
+            compactions: self.compactions.load(Ordering::SeqCst),
+            compacted_bytes: self.log.compacted_bytes.load(Ordering::SeqCst),
+            syncs: self.log.syncs.load(Ordering::SeqCst),
+            reclaimable_bytes: self.reclaimable_bytes.load(Ordering::SeqCst),
+            cache_hits: self.cache.hits(),
+            cache_misses: self.cache.misses(),
+        }
+    }
+}
+
+impl KvStore {
+    /// Remove a given key, the lock of the key must be held.
+    fn remove_record(&self, key: String) -> Result<()> {
         let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
         debug!("Remove key: {}", key);
+        self.lazy_index.resolve(&key, &self.log, &self.index)?;
         let cmd = Record::Remove { key: key.clone() };
-        self.log.set_record(&cmd)?;
-        self.index
-            .remove(&key)
-            .ok_or(KeyNotFound)?;
+        self.lazy_index.update(Some(&key), || -> Result<()> {
+            self.log.set_record(&cmd)?;
+            self.index
+                .remove(&key)
+                .ok_or(KeyNotFound)?;
+            Ok(())
+        })?;
+        self.cache.invalidate(&key);
         self.unused_records.fetch_add(1, Ordering::SeqCst);
-        Ok(())
+        self.check_and_rotate_log()
     }
 }
 
 impl KvStore {
+    /// Set the key and value which expires after `ttl`.
+    /// Expired keys are not returned by `get`, they are dropped from the `Index` lazily
+    /// and are not written to new passive datafiles during compaction.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        debug!("Set key: {}, value: {}, ttl: {:?}", key, value, ttl);
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        let cmd = Record::SetWithExpiry { key: key.clone(), value, expires_at };
+        let _key_lock = self.lock_key(&key);
+        self.set_record(key, cmd)
+    }
+
+    /// Remove `key` if it is present, returns whether it was removed.
+    /// Unlike `remove`, the absent key is not an error and nothing is written to the log for it.
+    pub fn remove_if_present(&self, key: String) -> Result<bool> {
+        let _key_lock = self.lock_key(&key);
+        if !self.contains_key(&key)? {
+            debug!("Key to remove is absent: {}", key);
+            return Ok(false);
+        }
+        match self.remove_record(key) {
+            Ok(()) => Ok(true),
+            // The key is dropped by concurrent `clear`
+            Err(KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set the value of `key` of `len` bytes streamed from `reader`, the value is never buffered.
+    /// Values are strings like the ones of `set`, so bytes are checked to be UTF-8 while streaming.
+    /// Compaction reads values into memory, only values of at least 64 KiB are written streamed again.
+    /// # Error
+    /// It returns `KvError::KeyTooLarge` or `KvError::ValueTooLarge` like `set`,
+    /// or `KvError::StorageFileError` if `reader` fails, is shorter than `len` or is not UTF-8,
+    /// nothing is stored then.
+    pub fn set_reader(&self, key: String, reader: impl Read, len: u64) -> Result<()> {
+        debug!("Set key: {}, streamed value of {} bytes", key, len);
+        self.check_size(key.len(), len)?;
+        let mut reader = Utf8Reader::new(reader, len);
+        let _key_lock = self.lock_key(&key);
+        let raw_key = key.clone();
+        self.update_value(key, || self.log.set_raw(&raw_key, &mut reader, len))
+    }
+
+    /// Write the value of `key` to `writer`, streamed values are copied without buffering.
+    /// Returns the length of the value or `None` if the given key does not exist or is expired.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if the value is corrupted,
+    /// bytes written to `writer` before the error are not trustworthy.
+    pub fn get_writer(&self, key: String, mut writer: impl Write) -> Result<Option<u64>> {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        debug!("Get key: {} to writer", key);
+        self.lazy_index.resolve(&key, &self.log, &self.index)?;
+        let pair = match self.index.get(&key) {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        match self.log.read_value(pair.val(), &mut writer)? {
+            Record::SetRaw { len, .. } => Ok(Some(len)),
+            record if record.is_expired() => {
+                self.drop_expired(pair.val(), &key);
+                Ok(None)
+            }
+            Record::Set { value, .. } | Record::SetWithExpiry { value, .. } => {
+                writer.write_all(value.as_bytes())?;
+                Ok(Some(value.len() as u64))
+            }
+            Record::Remove { .. } | Record::BatchBegin { .. } => Err(index_corruption(&key)),
+        }
+    }
+
+    /// Get the value of `key` with the location of its record, e.g. to diagnose compaction or reindexing.
+    /// The value is always read from its datafile bypassing the value cache.
+    /// Returns `None` if the key does not exist or is expired.
+    pub fn peek(&self, key: String) -> Result<Option<(String, LocationInfo)>> {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        debug!("Peek key: {}", key);
+        self.lazy_index.resolve(&key, &self.log, &self.index)?;
+        let pair = match self.index.get(&key) {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        match self.materialized_record(pair.val())? {
+            record if record.is_expired() => Ok(None),
+            Record::Set { value, .. } | Record::SetWithExpiry { value, .. } => {
+                Ok(Some((value, LocationInfo::from(pair.val()))))
+            }
+            Record::Remove { .. } | Record::BatchBegin { .. } | Record::SetRaw { .. } => Err(index_corruption(&key)),
+        }
+    }
+
+    /// Get the current record setting `key` from its datafile bypassing the value cache.
+    /// Returns `None` if the key does not exist or is expired.
+    fn live_record(&self, key: &str) -> Result<Option<Record>> {
+        let _commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        self.lazy_index.resolve(key, &self.log, &self.index)?;
+        let pair = match self.index.get(key) {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        let record = self.materialized_record(pair.val())?;
+        if record.is_expired() {
+            self.drop_expired(pair.val(), &key.to_owned());
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    /// Get record from `Log` by `Location`, the streamed value is read into `Record::Set`.
+    fn materialized_record(&self, location: &Location) -> Result<Record> {
+        let mut value = Vec::new();
+        match self.log.read_value(location, &mut value)? {
+            Record::SetRaw { key, .. } => Ok(Record::Set { key, value: String::from_utf8(value)? }),
+            record => Ok(record),
+        }
+    }
+
+    /// Open a `KvStore` with the given path and configuration.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if a passive datafile is corrupted, unless the storage
+    /// is indexed lazily, then the error is returned by the first command reading the datafile.
+    /// It returns `KvError::ManifestMismatch` if the codec, compression or chunk size set in `config`
+    /// differs from the manifest of the storage.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<Self> {
+        let path = path.into();
+        debug!("Open KvStore, path: {:?}, config: {:?}", path, config);
+        let log = Log::open(&path, &config)?;
+        let store = KvStore::with_log(log, config)?;
+        if store.config.compact_on_open {
+            store.compact_on_open()?;
+        }
+        Ok(store)
+    }
+
+    /// Compact the `Log` if it has reclaimable records, e.g. left by the unclean shutdown.
+    fn compact_on_open(&self) -> Result<()> {
+        let reclaimable_bytes = self.reclaimable_bytes()?;
+        if reclaimable_bytes == 0 {
+            debug!("Nothing to reclaim on opening");
+            return Ok(());
+        }
+        debug!("Compact {} reclaimable bytes on opening", reclaimable_bytes);
+        self.compact()
+    }
+
+    /// Open a `KvStore` with the given path for reading only.
+    /// Nothing is written to the directory: writes and compaction fail with `KvError::ReadOnly`,
+    /// the storage is not compacted on dropping. The directory may be opened for reading
+    /// by multiple handles, but changes made by writers after opening are not visible.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        debug!("Open KvStore for reading only, path: {:?}", path);
+        let config = KvStoreConfig::default();
+        let log = Log::open_read_only(&path, &config)?;
+        KvStore::with_log(log, config)
+    }
+
+    /// Create a `KvStore` over the opened `Log`.
+    fn with_log(log: Log, config: KvStoreConfig) -> Result<Self> {
+        let log = Arc::new(log);
+        let (index, lazy_index) = if config.lazy_indexing {
+            (Index::new(config.index_shards), LazyIndex::new(log.datafiles()))
+        } else {
+            (log.index(config.index_shards)?, LazyIndex::complete())
+        };
+
+        Ok(KvStore {
+            index: Arc::new(index),
+            lazy_index: Arc::new(lazy_index),
+            log,
+            unused_records: Arc::new(AtomicU64::new(0)),
+            unreclaimed_records: Arc::new(AtomicU64::new(0)),
+            compactions: Arc::new(AtomicU64::new(0)),
+            reclaimable_bytes: Arc::new(AtomicU64::new(0)),
+            instances: Arc::new(AtomicUsize::new(1)),
+            is_closed: false,
+            trees: Arc::new(Mutex::new(HashMap::new())),
+            is_tree: false,
+            key_locks: Arc::new((0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect()),
+            cache: Arc::new(ValueCache::new(config.cache_capacity.unwrap_or(0))),
+            backups_dir: None,
+            commands_wg: SmartWaitGroup::new(),
+            compaction_wg: SmartWaitGroup::new(),
+            config,
+        })
+    }
+
+    /// Close the instance, the last instance compacts the `Log` like dropping does.
+    /// Other instances are just released, the storage stays open for them.
+    /// # Error
+    /// It returns the error of the final compaction instead of logging it.
+    pub fn close(mut self) -> Result<()> {
+        debug!("Close KvStore");
+        self.is_closed = true;
+        self.release()
+    }
+
+    /// Release the instance and compact the `Log` if it is the last one.
+    fn release(&self) -> Result<()> {
+        // We must compact the log only if we release the last ("main") instance of KvStore.
+        // Instances are counted explicitly: checking the count of `Arc` pointers is racy
+        // if clones are released concurrently, while `fetch_sub` returns 1 to exactly one of them.
+        if self.instances.fetch_sub(1, Ordering::SeqCst) != 1 {
+            debug!("No compaction while release");
+            return Ok(());
+        }
+        if self.log.is_read_only() {
+            debug!("No compaction while release of read-only KvStore");
+            return Ok(());
+        }
+        if self.is_tree {
+            debug!("No compaction while release of tree, it is compacted by the parent storage");
+            return Ok(());
+        }
+        self.compact_log()
+    }
+
+    /// Lock the stripe of `key`.
+    /// Writes of the same key are serialized by the lock, different keys may share a stripe.
+    fn lock_key(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.key_locks[self.key_stripe(key)].lock().unwrap()
+    }
+
+    /// Lock all `keys` like `lock_key` does.
+    /// Stripes are locked once each in the ascending order, so concurrent batches never deadlock.
+    fn lock_keys(&self, keys: &[&str]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes = keys.iter().map(|key| self.key_stripe(key)).collect::<Vec<_>>();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| self.key_locks[stripe].lock().unwrap())
+            .collect()
+    }
+
+    /// Get the index of the lock stripe of `key`.
+    fn key_stripe(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.key_locks.len()
+    }
+
+    /// Write a record setting the value of `key` and update the index.
+    /// The lock of the key must be held.
+    /// # Error
+    /// It returns `KvError::KeyTooLarge` or `KvError::ValueTooLarge` if the record exceeds limits
+    /// of the config, nothing is written then.
+    fn set_record(&self, key: String, cmd: Record) -> Result<()> {
+        if let Record::Set { value, .. } | Record::SetWithExpiry { value, .. } = &cmd {
+            self.check_size(key.len(), value.len() as u64)?;
+        }
+        self.update_value(key, || self.log.set_record(&cmd))
+    }
+
+    /// Write the value of `key` by `write` and update the index, the lock of the key must be held.
+    /// The key is resolved first if the storage is indexed lazily, so its previous record is counted as unused.
+    fn update_value(&self, key: String, write: impl FnOnce() -> Result<Location>) -> Result<()> {
+        let mut prev_location = None;
+        {
+            let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+            self.lazy_index.resolve(&key, &self.log, &self.index)?;
+            prev_location = self.lazy_index.update(None, || -> Result<_> {
+                let location = write()?;
+                Ok(self.index.insert(key.clone(), location))
+            })?;
+            self.cache.invalidate(&key);
+        }
+        self.check_and_compact_log(prev_location)?;
+        self.check_and_rotate_log()
+    }
+
+    /// Drop expired `key` from the index.
+    /// The key is dropped only if it still refers to `location`, so a concurrent `set` is not lost.
+    fn drop_expired(&self, location: &Location, key: &String) {
+        debug!("Drop expired key: {}", key);
+        let removed = self.lazy_index.update(Some(key), || {
+            self.index.remove_with(key, |(_, current)| {
+                current.offset == location.offset && current.file.path == location.file.path
+            })
+        });
+        if removed.is_some() {
+            self.unused_records.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
     fn check_and_compact_log(&self, prev_location: Option<IndexEntry>) -> Result<()> {
         debug!("Check previous value (IndexEntry) by this key");
         if let Some(_) = prev_location {
-            self.unused_records.fetch_add(1, Ordering::SeqCst);
-            debug!("Increased unused records: {}", self.unused_records.load(Ordering::SeqCst));
+            let unused_records = self.unused_records.fetch_add(1, Ordering::SeqCst) + 1;
+            debug!("Increased unused records: {}", unused_records);
 
-            if self.unused_records.load(Ordering::SeqCst) > RECORDS_LIMIT {
+            // The counter is decreased by the compaction itself, so it is kept
+            // if the compaction is already in progress and this one is skipped
+            if self.exceeds_records_limit() {
+                // Nothing to do if the compaction is already in progress.
+                // The limit is checked again under the lock, the compaction may have just finished
                 if let Some(compact_doer) = self.compaction_wg.switch_unique(&self.commands_wg) {
-                    debug!("Unused records exceeds records limit({}). Compaction triggered", RECORDS_LIMIT);
-                    self.compact_log()?;
-                    self.unused_records.store(0, Ordering::SeqCst);
+                    if self.exceeds_records_limit() {
+                        debug!("Unused records exceeds records limit({}). Compaction triggered", self.config.records_limit);
+                        self.compact_log()?;
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// Check if unused records made after the last compaction exceed `records_limit` of the config.
+    fn exceeds_records_limit(&self) -> bool {
+        let unused_records = self.unused_records.load(Ordering::SeqCst);
+        let unreclaimed_records = self.unreclaimed_records.load(Ordering::SeqCst);
+        unused_records.saturating_sub(unreclaimed_records) > self.config.records_limit
+    }
+
+    /// Remove all keys of the storage.
+    /// Clearing waits for the running compaction and excludes other commands like compaction does,
+    /// so concurrent readers observe the storage either before or after clearing.
+    pub fn clear(&self) -> Result<()> {
+        let _clear_doer = self.wait_unique();
+        debug!("Clear KvStore");
+
+        self.log.clear()?;
+        self.lazy_index.clear();
+        self.index.clear();
+        self.cache.clear();
+        self.unused_records.store(0, Ordering::SeqCst);
+        self.unreclaimed_records.store(0, Ordering::SeqCst);
+        self.reclaimable_bytes.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Load `pairs` into the storage in parallel, the final state is the same as after `set` of them
+    /// in order. Records are written to new passive datafiles in parallel bypassing the active datafile,
+    /// and loaded keys are indexed when all datafiles are written. Other commands are excluded
+    /// while datafiles are written, like `clear` does.
+    /// # Error
+    /// It returns `KvError::KeyTooLarge` or `KvError::ValueTooLarge` if any pair exceeds limits
+    /// of the config, nothing is written then.
+    pub fn bulk_load<P>(&self, pairs: P) -> Result<()>
+    where
+        P: IntoParallelIterator<Item = (String, String)>,
+        P::Iter: IndexedParallelIterator,
+    {
+        // Later pairs of the same key override earlier ones like sequential `set` does
+        let latest = pairs
+            .into_par_iter()
+            .enumerate()
+            .fold(
+                || Ok(HashMap::new()),
+                |latest: Result<HashMap<String, (usize, String)>>, (pos, (key, value))| {
+                    let mut latest = latest?;
+                    self.check_size(key.len(), value.len() as u64)?;
+                    insert_latest(&mut latest, key, pos, value);
+                    Ok(latest)
+                },
+            )
+            .reduce(
+                || Ok(HashMap::new()),
+                |left, right| {
+                    let mut left = left?;
+                    for (key, (pos, value)) in right? {
+                        insert_latest(&mut left, key, pos, value);
+                    }
+                    Ok(left)
+                },
+            )?;
+        let records = latest
+            .into_iter()
+            .map(|(key, (_, value))| Record::Set { key, value })
+            .collect::<Vec<_>>();
+
+        let _load_doer = self.wait_unique();
+        self.lazy_index.resolve_all(&self.log, &self.index)?;
+        // Records of the active datafile are older than loaded ones, so they are dumped before
+        self.dump_log()?;
+        for (key, location) in self.log.bulk_load(records)? {
+            self.cache.invalidate(&key);
+            if self.index.insert(key, location).is_some() {
+                self.unused_records.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the end of the running compaction, dumping or clearing of the storage.
+    /// Reads after the fence observe all writes completed before it on any clone.
+    pub fn fence(&self) {
+        debug!("Fence KvStore");
+        drop(self.commands_wg.switch_wait_do(&self.compaction_wg));
+    }
+
+    /// Exclude other commands and compaction, waits for the end of the running compaction.
+    fn wait_unique(&self) -> Doer {
+        loop {
+            if let Some(unique_doer) = self.compaction_wg.switch_unique(&self.commands_wg) {
+                return unique_doer;
+            }
+            // Wait for the end of the running compaction
+            drop(self.commands_wg.switch_wait_do(&self.compaction_wg));
+        }
+    }
+
+    /// Write all live keys and values to `writer` as a stream of length-delimited records.
+    /// The snapshot is point-in-time: records are read while other commands are excluded,
+    /// and are written to `writer` afterwards. Keys with TTL keep their expiration time.
+    pub fn export(&self, mut writer: impl Write) -> Result<()> {
+        let records = {
+            let _export_doer = self.wait_unique();
+            debug!("Export KvStore");
+            self.lazy_index.resolve_all(&self.log, &self.index)?;
+            self.actual_commands()
+                .into_iter()
+                .filter(|record| !matches!(record, Ok(record) if record.is_expired()))
+                .collect::<Result<Vec<_>>>()?
+        };
+        for record in &records {
+            snapshot::write_entry(&mut writer, record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load keys and values exported by `export` into the empty storage.
+    /// # Error
+    /// It returns `KvError::NotEmpty` if the storage has keys.
+    pub fn import(&self, mut reader: impl Read) -> Result<()> {
+        debug!("Import KvStore");
+        if !self.is_empty() {
+            return Err(KvError::NotEmpty);
+        }
+        while let Some(record) = snapshot::read_entry(&mut reader)? {
+            match record {
+                Record::Remove { .. } | Record::BatchBegin { .. } | Record::SetRaw { .. } => {
+                    return Err(UnexpectedCommand)
+                }
+                record => {
+                    let key = record.key().clone();
+                    let _key_lock = self.lock_key(&key);
+                    self.set_record(key, record)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Dump the active datafile if its size exceeds `max_active_bytes` of the config.
+    /// All datafiles are indexed first if the storage is indexed lazily,
+    /// otherwise records of the dumped active datafile are never indexed.
+    fn check_and_rotate_log(&self) -> Result<()> {
+        if !self.log.is_active_full() {
+            return Ok(());
+        }
+        if let Some(rotation_doer) = self.compaction_wg.switch_unique(&self.commands_wg) {
+            debug!("Active datafile exceeds {:?} bytes. Dump triggered", self.config.max_active_bytes);
+            self.lazy_index.resolve_all(&self.log, &self.index)?;
+            self.dump_log()?;
+        }
+        Ok(())
+    }
+
+    /// Return an iterator over all keys of the storage.
+    /// Only the `Index` is traversed, values are not read from disk.
+    /// All datafiles are indexed first if the storage is indexed lazily.
+    ///
+    /// Keys are collected while holding the commands guard, so the snapshot is consistent with
+    /// respect to compaction: compaction can not start in the middle of the traversal.
+    /// Concurrent `set` and `remove` are best-effort: they may or may not be reflected.
+    pub fn keys(&self) -> impl Iterator<Item = String> {
+        self.scan("")
+    }
+
+    /// Return an iterator over all keys starting with `prefix`.
+    /// Has the same consistency guarantees as `keys`.
+    pub fn scan(&self, prefix: &str) -> impl Iterator<Item = String> {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        debug!("Scan keys, prefix: {}", prefix);
+        if let Err(e) = self.lazy_index.resolve_all(&self.log, &self.index) {
+            warn!("Unable to index all datafiles: {}", e);
+        }
+        self.index
+            .iter()
+            .filter(|pair| pair.key().starts_with(prefix))
+            .map(|pair| pair.key().clone())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Return a lazy iterator over all live keys and their values.
+    /// Keys are collected like `keys` does, but every value is read from disk only when it is pulled,
+    /// so values are never held in memory together.
+    ///
+    /// Values are resolved through the live `Index` at pull time, so the iteration survives
+    /// compaction moving records between datafiles. Keys removed after the iteration started are
+    /// skipped, values overwritten after it started are returned as the newest ones.
+    pub fn entries(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.keys().filter_map(move |key| match self.get(key.clone()) {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Remove all keys starting with `prefix`, returns the number of removed keys.
+    /// A `Remove` record is written for each key, so compaction eventually reclaims them.
+    /// Keys are collected like `scan` does, keys removed concurrently are not counted.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        debug!("Remove keys, prefix: {}", prefix);
+        let mut removed = 0;
+        for key in self.scan(prefix) {
+            let _key_lock = self.lock_key(&key);
+            match self.remove_record(key) {
+                Ok(()) => removed += 1,
+                Err(KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Open the named keyspace of the storage.
+    /// The tree is the separate `KvStore` with its own `Log` and `Index` in the subdirectory
+    /// `trees/<name>` of the storage, so its keys never collide with keys of other trees.
+    /// Every tree is opened once, further calls return clones of it sharing its `Log` and `Index`.
+    /// The tree is opened with the configuration of the storage and is compacted along with it,
+    /// dropping tree handles never compacts. Backups directory is not inherited.
+    /// Trees of the read-only storage are opened for reading only.
+    /// # Error
+    /// It returns `KvError::InvalidTreeName` if `name` is empty or contains characters
+    /// other than ASCII alphanumerics, `-` and `_`.
+    pub fn open_tree(&self, name: &str) -> Result<KvStore> {
+        debug!("Open tree: {}", name);
+        let is_valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid {
+            return Err(KvError::InvalidTreeName(name.to_owned()));
+        }
+
+        let mut trees = self.trees.lock().unwrap();
+        if let Some(tree) = trees.get(name) {
+            return Ok(tree.clone());
+        }
+        let tree_path = self.log.dir_path.join(TREES_DIR_NAME).join(name);
+        let mut tree = if self.log.is_read_only() {
+            KvStore::open_read_only(tree_path)?
+        } else {
+            fs::create_dir_all(&tree_path)?;
+            KvStore::open_with_config(tree_path, self.config.clone())?
+        };
+        tree.is_tree = true;
+        trees.insert(name.to_owned(), tree.clone());
+        Ok(tree)
+    }
+
+    /// Compact opened trees having unused records, the running compaction of a tree is not awaited.
+    fn compact_trees(&self) -> Result<()> {
+        // Trees are cloned, so opening trees is not blocked by their compaction
+        let trees = self.trees.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        for tree in trees {
+            if tree.unused_records.load(Ordering::SeqCst) > 0 {
+                tree.compact()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get `Manifest` describing the format and the datafiles of the storage.
+    pub fn manifest(&self) -> Manifest {
+        self.log.manifest()
+    }
+
+    /// Verify that the backup at `backup_dir` is restorable.
+    /// The backup is opened for reading only and reindexed, then every record referred
+    /// by the index is read back. Nothing is written to `backup_dir`.
+    pub fn verify_backup(backup_dir: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let backup_dir = backup_dir.into();
+        debug!("Verify backup, path: {:?}", backup_dir);
+        Log::open_read_only(&backup_dir, &KvStoreConfig::default())?.verify()
+    }
+
+    /// Estimate the number of bytes which would be reclaimed by the full compaction,
+    /// i.e. sizes of records which are overwritten, removed or are removal records.
+    /// All datafiles are indexed first if the storage is indexed lazily.
+    /// The estimate is reported by `stats` until the next call or compaction.
+    pub fn reclaimable_bytes(&self) -> Result<u64> {
+        let commands_doer = self.commands_wg.switch_wait_do(&self.compaction_wg);
+        self.lazy_index.resolve_all(&self.log, &self.index)?;
+        self.estimate_reclaimable_bytes()
+    }
+
+    /// Estimate reclaimable bytes of all indexed datafiles and keep the estimate for `stats`.
+    /// Commands or compaction must be excluded.
+    fn estimate_reclaimable_bytes(&self) -> Result<u64> {
+        let reclaimable_bytes = self.log
+            .datafiles()
+            .iter()
+            .map(|datafile_path| self.log.dead_bytes(datafile_path, &self.index))
+            .sum::<Result<u64>>()?;
+        self.reclaimable_bytes.store(reclaimable_bytes, Ordering::SeqCst);
+        Ok(reclaimable_bytes)
+    }
+
+    /// Get datafiles which are not indexed yet if the storage is indexed lazily.
+    pub fn unindexed_datafiles(&self) -> Vec<PathBuf> {
+        self.lazy_index.pending_datafiles()
+    }
+
     /// Set path for saving backups.
     pub fn set_backups_dir<T>(&mut self, path: T)
     where
@@ -182,42 +1052,83 @@ impl KvStore {
 
     /// Compact the `Log`.
     /// Compaction is the process of removing deprecated records from passive datafiles of `Log`.
-    /// Old passive datafiles will be replaced by new ones with only actual records.
-    /// Backup will be created if specified.
+    /// Old passive datafiles will be replaced by new ones with only actual records,
+    /// only the newest small ones are replaced by the tiered compaction.
+    /// The active datafile is dumped first unless `dump_on_compaction` of the config is disabled
+    /// and `max_active_bytes` is set.
+    /// Backup will be created if specified. Opened trees are compacted as well.
+    /// Unused records are decreased by the number of records dropped from datafiles.
     fn compact_log(&self) -> Result<()> {
         debug!("Compact log");
-        self.dump_log()?;
+        if self.log.is_read_only() {
+            return Err(KvError::ReadOnly);
+        }
+        self.compact_trees()?;
+        self.lazy_index.resolve_all(&self.log, &self.index)?;
+        let records = self.log.records.load(Ordering::SeqCst);
+        // Backups consist of passive datafiles, so recent records are dumped for them anyway.
+        // Without the size limit the active datafile is never rotated, so it is always dumped
+        let skip_dump = !self.config.dump_on_compaction && self.config.max_active_bytes.is_some();
+        if !skip_dump || self.backups_dir.is_some() {
+            self.dump_log()?;
+        }
 
         // Create backup if specified
         if let Some(backups_dir) = &self.backups_dir {
             debug!("Backup triggered, backups directory: {:?}", backups_dir);
-            let time = std::time::SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_micros(); // Note: Error while creating directory due to equal names if time duration is too big.
-            let backup_dir = backups_dir.join(format!("pre_compact_backup_{0}", time));
-            self.backup(&backup_dir)?;
+            self.backup(&create_backup_dir(backups_dir)?)?;
+            if let Some(max_backups) = self.config.max_backups {
+                prune_backups(backups_dir, max_backups)?;
+            }
         }
 
-        // Read actual commands
-        let commands = self.actual_commands();
+        match self.config.compaction {
+            CompactionStrategy::Full => {
+                // Read actual commands, records of the active datafile stay in place if it is not dumped
+                let commands = self.passive_commands();
 
-        // Create new passive files and write actual commands to them,
-        // then replace old passive files to new in self.log
-        self.log.compact(commands)?;
+                // Create new passive files and write actual commands to them,
+                // then replace old passive files to new in self.log
+                self.log.compact(commands, &self.index)?;
+            }
+            CompactionStrategy::Tiered { min_files, max_file_bytes } => {
+                let serial_numbers = self.log.small_passives(max_file_bytes)?;
+                let files = serial_numbers.clone().count();
+                if files < min_files {
+                    debug!("Only {} small passive files, merging is skipped", files);
+                    return Ok(());
+                }
+                let commands = self.merged_commands(serial_numbers.clone())?;
+                self.log.merge(commands, *serial_numbers.start())?;
+            }
+        }
         self.reindex_log()?; //todo implement indexfile for faster indexing of already compacted files
+        // Records of datafiles untouched by compaction are still unused, e.g. older passive datafiles
+        // skipped by the tiered compaction, so only dropped records are subtracted
+        let reclaimed = records.saturating_sub(self.log.records.load(Ordering::SeqCst));
+        // Commands are excluded by the compaction, so the counter is not changed concurrently
+        let unreclaimed_records = self.unused_records.load(Ordering::SeqCst).saturating_sub(reclaimed);
+        self.unused_records.store(unreclaimed_records, Ordering::SeqCst);
+        self.unreclaimed_records.store(unreclaimed_records, Ordering::SeqCst);
+        // Keys dropped by compaction are not tracked, so the cache is dropped wholesale
+        self.cache.clear();
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+        // Compacted datafiles have hints, so mostly the active datafile is read for the estimate
+        if let Err(e) = self.estimate_reclaimable_bytes() {
+            warn!("Unable to estimate reclaimable bytes: {}", e);
+        }
 
         Ok(())
     }
 
-    /// Copy passive datafiles of `Log` to specified directory.
+    /// Copy passive datafiles of `Log` to the specified existing directory.
     fn backup(&self, backup_dir: &PathBuf) -> Result<()> {
         debug!("Backup, path: {:?}", backup_dir);
-        fs::create_dir(&backup_dir)?;
 
-        for serial_number in 1..self.log.last_serial_number.load(Ordering::SeqCst) {
+        for serial_number in 1..=self.log.last_serial_number.load(Ordering::SeqCst) {
+            // Backups are flat regardless of the layout of the `Log` and have default names for `verify_backup`
             let file_name = format!("{}.{}", serial_number, PASSIVE_EXT);
-            let old_path = self.log.dir_path.join(&file_name);
+            let old_path = self.log.passive_path(serial_number);
             let new_path = backup_dir.join(&file_name);
             fs::copy(&old_path, &new_path)?;
         }
@@ -225,35 +1136,157 @@ impl KvStore {
         Ok(())
     }
 
-    /// Return actual commands from `Log`.
+    /// Return actual commands of keys of the newest passive datafiles `serial_numbers`.
+    /// Removed and expired keys are returned as removals if older datafiles may contain their records.
+    fn merged_commands(&self, serial_numbers: RangeInclusive<u64>) -> Result<Vec<Result<Record>>> {
+        debug!("Get actual commands of passive files {:?}", serial_numbers);
+        let has_older = *serial_numbers.start() > 1;
+        // Newer datafiles override locations of older ones
+        let mut locations = HashMap::new();
+        for serial_number in serial_numbers {
+            locations.extend(self.log.datafile_locations(&self.log.passive_path(serial_number))?);
+        }
+
+        let mut commands = Vec::with_capacity(locations.len());
+        for (key, location) in locations {
+            let record = match location {
+                Some(location) => self.materialized_record(&location)?,
+                None => Record::Remove { key },
+            };
+            match record {
+                Record::Set { .. } => commands.push(Ok(record)),
+                Record::SetWithExpiry { .. } if !record.is_expired() => commands.push(Ok(record)),
+                _ if has_older => commands.push(Ok(Record::Remove { key: record.key().clone() })),
+                _ => {
+                    self.index.remove(record.key());
+                }
+            }
+        }
+        Ok(commands)
+    }
+
+    /// Return actual commands of all datafiles of `Log`.
     fn actual_commands(&self) -> Vec<Result<Record>> {
         debug!("Get actual commands");
-        self.index
-            .iter()
-            .map(|pair| -> Result<Record> {
-                match self.log.get_record(pair.val())? {
-                    Record::Set { key, value } => Ok(Record::Set { key, value }),
-                    _ => Err(UnexpectedCommand),
-                }
+        self.indexed_commands(false)
+    }
+
+    /// Return actual commands of passive datafiles of `Log`.
+    /// Keys of the active datafile are skipped, their records stay in place.
+    fn passive_commands(&self) -> Vec<Result<Record>> {
+        debug!("Get actual commands of passive datafiles");
+        self.indexed_commands(true)
+    }
+
+    /// Return actual commands of keys of the `Index`, skipping keys of the active datafile if `skip_active` is set.
+    /// Records of shards of the `Index` are read in parallel.
+    fn indexed_commands(&self, skip_active: bool) -> Vec<Result<Record>> {
+        let shards: Vec<Vec<Result<Record>>> = self
+            .index
+            .shards()
+            .par_iter()
+            .map(|shard| {
+                shard
+                    .iter()
+                    .filter(|pair| !skip_active || pair.val().file.path != self.log.active_file_path)
+                    .map(|pair| -> Result<Record> {
+                        match self.materialized_record(pair.val())? {
+                            Record::Remove { .. } | Record::BatchBegin { .. } => Err(index_corruption(pair.key())),
+                            record => Ok(record),
+                        }
+                    })
+                    .collect()
             })
-            .collect()
+            .collect();
+        shards.into_iter().flatten().collect()
+    }
+}
+
+/// Insert `value` of `key` at position `pos` of loaded pairs unless `latest` has the later one.
+fn insert_latest(latest: &mut HashMap<String, (usize, String)>, key: String, pos: usize, value: String) {
+    match latest.get(&key) {
+        Some((latest_pos, _)) if *latest_pos > pos => {}
+        _ => {
+            latest.insert(key, (pos, value));
+        }
+    }
+}
+
+/// Report the index entry of `key` referring to the removal record.
+/// Removal records are never indexed, so the index is inconsistent with the `Log`.
+fn index_corruption(key: &str) -> KvError {
+    error!("Index is corrupted, key {} refers to the removal record", key);
+    KvError::IndexCorruption { key: key.to_owned() }
+}
+
+/// Create the directory of the new backup in `backups_dir`.
+/// The name of the directory is suffixed with the current time in microseconds,
+/// the suffix is incremented if the directory with the same name already exists,
+/// so suffixes of backups grow monotonically.
+fn create_backup_dir(backups_dir: &PathBuf) -> Result<PathBuf> {
+    let newest = list_backups(backups_dir)?.last().map_or(0, |(time, _)| *time);
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros();
+    let mut time = std::cmp::max(now, newest + 1);
+    loop {
+        let backup_dir = backups_dir.join(format!("{}{}", BACKUP_DIR_PREFIX, time));
+        match fs::create_dir(&backup_dir) {
+            Ok(()) => return Ok(backup_dir),
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => time += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Get backups in `backups_dir` with their times from the oldest to the newest.
+/// Entries not named like backups are ignored.
+fn list_backups(backups_dir: &PathBuf) -> Result<Vec<(u128, PathBuf)>> {
+    let mut backups: Vec<(u128, PathBuf)> = backups_dir
+        .read_dir()?
+        .filter_map(std::result::Result::ok)
+        .filter_map(|entry| {
+            let time = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(BACKUP_DIR_PREFIX)?
+                .parse::<u128>()
+                .ok()?;
+            Some((time, entry.path()))
+        })
+        .collect();
+    backups.sort();
+    Ok(backups)
+}
+
+/// Remove the oldest backups in `backups_dir` exceeding `max_backups`.
+fn prune_backups(backups_dir: &PathBuf, max_backups: usize) -> Result<()> {
+    let backups = list_backups(backups_dir)?;
+    let pruned = backups.len().saturating_sub(max_backups);
+    for (_, backup_dir) in &backups[..pruned] {
+        debug!("Prune backup: {:?}", backup_dir);
+        fs::remove_dir_all(backup_dir)?;
     }
+    Ok(())
 }
 
 impl Drop for KvStore {
-    /// Compact the log.
+    /// Compact the log if the last instance of KvStore is dropped without `close`.
+    /// Errors of compaction are only logged, `close` returns them.
     fn drop(&mut self) {
         debug!("Drop KvStore");
-        // We must compact the log only if we drop the last ("main") instance of KvStore.
-        // Thus if self.log has only one instance then the whole KvStore has only one instance.
-        // Arc::get_mut() returns Some(_) only if there are no other `Arc` or `Weak`
-        // pointers to the same allocation.
-        if let Some(_) = Arc::get_mut(&mut self.log) {
-            if let Err(e) = self.compact_log() {
-                panic!("Error of compaction while dropping KvStore: {}", e);
-            }
-        } else {
-            debug!("No compaction while drop");
+        if self.is_closed {
+            return;
+        }
+        // Compaction may fail again while unwinding, and panic in drop aborts the process
+        if thread::panicking() {
+            self.instances.fetch_sub(1, Ordering::SeqCst);
+            warn!("No compaction while drop due to panic");
+            return;
+        }
+        if let Err(e) = self.release() {
+            error!("Error of compaction while dropping KvStore: {}", e);
         }
     }
 }
@@ -262,11 +1295,25 @@ impl Clone for KvStore {
     fn clone(&self) -> Self {
         KvStore {
             index: Arc::clone(&self.index),
+            lazy_index: Arc::clone(&self.lazy_index),
             log: Arc::clone(&self.log),
             unused_records: Arc::clone(&self.unused_records),
+            unreclaimed_records: Arc::clone(&self.unreclaimed_records),
+            compactions: Arc::clone(&self.compactions),
+            reclaimable_bytes: Arc::clone(&self.reclaimable_bytes),
+            instances: {
+                self.instances.fetch_add(1, Ordering::SeqCst);
+                Arc::clone(&self.instances)
+            },
+            is_closed: false,
+            trees: Arc::clone(&self.trees),
+            is_tree: self.is_tree,
+            key_locks: Arc::clone(&self.key_locks),
+            cache: Arc::clone(&self.cache),
             backups_dir: self.backups_dir.clone(),
             commands_wg: self.commands_wg.clone(),
             compaction_wg: self.compaction_wg.clone(),
+            config: self.config.clone(),
         }
     }
 }
@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use log::debug;
+
+use super::index::Index;
+use super::log::Log;
+use crate::engine::Result;
+
+/// State of lazy indexing of the `Log`.
+/// Datafiles are indexed on demand from the newest to the oldest one,
+/// so a key found in the `Index` is never overridden by records of not indexed datafiles.
+/// Removed keys are kept as tombstones until all datafiles are indexed,
+/// otherwise older datafiles would restore them.
+#[derive(Debug)]
+pub struct LazyIndex {
+    pending: Mutex<Pending>,
+    is_complete: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Pending {
+    /// Not indexed datafiles from the oldest to the newest.
+    datafiles: Vec<PathBuf>,
+    tombstones: HashSet<String>,
+}
+
+impl LazyIndex {
+    /// Create the state with not indexed `datafiles` ordered from the oldest to the newest.
+    pub fn new(datafiles: Vec<PathBuf>) -> LazyIndex {
+        LazyIndex {
+            is_complete: AtomicBool::new(datafiles.is_empty()),
+            pending: Mutex::new(Pending {
+                datafiles,
+                tombstones: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Create the state of fully indexed `Log`.
+    pub fn complete() -> LazyIndex {
+        LazyIndex::new(Vec::new())
+    }
+
+    /// Get not indexed datafiles from the oldest to the newest.
+    pub fn pending_datafiles(&self) -> Vec<PathBuf> {
+        self.pending.lock().unwrap().datafiles.clone()
+    }
+
+    /// Index datafiles until `key` is found in the `Index` or is known to be removed.
+    pub fn resolve(&self, key: &str, log: &Log, index: &Index) -> Result<()> {
+        if self.is_complete.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut pending = self.pending.lock().unwrap();
+        while index.get(key).is_none() && !pending.tombstones.contains(key) {
+            if !pending.index_newest(log, index)? {
+                break;
+            }
+        }
+        self.check_complete(&mut pending);
+        Ok(())
+    }
+
+    /// Index all not indexed datafiles.
+    pub fn resolve_all(&self, log: &Log, index: &Index) -> Result<()> {
+        if self.is_complete.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut pending = self.pending.lock().unwrap();
+        while pending.index_newest(log, index)? {}
+        self.check_complete(&mut pending);
+        Ok(())
+    }
+
+    /// Run `update` of the `Index` exclusively with indexing of datafiles.
+    /// `removed` key is kept as a tombstone.
+    pub fn update<T>(&self, removed: Option<&str>, update: impl FnOnce() -> T) -> T {
+        if self.is_complete.load(Ordering::SeqCst) {
+            return update();
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(key) = removed {
+            pending.tombstones.insert(key.to_owned());
+        }
+        update()
+    }
+
+    /// Forget not indexed datafiles and tombstones, e.g. after clearing the `Log`.
+    pub fn clear(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.datafiles.clear();
+        self.check_complete(&mut pending);
+    }
+
+    fn check_complete(&self, pending: &mut Pending) {
+        if pending.datafiles.is_empty() {
+            debug!("Lazy indexing is complete");
+            pending.tombstones.clear();
+            self.is_complete.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Pending {
+    /// Index the newest of not indexed datafiles.
+    /// Returns `false` if all datafiles are indexed.
+    fn index_newest(&mut self, log: &Log, index: &Index) -> Result<bool> {
+        let datafile_path = match self.datafiles.last() {
+            Some(datafile_path) => datafile_path,
+            None => return Ok(false),
+        };
+        debug!("Index datafile lazily: {:?}", datafile_path);
+
+        for (key, location) in log.datafile_locations(datafile_path)? {
+            if index.get(&key).is_some() || self.tombstones.contains(&key) {
+                continue; // Overridden by newer datafiles
+            }
+            match location {
+                Some(location) => {
+                    index.insert(key, location);
+                }
+                None => {
+                    self.tombstones.insert(key);
+                }
+            }
+        }
+        self.datafiles.pop();
+        Ok(true)
+    }
+}
@@ -8,11 +8,12 @@ pub enum FileType {
 }
 
 impl FileType {
+    /// Passive datafiles are named by serial numbers, any other datafile is the active one.
     fn new(file_path: &PathBuf) -> FileType {
-        if file_path.file_name().unwrap() == ACTIVE_FILE_NAME {
-            FileType::ACTIVE
-        } else {
+        if get_serial_number(file_path).is_some() {
             FileType::PASSIVE
+        } else {
+            FileType::ACTIVE
         }
     }
 }
@@ -26,7 +27,7 @@ impl DataFile {
     pub fn serial_number(&self) -> Option<u64>{
         match self.file_type {
             FileType::ACTIVE => None,
-            FileType::PASSIVE => get_serial_number(&self.path).ok()
+            FileType::PASSIVE => get_serial_number(&self.path)
         }
     }
 }
@@ -56,4 +57,26 @@ impl Into<std::path::PathBuf> for Location {
     fn into(self)-> PathBuf {
         self.file.path
     }
-}
\ No newline at end of file
+}
+
+/// Read-only description of the `Location` of the value returned by `KvStore::peek`,
+/// e.g. to diagnose compaction or reindexing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocationInfo {
+    /// Serial number of the passive datafile, `None` for the active one.
+    pub serial_number: Option<u64>,
+    /// Path of the datafile.
+    pub path: PathBuf,
+    /// Offset of the record in bytes from the beginning of the datafile.
+    pub offset: u64,
+}
+
+impl From<&Location> for LocationInfo {
+    fn from(location: &Location) -> LocationInfo {
+        LocationInfo {
+            serial_number: location.file.serial_number(),
+            path: location.file.path.clone(),
+            offset: location.offset,
+        }
+    }
+}
@@ -1,15 +1,25 @@
+use std::collections::{HashMap, VecDeque};
 use std::fs;
-use std::io::{Seek, SeekFrom, BufWriter, BufReader, Write};
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom, BufWriter, BufReader, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
-use log::debug;
+use log::{debug, warn};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize}; //todo use it
 
+use super::codec::{Codec, DatafileHeader, RecordStream, HEADER_LEN, RECORD_VERSION};
+use super::config::{KvStoreConfig, LogConfig};
+use super::group_commit::GroupCommit;
+use super::frame::{decode_frame, decode_frames, decode_raw, encode_frame, encode_raw, RAW_CHECKSUM_LEN};
+use super::hint::{Hint, HintEntry};
 use super::location::*;
+use super::manifest::*;
 use super::utils::*;
-use super::kv_store::Index;
-use crate::engine::Result;
-use std::sync::Mutex;
+use super::verify::VerifyReport;
+use super::index::Index;
+use crate::engine::{DurabilityMode, KvError, Result};
+use std::sync::{Mutex, MutexGuard, RwLock};
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::fs::File;
@@ -22,10 +32,12 @@ use super::kv_store::Record;
 struct LogReader;
 
 impl LogReader {
-    pub fn get_reader(&self, location: impl Into<PathBuf>) -> BufReader<File> {
+    /// Open the datafile for reading.
+    /// Failure to open it, e.g. if it is removed or file descriptors are exhausted, is returned as the error.
+    pub fn get_reader(&self, location: impl Into<PathBuf>) -> Result<BufReader<File>> {
         //todo implement reusing of readers
         let path = location.into();
-        BufReader::new(File::open(path).unwrap())
+        Ok(BufReader::new(File::open(path)?))
     }
 }
 
@@ -36,115 +48,439 @@ impl LogReader {
 /// New records are added in the end of active datafile.
 /// Passive datafiles contain immutable sequence of records.
 /// Passive datafiles are enumerated monotonically starting from 1.
+/// Passive datafiles created by compaction are accompanied by hints to speed up indexing.
+/// Each datafile starts with the header identifying the `Codec` of its records,
+/// new records are written by the `Codec` of the `Log` and framed with CRC32 checksums.
+/// The `Log` opened for reading only has no writer.
+/// Records are read under the read lock of datafiles, while dumping and compaction
+/// replace datafiles under the write lock, so readers never open a just removed datafile.
 #[derive(Debug)]
 pub struct Log {
     reader: LogReader,
-    writer: Mutex<BufWriter<File>>,
+    writer: Option<Mutex<BufWriter<File>>>,
     pub dir_path: PathBuf,
     pub active_file_path: PathBuf,
     pub last_serial_number: AtomicU64,
+    /// Number of records in datafiles.
+    /// Only records of indexed datafiles are counted if the `Log` is indexed lazily.
+    pub records: AtomicU64,
+    /// Number of bytes written to passive datafiles by compactions since opening.
+    pub compacted_bytes: AtomicU64,
+    /// Number of syncs of the active datafile made by `commit` since opening.
+    pub syncs: AtomicU64,
+    records_in_compacted: usize,
+    /// Size of the active datafile in bytes.
+    active_bytes: AtomicU64,
+    max_active_bytes: Option<u64>,
+    durability: DurabilityMode,
+    /// Number of records written since the last sync.
+    unsynced: AtomicU64,
+    /// Syncs shared by concurrent writers, see `KvStoreConfig::group_commit`.
+    group_commit: Option<GroupCommit>,
+    codec: Codec,
+    compression: Compression,
+    /// Number of subdirectories passive datafiles are sharded into, 0 for the flat layout.
+    passive_shards: u64,
+    /// Names of files of the `Log` in its directory.
+    files: LogConfig,
+    datafiles_lock: RwLock<()>,
 }
 
 impl Log {
     /// Open a `Log` with the given path.
-    pub fn open(dir_path: impl Into<PathBuf>) -> Result<Log> {
+    /// Options which are not specified in `config` are restored from the manifest.
+    /// # Error
+    /// It returns `KvError::ManifestMismatch` if an option specified in `config` conflicts with the manifest.
+    pub fn open(dir_path: impl Into<PathBuf>, config: &KvStoreConfig) -> Result<Log> {
+        let mut log = Log::open_read_only(dir_path, config)?;
+        // The layout of the manifest is checked too, so the relayout interrupted by a crash is finished
+        let passive_shards = config.passive_shards.unwrap_or(log.passive_shards);
+        log.relayout(passive_shards)?;
+        log.recover_bulk_load()?;
+
+        let active_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .append(true)
+            .open(&log.active_file_path)?;
+        let active_len = active_file.metadata()?.len();
+        log.writer = Some(Mutex::new(BufWriter::new(active_file)));
+        log.active_bytes.store(active_len, Ordering::SeqCst);
+
+        if active_len == 0 {
+            let mut writer = log.writer()?.lock().unwrap();
+            writer.write_all(&[log.header().to_byte()])?;
+            writer.flush()?;
+            log.active_bytes.store(HEADER_LEN, Ordering::SeqCst);
+        } else {
+            let (header, records_start) = log.read_header(&log.active_file_path)?;
+            if config.repair_active && !header.checksums {
+                log.repair_active(header, records_start)?;
+            }
+            if header != log.header() {
+                // Records can't be appended to the active datafile written in another format
+                log.dump()?;
+            }
+        }
+
+        log.store_manifest()?;
+        Ok(log)
+    }
+
+    /// Open a `Log` with the given path for reading only.
+    /// Nothing is created or written in the directory, the active datafile may be absent.
+    /// Passive datafiles are read in the layout recorded in the manifest.
+    pub fn open_read_only(dir_path: impl Into<PathBuf>, config: &KvStoreConfig) -> Result<Log> {
         let dir_path = dir_path.into();
         debug!("Open Log, path: {:?}", dir_path);
 
-        let active_file_path = dir_path.join(ACTIVE_FILE_NAME);
+        let manifest = Manifest::load(&dir_path.join(&config.log.manifest_file_name))?;
+        if let Some(manifest) = &manifest {
+            manifest.check_config(config)?;
+        }
+        let records_in_compacted = config.records_in_compacted
+            .or(manifest.as_ref().map(|manifest| manifest.chunk_size))
+            .unwrap_or(RECORDS_IN_COMPACTED);
+        let codec = config.codec
+            .or(manifest.as_ref().map(|manifest| manifest.codec))
+            .unwrap_or(Codec::Json);
+        let compression = config.compression
+            .or(manifest.as_ref().map(|manifest| manifest.compression))
+            .unwrap_or(Compression::None);
+
+        let active_file_path = dir_path.join(&config.log.active_file_name);
 
-        let last_serial_number: u64 = dir_path
-            .read_dir()?
-            .filter_map(std::result::Result::ok)
-            .map(|file| Ok(get_serial_number(&file.path())?))
-            .filter_map(Result::ok)
+        let passive_shards = manifest.as_ref().map_or(0, |manifest| manifest.passive_shards);
+
+        let last_serial_number: u64 = passive_files(&dir_path, &config.log)?
+            .into_iter()
+            .filter(|path| path.extension() == Some(OsStr::new(&config.log.passive_ext)))
+            .filter_map(|path| get_serial_number(&path))
             .max()
             .unwrap_or(0);
 
         let last_serial_number = AtomicU64::new(last_serial_number);
-
-        let active_file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(&active_file_path)?;
-        let writer = Mutex::new(BufWriter::new(active_file));
         let reader = LogReader{};
 
         Ok(Log {
-            writer,
+            writer: None,
             reader,
             last_serial_number,
+            records: AtomicU64::new(0),
+            compacted_bytes: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
             dir_path,
             active_file_path,
+            records_in_compacted,
+            active_bytes: AtomicU64::new(0),
+            max_active_bytes: config.max_active_bytes,
+            durability: config.durability,
+            unsynced: AtomicU64::new(0),
+            group_commit: config.group_commit.map(GroupCommit::new),
+            codec,
+            compression,
+            passive_shards,
+            files: config.log.clone(),
+            datafiles_lock: RwLock::new(()),
         })
     }
 
+    /// Check if the `Log` is opened for reading only.
+    pub fn is_read_only(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// Get writer of the active datafile.
+    /// # Error
+    /// It returns `KvError::ReadOnly` if the `Log` is opened for reading only.
+    fn writer(&self) -> Result<&Mutex<BufWriter<File>>> {
+        self.writer.as_ref().ok_or(KvError::ReadOnly)
+    }
+
+    /// Get `Manifest` describing the current state of the `Log`.
+    pub fn manifest(&self) -> Manifest {
+        let last_serial_number = self.last_serial_number.load(Ordering::SeqCst);
+        Manifest {
+            format_version: FORMAT_VERSION,
+            codec: self.codec,
+            compression: self.compression,
+            record_separator: None,
+            chunk_size: self.records_in_compacted,
+            passive_shards: self.passive_shards,
+            first_serial_number: if last_serial_number == 0 { 0 } else { 1 },
+            last_serial_number,
+        }
+    }
+
+    /// Write the current `Manifest` to the directory of the `Log`.
+    fn store_manifest(&self) -> Result<()> {
+        self.manifest().store(&self.dir_path.join(&self.files.manifest_file_name))
+    }
+
+    /// Get header of datafiles written by the `Log`.
+    fn header(&self) -> DatafileHeader {
+        DatafileHeader {
+            codec: self.codec,
+            checksums: true,
+            compression: self.compression,
+            version: RECORD_VERSION,
+        }
+    }
+
     /// Get record from `Log` by `Location`.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if the record is truncated or its checksum mismatches.
+    /// Raw bytes of streamed values are verified, but not returned, see `read_value`.
     pub fn get_record(&self, location: &Location) -> Result<Record> {
-        let mut reader = self.reader.get_reader(&location.file.path);
+        let _datafiles = self.datafiles_lock.read().unwrap();
+        self.record_at(location)
+    }
+
+    /// Get record from `Log` by `Location` and copy the value of the streamed record to `writer`.
+    /// Other records are returned like `get_record` does, nothing is written to `writer` then.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if the record is truncated or its checksum mismatches,
+    /// bytes of the value written to `writer` before the error are not trustworthy.
+    pub fn read_value(&self, location: &Location, writer: &mut dyn Write) -> Result<Record> {
+        let _datafiles = self.datafiles_lock.read().unwrap();
+        self.value_at(location, writer)
+    }
+
+    /// Read the record at `location`, the lock of datafiles must be held.
+    fn record_at(&self, location: &Location) -> Result<Record> {
+        self.value_at(location, &mut io::sink())
+    }
+
+    /// Read the single record at `location` and copy the value of the streamed record to `writer`,
+    /// the lock of datafiles must be held.
+    /// Only the record itself is read, so point reads don't pay for decoding a stream of records.
+    fn value_at(&self, location: &Location, writer: &mut dyn Write) -> Result<Record> {
+        let mut reader = self.reader.get_reader(&location.file.path)?;
+        let (header, _) = read_header_from(&mut reader, &location.file.path)?;
         reader.seek(SeekFrom::Start(location.offset))?;
-        Ok(serde_json::Deserializer::from_reader(reader.get_mut())
-            .into_iter()
-            .next()
-            .unwrap()?)
+        let record = if header.checksums {
+            match decode_frame(&mut reader, header)? {
+                Some((_, Some(Record::SetRaw { key, len }))) => {
+                    if decode_raw(&mut reader, writer, len)? {
+                        Some(Record::SetRaw { key, len })
+                    } else {
+                        None
+                    }
+                }
+                Some((_, record)) => record,
+                None => None,
+            }
+        } else {
+            header.record_codec().decode_record(&mut reader)?
+        };
+        record.ok_or_else(|| KvError::CorruptRecord {
+            file: location.file.path.clone(),
+            offset: location.offset,
+        })
     }
 
     pub fn set_record(&self, record: &Record) -> Result<Location> {
-        let mut writer = self.writer.lock().unwrap();
-        let pos = writer.seek(SeekFrom::Current(0))?;
-        serde_json::to_writer(writer.get_mut(),record)?;
+        let mut writer = self.writer()?.lock().unwrap();
+        // Records are appended, so the end of the datafile is the position of the new record
+        let pos = writer.seek(SeekFrom::End(0))?;
+        let frame_len = encode_frame(writer.get_mut(), self.header(), record)?;
+        self.records.fetch_add(1, Ordering::SeqCst);
+        self.active_bytes.store(pos + frame_len, Ordering::SeqCst);
         writer.flush()?;
+        self.commit(writer)?;
         Ok(
             Location::new(pos,
                          &self.active_file_path)
         )
     }
 
+    /// Write the record of `key` with the value of `len` bytes streamed from `reader`.
+    /// Raw bytes of the value follow the frame of the record, so the value is never buffered.
+    /// # Error
+    /// It returns `KvError::StorageFileError` if `reader` fails or is over before `len` bytes,
+    /// the partially written record is truncated then.
+    pub fn set_raw(&self, key: &str, reader: &mut dyn Read, len: u64) -> Result<Location> {
+        let mut writer = self.writer()?.lock().unwrap();
+        let pos = writer.seek(SeekFrom::End(0))?;
+        let record = Record::SetRaw { key: key.to_string(), len };
+        let frame_len = encode_frame(writer.get_mut(), self.header(), &record)?;
+        let copied = match encode_raw(reader, writer.get_mut(), len) {
+            Ok(copied) => copied,
+            Err(e) => {
+                writer.get_ref().set_len(pos)?;
+                return Err(e);
+            }
+        };
+        if copied < len {
+            writer.get_ref().set_len(pos)?;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("value of key {} is {} bytes instead of {}", key, copied, len),
+            ).into());
+        }
+        self.records.fetch_add(1, Ordering::SeqCst);
+        self.active_bytes.store(pos + frame_len + len + RAW_CHECKSUM_LEN, Ordering::SeqCst);
+        writer.flush()?;
+        self.commit(writer)?;
+        Ok(Location::new(pos, &self.active_file_path))
+    }
+
+    /// Write `records` as one batch: the `BatchBegin` marker is followed by all records,
+    /// so the batch cut off by a crash is detected and rolled back while reading the datafile.
+    /// Returns locations of `records`.
+    pub fn set_batch(&self, records: &[Record]) -> Result<Vec<Location>> {
+        let mut writer = self.writer()?.lock().unwrap();
+        let marker = Record::BatchBegin { len: records.len() as u64 };
+        let mut pos = writer.seek(SeekFrom::End(0))?;
+        pos += encode_frame(writer.get_mut(), self.header(), &marker)?;
+        let mut locations = Vec::with_capacity(records.len());
+        for record in records {
+            locations.push(Location::new(pos, &self.active_file_path));
+            pos += encode_frame(writer.get_mut(), self.header(), record)?;
+        }
+        self.records.fetch_add(records.len() as u64, Ordering::SeqCst);
+        self.active_bytes.store(pos, Ordering::SeqCst);
+        writer.flush()?;
+        self.commit(writer)?;
+        Ok(locations)
+    }
+
+    /// Sync the active datafile after the write if the durability mode requires it.
+    /// With group commit the sync is shared with concurrent writers,
+    /// so it is awaited after releasing the lock of the writer.
+    fn commit(&self, writer: MutexGuard<BufWriter<File>>) -> Result<()> {
+        if !self.durability.needs_sync(&self.unsynced) {
+            return Ok(());
+        }
+        let group_commit = match &self.group_commit {
+            Some(group_commit) => group_commit,
+            None => {
+                writer.get_ref().sync_all()?;
+                self.syncs.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+        };
+        let ticket = group_commit.issue();
+        drop(writer);
+        group_commit.wait(ticket, || {
+            let (last_issued, active_file) = {
+                let writer = self.writer()?.lock().unwrap();
+                (group_commit.last_issued(), writer.get_ref().try_clone()?)
+            };
+            active_file.sync_all()?;
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            Ok(last_issued)
+        })
+    }
+
+    /// Flush the writer of the active datafile and sync it to disk.
+    /// Does nothing if the `Log` is opened for reading only.
+    pub fn sync(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock().unwrap();
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            self.unsynced.store(0, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Check if the size of the active datafile exceeds `max_active_bytes` of the config.
+    pub fn is_active_full(&self) -> bool {
+        self.max_active_bytes
+            .map_or(false, |max_active_bytes| self.active_bytes.load(Ordering::SeqCst) > max_active_bytes)
+    }
+
     //todo update docs
     /// Dump the active datafile.
     /// Dumping is the process of moving the content of active datafile to the new passive one
     /// and creating new empty active datafile.
     pub fn dump(&self) -> Result<()> {
         debug!("Dump Log");
+        let writer = self.writer()?;
+        let _datafiles = self.datafiles_lock.write().unwrap();
         let active_path = &self.active_file_path;
-        let mut active_file = self.reader.get_reader(&active_path);
-        if active_file.get_mut().metadata()?.len() == 0 {
+        let mut active_file = self.reader.get_reader(&active_path)?;
+        let (_, records_start) = self.read_header(&active_path)?;
+        if active_file.get_mut().metadata()?.len() <= records_start {
             debug!("File is already empty"); // Nothing to do here
             return Ok(());
         }
 
-        // Rename current ACTIVE_FILE_NAME to serial_number.passive
+        // Rename the active datafile to the passive one with the next serial number
         self.last_serial_number.fetch_add(1, Ordering::SeqCst);
         let new_path = self.passive_path(self.last_serial_number.load(Ordering::SeqCst));
+        create_parent_dir(&new_path)?;
         fs::rename(active_path, &new_path)?;
 
         debug!("Move active file to {:?}", new_path);
 
         self.create_active()?;
+        self.active_bytes.store(HEADER_LEN, Ordering::SeqCst);
         let active_file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .append(true)
             .open(active_path)?; //todo remove opening active file twice
-        *self.writer.lock().unwrap() = BufWriter::new(active_file);
-        debug!("Active file writer after dumping: {:?}", self.writer);
-        Ok(())
+        let mut writer_guard = writer.lock().unwrap();
+        if self.group_commit.is_some() {
+            // Writes awaiting the group commit are synced in the dumped datafile
+            writer_guard.flush()?;
+            writer_guard.get_ref().sync_all()?;
+        }
+        *writer_guard = BufWriter::new(active_file);
+        drop(writer_guard);
+        debug!("Active file writer after dumping: {:?}", writer);
+        self.store_manifest()
+    }
+
+    /// Remove all records of the log.
+    /// Passive datafiles are removed and the active datafile is truncated to its header.
+    pub fn clear(&self) -> Result<()> {
+        debug!("Clear Log");
+        let mut writer = self.writer()?.lock().unwrap();
+        let _datafiles = self.datafiles_lock.write().unwrap();
+        self.clear_passives()?;
+        self.last_serial_number.store(0, Ordering::SeqCst);
+
+        writer.flush()?;
+        writer.get_mut().set_len(0)?;
+        writer.write_all(&[self.header().to_byte()])?;
+        writer.flush()?;
+        self.active_bytes.store(HEADER_LEN, Ordering::SeqCst);
+        self.records.store(0, Ordering::SeqCst);
+
+        self.store_manifest()
     }
 
     /// Compact the log.
     /// Compaction is the process of removing deprecated records from passive datafiles of Log.
     /// Old passive datafiles will be replaced by new ones with only actual(unique) records.
     /// New files are compacted and created from unique records in the next way:
-    /// 1. Split commands to chunks of `RECORDS_IN_COMPACTED` elements
+    /// 1. Split commands to chunks of `records_in_compacted` elements
     /// 2. Write each chunk to new passive file in log directory.
     /// 3. Collect passive files to BTreeMap and set it to `self.passive`.
-    pub fn compact(&self, mut records: Vec<Result<Record>>) -> Result<()> {
+    /// Expired records are not written to new passive files and their keys are removed from `index`.
+    pub fn compact(&self, records: Vec<Result<Record>>, index: &Index) -> Result<()> {
         debug!("Compact Log");
+        self.writer()?;
+        let _datafiles = self.datafiles_lock.write().unwrap();
         self.clear_passives()?;
 
+        let mut records: Vec<Result<Record>> = records
+            .into_iter()
+            .filter(|record| match record {
+                Ok(record) if record.is_expired() => {
+                    debug!("Skip expired record, key: {}", record.key());
+                    index.remove(record.key());
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+
         let mut counter: u64 = 0; // serial number of passive file
 
         // Create `counter` passive files with appropriated records on the filesystem
@@ -152,7 +488,7 @@ impl Log {
         while !records.is_empty() {
             counter += 1;
             let chunk = std::iter::from_fn(|| records.pop())
-                .take(RECORDS_IN_COMPACTED)
+                .take(self.records_in_compacted)
                 .collect::<Vec<_>>();
 
             self.create_passive(chunk, counter)?;
@@ -160,54 +496,469 @@ impl Log {
         debug!("Created {} compacted passive files", counter);
         self.last_serial_number.store(counter, Ordering::SeqCst);
 
+        self.store_manifest()
+    }
+
+    /// Merge passive datafiles from `first_serial_number` to the newest one
+    /// into one passive datafile of `records` with `first_serial_number`.
+    /// Older passive datafiles are untouched, so `records` must contain removals of keys
+    /// which may be restored from them. Nothing is written if there are no `records`.
+    pub fn merge(&self, records: Vec<Result<Record>>, first_serial_number: u64) -> Result<()> {
+        debug!("Merge passive files starting from {}", first_serial_number);
+        self.writer()?;
+        // Records are read before removing merged datafiles
+        let records = records.into_iter().collect::<Result<Vec<_>>>()?;
+        let _datafiles = self.datafiles_lock.write().unwrap();
+
+        for serial_number in first_serial_number..=self.last_serial_number.load(Ordering::SeqCst) {
+            let passive_path = self.passive_path(serial_number);
+            let hint_path = Hint::path(&passive_path, &self.files.hint_ext);
+            if hint_path.exists() {
+                fs::remove_file(hint_path)?;
+            }
+            fs::remove_file(passive_path)?;
+        }
+
+        if records.is_empty() {
+            self.last_serial_number.store(first_serial_number - 1, Ordering::SeqCst);
+        } else {
+            self.create_passive(records.into_iter().map(Ok).collect(), first_serial_number)?;
+            self.last_serial_number.store(first_serial_number, Ordering::SeqCst);
+        }
+        self.store_manifest()
+    }
+
+    /// Write `records` to new passive datafiles following the newest one,
+    /// chunks of `records_in_compacted` records are written in parallel.
+    /// Datafiles are written to the staging directory which is renamed when all of them are written,
+    /// the rename commits the loading: the staging directory is dropped on opening after a crash,
+    /// while the renamed one is moved in place. So the failed loading leaves no datafiles.
+    /// Returns locations of keys of `records`.
+    pub fn bulk_load(&self, records: Vec<Record>) -> Result<Vec<(String, Location)>> {
+        debug!("Bulk load {} records", records.len());
+        self.writer()?;
+        let _datafiles = self.datafiles_lock.write().unwrap();
+        let first_serial_number = self.last_serial_number.load(Ordering::SeqCst) + 1;
+        let staging_dir = self.dir_path.join(BULK_LOAD_DIR_NAME);
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir(&staging_dir)?;
+
+        let mut records = records.into_iter().peekable();
+        let mut chunks = Vec::new();
+        while records.peek().is_some() {
+            chunks.push(records.by_ref().take(self.records_in_compacted).map(Ok).collect::<Vec<_>>());
+        }
+        let written = chunks
+            .into_par_iter()
+            .enumerate()
+            .map(|(chunk, records)| {
+                let serial_number = first_serial_number + chunk as u64;
+                let staged_path = staging_dir.join(format!("{}.{}", serial_number, self.files.passive_ext));
+                let hint = self.write_passive(records, &staged_path)?;
+                hint.store(&staged_path, &self.files.hint_ext)?;
+                Ok((serial_number, hint))
+            })
+            .collect::<Result<Vec<_>>>();
+        let written = match written {
+            Ok(written) => written,
+            Err(e) => {
+                fs::remove_dir_all(&staging_dir)?;
+                return Err(e);
+            }
+        };
+
+        fs::rename(&staging_dir, self.dir_path.join(BULK_LOADED_DIR_NAME))?;
+        self.publish_bulk_load()?;
+
+        let mut locations = Vec::new();
+        for (serial_number, hint) in written {
+            self.records.fetch_add(hint.entries.len() as u64, Ordering::SeqCst);
+            let passive_path = self.passive_path(serial_number);
+            locations.extend(
+                hint.entries
+                    .into_iter()
+                    .map(|entry| (entry.key, Location::new(entry.offset, &passive_path))),
+            );
+        }
+        Ok(locations)
+    }
+
+    /// Move passive datafiles of the committed bulk loading in place and store the manifest.
+    /// Files already moved are skipped, so the publishing interrupted by a crash is finished by the next call.
+    fn publish_bulk_load(&self) -> Result<()> {
+        let loaded_dir = self.dir_path.join(BULK_LOADED_DIR_NAME);
+        for entry in loaded_dir.read_dir()? {
+            let path = entry?.path();
+            let serial_number = match get_serial_number(&path) {
+                Some(serial_number) => serial_number,
+                None => continue,
+            };
+            let passive_path = self.passive_path(serial_number);
+            let new_path = if path.extension() == Some(OsStr::new(&self.files.hint_ext)) {
+                Hint::path(&passive_path, &self.files.hint_ext)
+            } else {
+                passive_path
+            };
+            create_parent_dir(&new_path)?;
+            fs::rename(&path, &new_path)?;
+            self.last_serial_number.fetch_max(serial_number, Ordering::SeqCst);
+        }
+        fs::remove_dir_all(&loaded_dir)?;
+        self.store_manifest()
+    }
+
+    /// Recover the bulk loading interrupted by a crash: the uncommitted one is dropped,
+    /// the committed one is published. Temporary files left by the previous versions are removed.
+    fn recover_bulk_load(&self) -> Result<()> {
+        let staging_dir = self.dir_path.join(BULK_LOAD_DIR_NAME);
+        if staging_dir.exists() {
+            warn!("Drop interrupted bulk loading {:?}", staging_dir);
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        if self.dir_path.join(BULK_LOADED_DIR_NAME).exists() {
+            warn!("Finish interrupted bulk loading");
+            self.publish_bulk_load()?;
+        }
+        for dir in passive_dirs(&self.dir_path)? {
+            for entry in dir.read_dir()? {
+                let path = entry?.path();
+                // Temporary files are named after datafiles or hints, e.g. `42.passive.tmp`
+                let is_tmp = path.extension() == Some(OsStr::new("tmp"))
+                    && path.file_stem().map_or(false, |stem| get_serial_number(Path::new(stem)).is_some());
+                if is_tmp && path.is_file() {
+                    warn!("Remove stale temporary file {:?}", path);
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Get serial numbers of the newest passive datafiles smaller than `max_file_bytes`,
+    /// they are merged by the tiered compaction.
+    pub fn small_passives(&self, max_file_bytes: u64) -> Result<RangeInclusive<u64>> {
+        let last_serial_number = self.last_serial_number.load(Ordering::SeqCst);
+        let mut first_serial_number = last_serial_number + 1;
+        while first_serial_number > 1
+            && fs::metadata(self.passive_path(first_serial_number - 1))?.len() < max_file_bytes
+        {
+            first_serial_number -= 1;
+        }
+        Ok(first_serial_number..=last_serial_number)
+    }
+
     /// Get path of passive datafile with specified `serial_number`
     /// Note: `serial_number` must refer to an existing file
     pub fn passive_path(&self, serial_number: u64) -> PathBuf {
-        self.dir_path.join(format!("{}.{}",serial_number, PASSIVE_EXT))
+        let file_name = format!("{}.{}", serial_number, self.files.passive_ext);
+        if self.passive_shards == 0 {
+            return self.dir_path.join(file_name);
+        }
+        self.dir_path
+            .join(PASSIVES_DIR_NAME)
+            .join(format!("{:02}", serial_number % self.passive_shards))
+            .join(file_name)
+    }
+
+    /// Move passive datafiles and their hints to the layout of `passive_shards` subdirectories.
+    /// The new layout is stored in the manifest before moving, and files already in place are not moved,
+    /// so the relayout interrupted by a crash is finished by the next call.
+    fn relayout(&mut self, passive_shards: u64) -> Result<()> {
+        if passive_shards != self.passive_shards {
+            debug!("Move passive files from {} to {} shards", self.passive_shards, passive_shards);
+            self.passive_shards = passive_shards;
+            self.store_manifest()?;
+        }
+        for path in passive_files(&self.dir_path, &self.files)? {
+            // Only files named by serial numbers are returned by `passive_files`
+            let passive_path = self.passive_path(get_serial_number(&path).unwrap());
+            let new_path = if path.extension() == Some(OsStr::new(&self.files.hint_ext)) {
+                Hint::path(&passive_path, &self.files.hint_ext)
+            } else {
+                passive_path
+            };
+            if new_path != path {
+                debug!("Move passive file {:?} to {:?}", path, new_path);
+                create_parent_dir(&new_path)?;
+                fs::rename(&path, &new_path)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn index(&self) -> Result<Index> {
-        let index = Index::new();
-        self.reindex(&index);
+    /// Build the `Index` of `shards` shards from datafiles.
+    /// # Error
+    /// It returns the error of indexing datafiles like `reindex` does, so the `Log` with the corrupted
+    /// passive datafile fails to open rather than silently losing keys of the datafile.
+    pub fn index(&self, shards: usize) -> Result<Index> {
+        let index = Index::new(shards);
+        self.reindex(&index)?;
         Ok(index)
     }
     
     /// Index active and passive datafiles from `Log`.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if a passive datafile has the corrupted record,
+    /// the corrupted tail of the active datafile is cut off instead.
     pub fn reindex(&self, index: &Index) -> Result<()> {
         debug!("Reindex log {:?}", &self);
 
         // Clear old_index
-        // Index::clear(&mut self) is unusable because we have only &self
         // This code is correct until there are no calls to index from other threads
-        index.iter().map(|pair| index.remove(pair.key()));
+        index.clear();
 
-        for serial_number in 1..=self.last_serial_number.load(Ordering::SeqCst) {
-            self.reindex_datafile(&index, &self.passive_path(serial_number))?
-        }
+        // Records are counted by reading locations of datafiles
+        self.records.store(0, Ordering::SeqCst);
 
-        self.reindex_datafile(&index, &self.active_file_path)?;
+        // Passive datafiles are read in parallel,
+        // their locations are merged from the oldest to the newest, so newer records win
+        let passive_paths: Vec<PathBuf> = (1..=self.last_serial_number.load(Ordering::SeqCst))
+            .map(|serial_number| self.passive_path(serial_number))
+            .collect();
+        let passive_locations = passive_paths
+            .par_iter()
+            .map(|passive_path| self.datafile_locations(passive_path))
+            .collect::<Result<Vec<_>>>()?;
+        for locations in passive_locations {
+            merge_locations(index, locations);
+        }
 
+        // Active datafile is the newest one, it is merged last.
+        // It may be absent if the `Log` is opened for reading only
+        if self.active_file_path.exists() {
+            merge_locations(index, self.datafile_locations(&self.active_file_path)?);
+        }
         Ok(())
     }
 
-    fn reindex_datafile(&self, index: &Index, datafile_path: &PathBuf) -> Result<()> {
+    /// Verify that all records of the `Log` are readable
+    /// and every `Location` of the index built from them refers to the record of its key.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        debug!("Verify log {:?}", &self);
+        let mut report = VerifyReport::default();
+        let index = Index::new(INDEX_SHARDS);
+
+        for datafile_path in self.datafiles() {
+            report.datafiles += 1;
+            if !datafile_path.exists() {
+                report.corrupted.push(datafile_path);
+                continue;
+            }
+            match self.reindex_datafile(&index, &datafile_path) {
+                Ok(records) => report.records += records,
+                Err(e) => {
+                    warn!("Datafile {:?} is corrupted: {}", datafile_path, e);
+                    report.corrupted.push(datafile_path);
+                }
+            }
+        }
+
+        self.verify_index(&index, &mut report);
+
+        debug!("Verify report: {:?}", report);
+        Ok(report)
+    }
+
+    /// Check that every `Location` of `index` refers to the readable record setting the value of its key.
+    /// Other keys are added to inconsistent ones of `report`. Returns the number of successfully read records.
+    pub fn verify_index(&self, index: &Index, report: &mut VerifyReport) -> usize {
+        let mut records = 0;
+        for pair in index.iter() {
+            report.keys += 1;
+            match self.get_record(pair.val()) {
+                Ok(record) => {
+                    records += 1;
+                    let is_set = match record {
+                        Record::Set { .. } | Record::SetWithExpiry { .. } | Record::SetRaw { .. } => true,
+                        Record::Remove { .. } | Record::BatchBegin { .. } => false,
+                    };
+                    if !is_set || record.key() != pair.key() {
+                        let location = pair.val();
+                        warn!("Key {} refers to another record at {:?}:{}", pair.key(), location.file.path, location.offset);
+                        report.inconsistent.push(pair.key().clone());
+                    }
+                }
+                Err(e) => {
+                    warn!("Record of key {} is unreadable: {}", pair.key(), e);
+                    report.inconsistent.push(pair.key().clone());
+                }
+            }
+        }
+        records
+    }
+
+    /// Get paths of all datafiles from the oldest to the newest.
+    pub fn datafiles(&self) -> Vec<PathBuf> {
+        let mut datafiles = (1..=self.last_serial_number.load(Ordering::SeqCst))
+            .map(|serial_number| self.passive_path(serial_number))
+            .collect::<Vec<_>>();
+        if self.active_file_path.exists() {
+            datafiles.push(self.active_file_path.clone());
+        }
+        datafiles
+    }
+
+    /// Get the actual `Location` of every key in the datafile,
+    /// `None` if the key is removed or expired by the datafile.
+    /// The corrupted tail of the active datafile is cut off as while reindexing.
+    pub fn datafile_locations(&self, datafile_path: &PathBuf) -> Result<HashMap<String, Option<Location>>> {
+        debug!("Read locations of datafile: {:?}", datafile_path);
+        let mut locations = HashMap::new();
+        if let Some(hint) = Hint::load(datafile_path, &self.files.hint_ext)? {
+            self.records.fetch_add(hint.entries.len() as u64, Ordering::SeqCst);
+            for entry in hint.entries {
+                let location = if entry.is_removal || entry.is_expired() {
+                    None
+                } else {
+                    Some(Location::new(entry.offset, datafile_path))
+                };
+                locations.insert(entry.key, location);
+            }
+            return Ok(locations);
+        }
+        for item in self.read_records(datafile_path, None)? {
+            let (pos, record) = match item {
+                Err(KvError::CorruptRecord { offset, .. }) if *datafile_path == self.active_file_path => {
+                    self.truncate_active(offset)?;
+                    break;
+                }
+                item => item?,
+            };
+            self.records.fetch_add(1, Ordering::SeqCst);
+            let location = match record {
+                Record::Remove { .. } => None,
+                ref record if record.is_expired() => None,
+                _ => Some(Location::new(pos, datafile_path)),
+            };
+            locations.insert(record.key().clone(), location);
+        }
+        Ok(locations)
+    }
+
+    /// Estimate the number of bytes of records in the datafile which are not referred by `index`:
+    /// overwritten and removed records and removal records themselves.
+    /// Offsets of records are taken from the hint if it exists, the datafile is not read then.
+    pub fn dead_bytes(&self, datafile_path: &PathBuf, index: &Index) -> Result<u64> {
+        let (offsets, datafile_len) = match Hint::load(datafile_path, &self.files.hint_ext)? {
+            Some(hint) => {
+                let offsets = hint.entries.into_iter().map(|entry| (entry.key, entry.offset)).collect::<Vec<_>>();
+                (offsets, hint.datafile_len)
+            }
+            None => {
+                let mut offsets = Vec::new();
+                for item in self.read_records(datafile_path, None)? {
+                    let (pos, record) = match item {
+                        // The tail of the active datafile may be written right now
+                        Err(KvError::CorruptRecord { .. }) if *datafile_path == self.active_file_path => break,
+                        item => item?,
+                    };
+                    offsets.push((record.key().clone(), pos));
+                }
+                (offsets, fs::metadata(datafile_path)?.len())
+            }
+        };
+
+        // Every record lasts until the next one, the last one until the end of the datafile
+        let ends = offsets
+            .iter()
+            .skip(1)
+            .map(|(_, pos)| *pos)
+            .chain(std::iter::once(datafile_len));
+        let dead_bytes = offsets
+            .iter()
+            .zip(ends)
+            .filter(|((key, pos), _)| {
+                index.get(key).map_or(true, |pair| {
+                    pair.val().offset != *pos || pair.val().file.path != *datafile_path
+                })
+            })
+            .map(|((_, pos), end)| end.saturating_sub(*pos))
+            .sum();
+        Ok(dead_bytes)
+    }
+
+    /// Index records of the datafile.
+    /// Returns the number of records in the datafile.
+    fn reindex_datafile(&self, index: &Index, datafile_path: &PathBuf) -> Result<usize> {
         debug!("Index datafile: {:?}", datafile_path);
-        let mut reader= self.reader.get_reader(datafile_path);
-        let mut pos = reader.seek(SeekFrom::Start(0))?;
-        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter();
-        while let Some(item) = stream.next() {
-            match item? {
-                Record::Set { key, .. } => {
+        let mut records = 0;
+        for item in self.read_records(datafile_path, None)? {
+            let (pos, record) = item?;
+            records += 1;
+            match record {
+                Record::Set { key, .. } | Record::SetRaw { key, .. } => {
                     index.insert(key, Location::new(pos, datafile_path));
                 }
+                record @ Record::SetWithExpiry { .. } => {
+                    if record.is_expired() {
+                        index.remove(record.key());
+                    } else {
+                        index.insert(record.key().clone(), Location::new(pos, datafile_path));
+                    }
+                }
                 Record::Remove { key } => {
                     index.remove(&key);
                 }
+                Record::BatchBegin { .. } => {}
             }
-            pos = stream.byte_offset() as u64;
+        }
+        Ok(records)
+    }
+
+    /// Read records of the datafile starting from `offset` or from the first record.
+    /// Records are returned with their offsets in the datafile, batch markers are resolved.
+    fn read_records(&self, datafile_path: &PathBuf, offset: Option<u64>) -> Result<RecordStream<'static>> {
+        let mut reader = self.reader.get_reader(datafile_path)?;
+        let (header, records_start) = read_header_from(&mut reader, datafile_path)?;
+        let offset = offset.unwrap_or(records_start);
+        reader.seek(SeekFrom::Start(offset))?;
+
+        if header.checksums {
+            let records = decode_frames(Box::new(reader), header, datafile_path.clone(), offset);
+            Ok(resolve_batches(records, datafile_path.clone()))
+        } else {
+            let records = header.record_codec().decode_stream(Box::new(reader));
+            Ok(Box::new(records.map(move |item| item.map(|(pos, record)| (offset + pos, record)))))
+        }
+    }
+
+    /// Get the header of the datafile and the offset of its first record.
+    /// Datafiles without header contain records from the very beginning.
+    /// # Error
+    /// It returns `KvError::UnsupportedRecordVersion` if records of the datafile are written by a newer version.
+    fn read_header(&self, datafile_path: &PathBuf) -> Result<(DatafileHeader, u64)> {
+        let mut reader = self.reader.get_reader(datafile_path)?;
+        read_header_from(&mut reader, datafile_path)
+    }
+
+    /// Cut off the corrupted tail of the active datafile starting from `offset`.
+    /// Records after the corrupted one are lost, e.g. they are partially written before a crash.
+    /// Nothing is changed if the `Log` is opened for reading only.
+    fn truncate_active(&self, offset: u64) -> Result<()> {
+        warn!("Active datafile is corrupted at offset {}", offset);
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&self.active_file_path)?
+            .set_len(offset)?;
+        self.active_bytes.store(offset, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Cut off the tail of the active datafile without checksums after its last whole record.
+    /// # Error
+    /// It returns `KvError::CorruptRecord` if the undecodable record is not the cut-off last one,
+    /// nothing is truncated then.
+    fn repair_active(&self, header: DatafileHeader, records_start: u64) -> Result<()> {
+        let mut reader = self.reader.get_reader(&self.active_file_path)?;
+        reader.seek(SeekFrom::Start(records_start))?;
+        let valid_len = header.record_codec().valid_len(Box::new(reader), &self.active_file_path, records_start)?;
+        if valid_len < self.active_bytes.load(Ordering::SeqCst) {
+            self.truncate_active(valid_len)?;
         }
         Ok(())
     }
@@ -216,39 +967,167 @@ impl Log {
         let active_file_path = &self.active_file_path;
         debug!("Create new active file {:?}", active_file_path);
 
-        fs::OpenOptions::new()
+        let mut active_file = fs::OpenOptions::new()
             .create(true)
             .write(true)
             .open(active_file_path)?; //todo return it!!!
+        active_file.write_all(&[self.header().to_byte()])?;
         Ok(())
     }
 
+    /// Create the passive datafile from compacted `records` and its hint.
     fn create_passive(&self, records: Vec<Result<Record>>, serial_number: u64) -> Result<()> {
         let passive_file_path = self.passive_path(serial_number);
+        let hint = self.write_passive(records, &passive_file_path)?;
+        self.compacted_bytes.fetch_add(hint.datafile_len, Ordering::SeqCst);
+        hint.store(&passive_file_path, &self.files.hint_ext)
+    }
+
+    /// Write `records` to the new passive datafile at `passive_file_path`.
+    /// Returns the hint of the datafile, it is not stored.
+    fn write_passive(&self, records: Vec<Result<Record>>, passive_file_path: &PathBuf) -> Result<Hint> {
         debug!("Create new passive file {:?} from {} records", passive_file_path, records.len());
+        create_parent_dir(&passive_file_path)?;
         let file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .append(true)
-            .open(passive_file_path)?;
+            .open(&passive_file_path)?;
         let mut writer = BufWriter::new(file);
+        writer.write_all(&[self.header().to_byte()])?;
 
+        let mut hint = Hint {
+            datafile_len: HEADER_LEN,
+            entries: Vec::with_capacity(records.len()),
+        };
         for record in records {
-            serde_json::to_writer(&mut writer, &record?)?;
+            let record = record?;
+            hint.entries.push(HintEntry::new(&record, hint.datafile_len));
+            match record {
+                // Large values are written streamed, so `KvStore::get_writer` never buffers them
+                Record::Set { key, value } if value.len() as u64 >= STREAMED_VALUE_BYTES => {
+                    let len = value.len() as u64;
+                    let record = Record::SetRaw { key, len };
+                    hint.datafile_len += encode_frame(&mut writer, self.header(), &record)?;
+                    encode_raw(&mut value.as_bytes(), &mut writer, len)?;
+                    hint.datafile_len += len + RAW_CHECKSUM_LEN;
+                }
+                record => hint.datafile_len += encode_frame(&mut writer, self.header(), &record)?,
+            }
         }
         writer.flush()?;
-        Ok(())
+        Ok(hint)
     }
 
-    /// Remove all passive datafiles from fs
+    /// Remove all passive datafiles and their hints from fs
     fn clear_passives(&self) -> Result<()> {
         debug!("Clear passive files");
-        self.dir_path
-            .read_dir()?
-            .filter_map(std::result::Result::ok)
-            .filter(|entry| entry.path().extension() == Some(OsStr::new(PASSIVE_EXT)))
-            .try_for_each(|entry| fs::remove_file(entry.path()))?;
+        passive_files(&self.dir_path, &self.files)?
+            .iter()
+            .try_for_each(fs::remove_file)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Read the header of the datafile `datafile_path` from the beginning of `reader`, see `Log::read_header`.
+/// The position of `reader` is unspecified afterwards.
+fn read_header_from(reader: &mut impl Read, datafile_path: &PathBuf) -> Result<(DatafileHeader, u64)> {
+    let mut header = [0; HEADER_LEN as usize];
+    if reader.read(&mut header)? == header.len() {
+        if let Some(header) = DatafileHeader::from_byte(header[0]) {
+            if !header.is_supported() {
+                return Err(KvError::UnsupportedRecordVersion {
+                    file: datafile_path.clone(),
+                    version: header.version,
+                });
+            }
+            return Ok((header, HEADER_LEN));
+        }
+    }
+    Ok((DatafileHeader::LEGACY, 0))
+}
+
+/// Drop batch markers of `records`, so records of complete batches follow one another.
+/// The batch cut off by a crash is reported as the corrupt record at its marker,
+/// so the whole batch is rolled back like any corrupted tail of the active datafile.
+fn resolve_batches(mut records: RecordStream<'static>, datafile_path: PathBuf) -> RecordStream<'static> {
+    let mut batch = VecDeque::new();
+    Box::new(std::iter::from_fn(move || loop {
+        if let Some(item) = batch.pop_front() {
+            return Some(Ok(item));
+        }
+        match records.next()? {
+            Ok((pos, Record::BatchBegin { len })) => {
+                for _ in 0..len {
+                    match records.next() {
+                        Some(Ok(item)) => batch.push_back(item),
+                        None | Some(Err(KvError::CorruptRecord { .. })) => {
+                            return Some(Err(KvError::CorruptRecord {
+                                file: datafile_path.clone(),
+                                offset: pos,
+                            }));
+                        }
+                        Some(Err(e)) => return Some(Err(e)),
+                    }
+                }
+            }
+            item => return Some(item),
+        }
+    }))
+}
+
+/// Get paths of passive datafiles and their hints in `dir_path` and in its shards of passive datafiles,
+/// so they are found in any layout. Files are recognized by extensions of `names` and serial numbers,
+/// other files are left alone, e.g. `backup.passive`.
+fn passive_files(dir_path: &Path, names: &LogConfig) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for dir in passive_dirs(dir_path)? {
+        for entry in dir.read_dir()? {
+            let path = entry?.path();
+            let extension = path.extension();
+            let has_extension = extension == Some(OsStr::new(&names.passive_ext))
+                || extension == Some(OsStr::new(&names.hint_ext));
+            if has_extension && path.is_file() && get_serial_number(&path).is_some() {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Get `dir_path` and its shards of passive datafiles.
+fn passive_dirs(dir_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![dir_path.to_path_buf()];
+    let passives_dir = dir_path.join(PASSIVES_DIR_NAME);
+    if passives_dir.is_dir() {
+        for entry in passives_dir.read_dir()? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+/// Create the directory of the datafile if it is missing, e.g. the shard of passive datafiles.
+fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+/// Apply `locations` of the datafile to `index`, keys without location are removed.
+fn merge_locations(index: &Index, locations: HashMap<String, Option<Location>>) {
+    for (key, location) in locations {
+        match location {
+            Some(location) => {
+                index.insert(key, location);
+            }
+            None => {
+                index.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt::Debug;
+use std::fs;
+use std::path::PathBuf;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::codec::Codec;
+use super::config::KvStoreConfig;
+use crate::engine::{KvError, Result};
+
+/// Version of the datafiles format produced by this implementation.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Compression of records in datafiles.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Records are compressed by gzip, it pays off for large compressible values.
+    Gzip,
+}
+
+/// `Manifest` is a self-describing metadata of the storage directory.
+/// It is kept in the single `MANIFEST` file, see `LogConfig::manifest_file_name`.
+/// It records the format of datafiles, so `Log` can configure itself on opening and refuse to open incompatible data.
+/// Passive datafiles of the `Log` are `first_serial_number..=last_serial_number`,
+/// there are no passive datafiles if `last_serial_number` is 0.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub codec: Codec,
+    pub compression: Compression,
+    pub record_separator: Option<char>,
+    pub chunk_size: usize,
+    /// Number of subdirectories passive datafiles are sharded into, 0 for the flat layout.
+    #[serde(default)]
+    pub passive_shards: u64,
+    pub first_serial_number: u64,
+    pub last_serial_number: u64,
+}
+
+impl Manifest {
+    /// Read `Manifest` from the manifest file `path`.
+    /// Returns `None` if there is no manifest file.
+    pub fn load(path: &PathBuf) -> Result<Option<Manifest>> {
+        if !path.exists() {
+            debug!("No manifest file {:?}", path);
+            return Ok(None);
+        }
+
+        let manifest: Manifest = serde_json::from_reader(fs::File::open(&path)?)?;
+        debug!("Load manifest: {:?}", manifest);
+        manifest.check()?;
+        Ok(Some(manifest))
+    }
+
+    /// Write `Manifest` to the manifest file `path`.
+    /// The manifest is written to the temporary file first and then renamed,
+    /// so a crash never leaves a partially written manifest.
+    pub fn store(&self, path: &PathBuf) -> Result<()> {
+        debug!("Store manifest: {:?}", self);
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        serde_json::to_writer(fs::File::create(&tmp_path)?, self)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Check that options explicitly set in `config` agree with the manifest,
+    /// options which are not set are restored from the manifest.
+    /// # Error
+    /// It returns `KvError::ManifestMismatch` for the first conflicting option.
+    pub fn check_config(&self, config: &KvStoreConfig) -> Result<()> {
+        check_option("codec", self.codec, config.codec)?;
+        check_option("compression", self.compression, config.compression)?;
+        check_option("chunk size", self.chunk_size, config.records_in_compacted)
+    }
+
+    /// Check that datafiles described by the manifest can be read by this implementation.
+    fn check(&self) -> Result<()> {
+        if self.format_version != FORMAT_VERSION {
+            return Err(KvError::IncompatibleManifest(format!(
+                "format version {}, expected {}",
+                self.format_version, FORMAT_VERSION
+            )));
+        }
+        if self.record_separator.is_some() {
+            return Err(KvError::IncompatibleManifest(format!(
+                "record separator {:?} is not supported",
+                self.record_separator
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Check that the option `field` set in the config is the same as in the manifest.
+fn check_option<T: PartialEq + Debug>(field: &str, manifest: T, config: Option<T>) -> Result<()> {
+    match config {
+        Some(config) if config != manifest => Err(KvError::ManifestMismatch {
+            field: field.to_owned(),
+            manifest: format!("{:?}", manifest),
+            config: format!("{:?}", config),
+        }),
+        _ => Ok(()),
+    }
+}
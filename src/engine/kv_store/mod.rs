@@ -1,6 +1,22 @@
+pub use config::{CompactionStrategy, KvStoreConfig, LogConfig};
 pub use kv_store::KvStore;
+pub use location::LocationInfo;
+pub use codec::Codec;
+pub use manifest::{Compression, Manifest};
+pub use verify::VerifyReport;
 
+mod cache;
+mod codec;
+mod config;
+mod frame;
+mod group_commit;
+mod hint;
+mod index;
 mod kv_store;
+mod lazy_index;
 mod log;
 mod location;
+mod manifest;
+mod snapshot;
 mod utils;
+mod verify;
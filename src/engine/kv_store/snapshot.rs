@@ -0,0 +1,41 @@
+use std::io::{self, Read, Write};
+
+use super::kv_store::Record;
+use crate::engine::Result;
+
+/// Length of the prefix of snapshot entries: length of the payload as little-endian `u32`.
+const PREFIX_LEN: usize = 4;
+
+/// Write `record` to the snapshot as the prefix followed by the JSON payload.
+/// JSON is used regardless of the codec of the storage, so snapshots are portable.
+pub fn write_entry(writer: &mut dyn Write, record: &Record) -> Result<()> {
+    let payload = serde_json::to_vec(record)?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Read the next record of the snapshot.
+/// Returns `None` at the end of the snapshot, a truncated entry is an error.
+pub fn read_entry(reader: &mut dyn Read) -> Result<Option<Record>> {
+    let mut prefix = [0; PREFIX_LEN];
+    let mut read = 0;
+    while read < PREFIX_LEN {
+        match reader.read(&mut prefix[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let len = u32::from_le_bytes(prefix) as usize;
+    // The buffer grows with read bytes, so the length claimed by the corrupt snapshot is not preallocated
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
@@ -1,23 +1,92 @@
-use std::path::PathBuf;
-
-use crate::engine::{KvError, Result};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const ACTIVE_FILE_NAME: &'static str = "log.active";
 pub const PASSIVE_EXT: &'static str = "passive";
+pub const HINT_EXT: &'static str = "hint";
+pub const MANIFEST_FILE_NAME: &'static str = "MANIFEST";
+pub const TREES_DIR_NAME: &'static str = "trees";
+pub const PASSIVES_DIR_NAME: &'static str = "passive";
+/// Directory of passive datafiles being written by bulk loading.
+pub const BULK_LOAD_DIR_NAME: &'static str = "bulk_load.tmp";
+/// Directory of completely written passive datafiles of bulk loading which are not moved in place yet.
+pub const BULK_LOADED_DIR_NAME: &'static str = "bulk_load.ready";
+pub const BACKUP_DIR_PREFIX: &'static str = "pre_compact_backup_";
 pub const RECORDS_IN_COMPACTED: usize = 100;
+pub const RECORDS_LIMIT: u64 = 1024;
+pub const KEY_LOCK_STRIPES: usize = 64;
+pub const INDEX_SHARDS: usize = 16;
+/// Values of at least this length are written streamed by compaction.
+pub const STREAMED_VALUE_BYTES: u64 = 64 * 1024;
 
-/// Get serial number from name of passive file
+/// Get serial number from name of passive file or its hint.
+/// Returns `None` if the file is not named by a serial number, i.e. it is not a passive datafile.
+/// Only the file name is parsed, so parent directories of the file don't matter.
 ///
 /// # Examples:
 ///
+/// ```text
+/// 42.passive           -> Some(42)
+/// passive/02/42.hint   -> Some(42)
+/// log.active           -> None
+/// +42.passive, .hidden -> None
 /// ```
-/// use std::path::Path;
-/// assert_eq!(Ok(42), get_serial_number(Path::from("42.passive").to_path_buf()));
-/// ```
-pub fn get_serial_number(path: &PathBuf) -> Result<u64> {
-    path.file_stem()
-        .and_then(|name| name.to_str())
-        .ok_or(KvError::InvalidDatafileName)?
-        .parse::<u64>()
-        .or(Err(KvError::InvalidDatafileName))
+pub fn get_serial_number(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    // `u64::from_str` accepts the leading sign
+    if stem.is_empty() || !stem.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    stem.parse().ok()
+}
+
+/// Get current time in milliseconds since UNIX epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Reader of `len` bytes of the inner reader checking that they are UTF-8, so streamed values
+/// are validated without buffering them. Invalid bytes are reported as the error of kind `InvalidData`.
+pub struct Utf8Reader<R> {
+    inner: io::Take<R>,
+    /// Leading bytes of the character split by the previous read.
+    pending: Vec<u8>,
+}
+
+impl<R: Read> Utf8Reader<R> {
+    pub fn new(inner: R, len: u64) -> Utf8Reader<R> {
+        Utf8Reader { inner: inner.take(len), pending: Vec::new() }
+    }
+}
+
+impl<R: Read> Read for Utf8Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let invalid_utf8 = || io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+        let read = self.inner.read(buf)?;
+        let mut bytes = &buf[..read];
+        while !self.pending.is_empty() && !bytes.is_empty() {
+            self.pending.push(bytes[0]);
+            bytes = &bytes[1..];
+            match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.clear(),
+                Err(e) if e.error_len().is_some() => return Err(invalid_utf8()),
+                Err(_) => {}
+            }
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(_) => {}
+            // The last character may be completed by the next read
+            Err(e) if e.error_len().is_none() => self.pending.extend_from_slice(&bytes[e.valid_up_to()..]),
+            Err(_) => return Err(invalid_utf8()),
+        }
+        // The stream ending by the incomplete character is reported as the short one by the caller
+        if self.inner.limit() == 0 && !self.pending.is_empty() {
+            return Err(invalid_utf8());
+        }
+        Ok(read)
+    }
 }
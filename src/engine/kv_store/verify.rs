@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Result of verification of datafiles.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct VerifyReport {
+    /// Number of checked datafiles.
+    pub datafiles: usize,
+    /// Number of successfully read records.
+    pub records: usize,
+    /// Number of keys in the index built from datafiles.
+    pub keys: usize,
+    /// Datafiles which are absent or contain unreadable records.
+    pub corrupted: Vec<PathBuf>,
+    /// Keys whose location does not refer to the record of the key.
+    pub inconsistent: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Check if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty() && self.inconsistent.is_empty()
+    }
+}
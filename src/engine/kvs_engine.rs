@@ -1,10 +1,171 @@
-use super::error::Result;
+use super::error::{KvError, Result};
+use super::kv_store::VerifyReport;
+use super::stats::KvStats;
 use std::path::PathBuf;
 use std::panic::UnwindSafe;
 
+use log::debug;
+
 pub trait KvsEngine : Send + Clone + 'static {
     fn open(path: impl Into<PathBuf>) -> Result<Self>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn set(&self, key: String, value: String) -> Result<()>;
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Get values of `keys` in the same order, `None` for absent keys.
+    fn get_batch(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Check if `key` is present without reading its value where the engine allows it.
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key.to_owned())?.is_some())
+    }
+
+    /// Atomically replace the value of `key` with `new` if the current value equals `expected`.
+    /// `None` means the absent key for both values.
+    /// Returns `true` if the swap happened.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool>;
+
+    /// Atomically set the value of `key` only if the key is absent.
+    /// Returns `true` if the value is set, `false` if the key already exists.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Atomically add `delta` to the integer value of `key` and return the new value.
+    /// The absent key is created with the value of `delta`.
+    /// # Error
+    /// It returns `KvError::NotAnInteger` if the value is not an integer or the sum overflows.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        loop {
+            let current = self.get(key.clone())?;
+            let value = add_to_value(current.as_ref(), delta)?;
+            if self.compare_and_swap(key.clone(), current, Some(value.to_string()))? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Get the max size of keys accepted by the engine, `None` if it is unlimited.
+    fn max_key_bytes(&self) -> Option<usize> {
+        None
+    }
+
+    /// Get the max size of values accepted by the engine, `None` if it is unlimited.
+    fn max_value_bytes(&self) -> Option<usize> {
+        None
+    }
+
+    /// Check sizes of the key and the value against limits of the engine,
+    /// e.g. before receiving the value.
+    /// # Error
+    /// It returns `KvError::KeyTooLarge` or `KvError::ValueTooLarge` if the limit is exceeded.
+    fn check_size(&self, key_len: usize, value_len: u64) -> Result<()> {
+        if let Some(max) = self.max_key_bytes() {
+            if key_len > max {
+                return Err(KvError::KeyTooLarge { len: key_len, max });
+            }
+        }
+        if let Some(max) = self.max_value_bytes() {
+            if value_len > max as u64 {
+                return Err(KvError::ValueTooLarge { len: value_len, max });
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the number of live keys.
+    fn len(&self) -> usize;
+
+    /// Check if there are no live keys.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get keys starting with `prefix` in ascending order, at most `limit` keys if it is set.
+    /// # Error
+    /// The default implementation returns `KvError::UnknownError`, keys can't be listed by it.
+    fn scan_keys(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        debug!("Scan keys, prefix: {}, limit: {:?}", prefix, limit);
+        Err(KvError::UnknownError("Scanning keys is not supported by the engine".to_owned()))
+    }
+
+    /// Apply `ops` atomically in their order: either all of them become visible or none.
+    /// Removing the absent key is not an error in the batch.
+    /// # Error
+    /// The default implementation returns `KvError::UnknownError`, it can't apply operations atomically.
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<()> {
+        debug!("Write batch of {} operations", ops.len());
+        Err(KvError::UnknownError("Batches are not supported by the engine".to_owned()))
+    }
+
+    /// Force all acknowledged writes to disk, e.g. to checkpoint a batch of writes
+    /// made without syncing. Without it writes are only as durable as the `DurabilityMode`
+    /// of the engine allows, for `DurabilityMode::NoSync` it is the OS buffer cache.
+    /// Does nothing for engines which don't buffer writes.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Compact the storage immediately.
+    /// Does nothing for engines which compact themselves.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start a new log file, older records are kept in the previous one.
+    /// Does nothing for engines without log files.
+    fn rotate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove all keys of the storage.
+    /// # Error
+    /// The default implementation returns `KvError::UnknownError`, keys can't be removed all together by it.
+    fn clear(&self) -> Result<()> {
+        Err(KvError::UnknownError("Clearing is not supported by the engine".to_owned()))
+    }
+
+    /// Check that every live key refers to the readable record of its value, e.g. to detect drift
+    /// of the index from the log. Problems are collected to the report instead of failing.
+    /// # Error
+    /// The default implementation returns `KvError::UnknownError`, the engine has nothing to verify by it.
+    fn verify(&self) -> Result<VerifyReport> {
+        Err(KvError::UnknownError("Verification is not supported by the engine".to_owned()))
+    }
+
+    /// Get statistics of the engine.
+    fn stats(&self) -> KvStats {
+        KvStats {
+            live_keys: self.len() as u64,
+            ..KvStats::default()
+        }
+    }
+}
+
+/// Operation of the batch applied atomically by `KvsEngine::write_batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl WriteOp {
+    /// Get the key of the operation.
+    pub fn key(&self) -> &str {
+        match self {
+            WriteOp::Set { key, .. } => key,
+            WriteOp::Remove { key } => key,
+        }
+    }
+}
+
+/// Add `delta` to the integer `value`, the absent value is 0.
+pub fn add_to_value(value: Option<&String>, delta: i64) -> Result<i64> {
+    let value = match value {
+        Some(value) => value.parse::<i64>().or(Err(KvError::NotAnInteger))?,
+        None => 0,
+    };
+    value.checked_add(delta).ok_or(KvError::NotAnInteger)
 }
@@ -0,0 +1,102 @@
+use crate::{KvError, KvsEngine, Result};
+
+use lockfree::map::Map;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// `MemoryEngine` keeps keys and values in memory only, nothing is written to disk.
+/// It is intended for tests and ephemeral storages: all data is lost when the last clone is dropped.
+///
+/// Reads are lock-free, writes are serialized by one lock,
+/// so `compare_and_swap` is atomic with respect to other writes.
+#[derive(Clone)]
+pub struct MemoryEngine {
+    map: Arc<Map<String, String>>,
+    writes: Arc<Mutex<()>>,
+}
+
+impl KvsEngine for MemoryEngine {
+    /// Create the empty `MemoryEngine`, `path` is ignored.
+    fn open(_path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(MemoryEngine::new())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).map(|pair| pair.val().clone()))
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.map.get(key).is_some())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _write = self.writes.lock().unwrap();
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _write = self.writes.lock().unwrap();
+        self.map.remove(&key).ok_or(KvError::KeyNotFound)?;
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let _write = self.writes.lock().unwrap();
+        let current = self.map.get(&key).map(|pair| pair.val().clone());
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                self.map.insert(key, value);
+            }
+            None => {
+                self.map.remove(&key);
+            }
+        }
+        Ok(true)
+    }
+
+    fn len(&self) -> usize {
+        self.map.iter().count()
+    }
+
+    fn clear(&self) -> Result<()> {
+        let _write = self.writes.lock().unwrap();
+        let keys: Vec<String> = self.map.iter().map(|pair| pair.key().clone()).collect();
+        for key in keys {
+            self.map.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn scan_keys(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        let mut keys = self
+            .map
+            .iter()
+            .filter(|pair| pair.key().starts_with(prefix))
+            .map(|pair| pair.key().clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        if let Some(limit) = limit {
+            keys.truncate(limit);
+        }
+        Ok(keys)
+    }
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        MemoryEngine {
+            map: Arc::new(Map::new()),
+            writes: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        MemoryEngine::new()
+    }
+}
@@ -0,0 +1,34 @@
+use log::{debug, info};
+
+use super::error::{KvError, Result};
+use super::kvs_engine::KvsEngine;
+
+/// Copy all keys of `src` to `dst`, e.g. to move the storage to another engine.
+/// Keys removed from `src` concurrently are skipped. Returns the number of migrated keys.
+/// Only values are copied: `KvsEngine` has no notion of TTLs, so keys set by `KvStore::set_with_ttl`
+/// are copied without expiration, keys expired before the migration are skipped.
+/// # Error
+/// It returns `KvError::NotEmpty` if `dst` has keys, so the migration should write to a fresh directory,
+/// and `KvError::MigrationMismatch` if `dst` doesn't end up with all migrated keys.
+pub fn migrate(src: &impl KvsEngine, dst: &impl KvsEngine) -> Result<usize> {
+    if !dst.is_empty() {
+        return Err(KvError::NotEmpty);
+    }
+
+    let mut migrated = 0;
+    for key in src.scan_keys("", None)? {
+        if let Some(value) = src.get(key.clone())? {
+            debug!("Migrate key: {}", key);
+            dst.set(key, value)?;
+            migrated += 1;
+        }
+    }
+    dst.flush()?;
+
+    let stored = dst.len();
+    if stored != migrated {
+        return Err(KvError::MigrationMismatch { migrated, stored });
+    }
+    info!("Migrated {} keys", migrated);
+    Ok(migrated)
+}
@@ -1,7 +1,21 @@
+#[cfg(feature = "async")]
+pub use async_engine::AsyncKvsEngine;
+pub use durability::DurabilityMode;
+pub use engine_file::{reconcile_engine_file, EngineKind, ENGINE_FILE_NAME};
 pub use error::{KvError, Result};
-pub use kvs_engine::KvsEngine;
+pub use kvs_engine::{KvsEngine, WriteOp};
+pub use migrate::migrate;
+pub use stats::KvStats;
 
+#[cfg(feature = "async")]
+pub mod async_engine;
+pub mod durability;
+pub mod engine_file;
 pub mod error;
 pub mod kv_store;
 pub mod kvs_engine;
+#[cfg(feature = "memory")]
+pub mod memory;
+pub mod migrate;
 pub mod sled;
+pub mod stats;
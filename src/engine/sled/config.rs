@@ -0,0 +1,39 @@
+use crate::engine::DurabilityMode;
+
+/// Configuration of `SledEngine`.
+///
+/// # Example:
+/// ```rust
+/// use kvs::{DurabilityMode, SledConfig, SledEngine};
+/// let config = SledConfig {
+///     durability: DurabilityMode::NoSync,
+///     flush_every_ms: Some(100),
+///     ..SledConfig::default()
+/// };
+/// let engine = SledEngine::open_with(std::env::current_dir().unwrap(), config).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledConfig {
+    /// When writes are flushed by the engine, every write is flushed by default.
+    pub durability: DurabilityMode,
+
+    /// Size of the page cache of sled in bytes.
+    /// `None` keeps the default of sled.
+    pub cache_capacity: Option<u64>,
+
+    /// Interval of flushes made by sled itself in the background, in milliseconds.
+    /// With `DurabilityMode::NoSync` it bounds the time writes may stay unflushed,
+    /// `None` disables background flushes.
+    pub flush_every_ms: Option<u64>,
+}
+
+impl Default for SledConfig {
+    fn default() -> Self {
+        SledConfig {
+            durability: DurabilityMode::FsyncEveryWrite,
+            cache_capacity: None,
+            // Default of sled
+            flush_every_ms: Some(500),
+        }
+    }
+}
@@ -1,22 +1,32 @@
-use crate::{KvError, KvsEngine, Result};
+pub use config::SledConfig;
+
+use crate::{DurabilityMode, KvError, KvsEngine, Result, WriteOp};
 
 use sled;
-use sled::{Db, Tree};
+use sled::{Batch, Db, Tree};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+mod config;
 
+/// `SledEngine` shares `Db` between clones without locking,
+/// `sled::Db` is thread-safe itself.
 pub struct SledEngine {
-    db: Arc<Mutex<Db>>,
+    db: Arc<Db>,
+    durability: DurabilityMode,
+    /// Number of writes since the last flush, shared between clones.
+    unflushed: Arc<AtomicU64>,
 }
 
 impl KvsEngine for SledEngine {
+    /// Open `SledEngine` flushing every write.
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let db = Arc::new(Mutex::new(sled::open(path.into())?));
-        Ok(SledEngine { db })
+        SledEngine::open_with_durability(path, DurabilityMode::FsyncEveryWrite)
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.db.lock().unwrap();
+        let tree: &Tree = &self.db;
         Ok(tree
             .get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
@@ -24,23 +34,128 @@ impl KvsEngine for SledEngine {
             .transpose()?)
     }
 
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let tree: &Tree = &self.db;
+        Ok(tree.contains_key(key)?)
+    }
+
     fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.db.lock().unwrap();
+        let tree: &Tree = &self.db;
         tree.insert(key, value.into_bytes())?;
-        tree.flush()?;
+        self.flush_if_needed(tree)?;
         Ok(())
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.db.lock().unwrap();
+        let tree: &Tree = &self.db;
         tree.remove(key)?.ok_or(KvError::KeyNotFound)?;
-        tree.flush()?;
+        self.flush_if_needed(tree)?;
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let tree: &Tree = &self.db;
+        let swapped = tree
+            .compare_and_swap(key, expected, new.map(String::into_bytes))?
+            .is_ok();
+        self.flush_if_needed(tree)?;
+        Ok(swapped)
+    }
+
+    /// Apply `ops` as one sled `Batch`.
+    fn write_batch(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let tree: &Tree = &self.db;
+        let mut batch = Batch::default();
+        for op in ops {
+            match op {
+                WriteOp::Set { key, value } => batch.insert(key.as_bytes(), value.into_bytes()),
+                WriteOp::Remove { key } => batch.remove(key.as_bytes()),
+            }
+        }
+        tree.apply_batch(batch)?;
+        self.flush_if_needed(tree)
+    }
+
+    /// Flush all writes of the database to disk.
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        self.unflushed.store(0, Ordering::SeqCst);
         Ok(())
     }
+
+    fn scan_keys(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        let tree: &Tree = &self.db;
+        tree.scan_prefix(prefix)
+            .keys()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|key| Ok(String::from_utf8(AsRef::<[u8]>::as_ref(&key?).to_vec())?))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        let tree: &Tree = &self.db;
+        tree.len()
+    }
+
+    fn clear(&self) -> Result<()> {
+        let tree: &Tree = &self.db;
+        tree.clear()?;
+        self.flush_if_needed(tree)
+    }
+}
+
+impl SledEngine {
+    /// Open `SledEngine` flushing writes according to `durability`.
+    /// With `DurabilityMode::NoSync` writes are flushed by sled itself in the background.
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: DurabilityMode) -> Result<Self> {
+        SledEngine::open_with(path, SledConfig { durability, ..SledConfig::default() })
+    }
+
+    /// Open `SledEngine` with the given path and configuration.
+    pub fn open_with(path: impl Into<PathBuf>, config: SledConfig) -> Result<Self> {
+        let mut sled_config = sled::Config::new()
+            .path(path.into())
+            .flush_every_ms(config.flush_every_ms);
+        if let Some(cache_capacity) = config.cache_capacity {
+            sled_config = sled_config.cache_capacity(cache_capacity);
+        }
+        let db = Arc::new(sled_config.open()?);
+        Ok(SledEngine {
+            db,
+            durability: config.durability,
+            unflushed: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Flush `tree` if it is required by the durability mode.
+    fn flush_if_needed(&self, tree: &Tree) -> Result<()> {
+        if self.durability.needs_sync(&self.unflushed) {
+            tree.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Remove all keys starting with `prefix` in one batch, returns the number of removed keys.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let tree: &Tree = &self.db;
+        let mut batch = Batch::default();
+        let mut removed = 0;
+        for key in tree.scan_prefix(prefix).keys() {
+            batch.remove(key?);
+            removed += 1;
+        }
+        tree.apply_batch(batch)?;
+        self.flush_if_needed(tree)?;
+        Ok(removed)
+    }
 }
 
 impl Clone for SledEngine {
     fn clone(&self) -> Self {
-        SledEngine{ db: Arc::clone(&self.db) }
+        SledEngine {
+            db: Arc::clone(&self.db),
+            durability: self.durability,
+            unflushed: Arc::clone(&self.unflushed),
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+/// Statistics of the storage engine.
+/// Engines without a log report only `live_keys`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct KvStats {
+    /// Number of live keys.
+    pub live_keys: u64,
+    /// Number of records in datafiles including unused ones.
+    pub records: u64,
+    /// Number of records which are overwritten or removed since the last compaction.
+    pub unused_records: u64,
+    /// Number of passive datafiles.
+    pub passive_files: u64,
+    /// Number of compactions performed since opening.
+    pub compactions: u64,
+    /// Number of bytes written to passive datafiles by compactions since opening.
+    pub compacted_bytes: u64,
+    /// Number of syncs of the active datafile required by the durability mode since opening,
+    /// syncs shared by the group commit are counted once.
+    pub syncs: u64,
+    /// Estimated number of bytes of unused records which would be reclaimed by the full compaction.
+    pub reclaimable_bytes: u64,
+    /// Number of reads served by the value cache.
+    pub cache_hits: u64,
+    /// Number of reads missed the value cache while it is enabled.
+    pub cache_misses: u64,
+}
@@ -1,11 +1,48 @@
-pub use client::Client;
-pub use engine::kv_store::KvStore;
-pub use engine::sled::SledEngine;
-pub use engine::{KvError, KvsEngine, Result};
-pub use server::Server;
+//! Key-value storage with the log-based `KvStore` and `SledEngine` engines,
+//! the client and the server of its network protocol.
+//!
+//! Storage types are re-exported from the crate root only,
+//! there are no top-level duplicates of the `engine` modules:
+//!
+//! ```compile_fail
+//! use kvs::kv::KvStore;
+//! ```
+//!
+//! ```compile_fail
+//! use kvs::log::LogPointer;
+//! ```
+//!
+//! ```compile_fail
+//! use kvs::datafile::DataFile;
+//! ```
+//!
+//! ```compile_fail
+//! use kvs::error::KvError;
+//! ```
+
+pub use client::{Client, ClientBuilder, Session, TraceEntry};
+pub use engine::kv_store::{
+    Codec, CompactionStrategy, Compression, KvStore, KvStoreConfig, LocationInfo, LogConfig, Manifest, VerifyReport,
+};
+pub use engine::sled::{SledConfig, SledEngine};
+pub use engine::{
+    migrate, reconcile_engine_file, DurabilityMode, EngineKind, KvError, KvStats, KvsEngine, Result, WriteOp,
+    ENGINE_FILE_NAME,
+};
+pub use metrics::Metrics;
+pub use server::{Server, ServerConfig, ShutdownHandle};
+pub use utils::WaitGroup;
+#[cfg(feature = "async")]
+pub use engine::AsyncKvsEngine;
+#[cfg(feature = "async")]
+pub use server::AsyncServer;
+#[cfg(feature = "memory")]
+pub use engine::memory::MemoryEngine;
 
 mod client;
 mod engine;
+mod metrics;
 pub mod protocol;
 mod server;
 pub mod thread_pool;
+mod utils;
@@ -0,0 +1,98 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::protocol::{Request, Response};
+use crate::KvStats;
+
+/// Metrics of the server: counters of served requests and gauges of the engine.
+/// Counters are incremented by the server, gauges are updated from `KvStats` of the engine.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    errors: AtomicU64,
+    compactions: AtomicU64,
+    live_keys: AtomicU64,
+    passive_files: AtomicU64,
+    reclaimable_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count the request, items of batches are counted separately.
+    pub fn record_request(&self, request: &Request) {
+        let (counter, count) = match request {
+            Request::Get { .. } => (&self.gets, 1),
+            Request::MGet(keys) => (&self.gets, keys.len() as u64),
+            Request::Set { .. } | Request::SetNx { .. } | Request::SetStream { .. } => (&self.sets, 1),
+            Request::Rm { .. } => (&self.removes, 1),
+            _ => return,
+        };
+        counter.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Count the error response, errors of batch items are counted separately.
+    pub fn record_response(&self, response: &Response) {
+        match response {
+            Response::Err(_) => {
+                self.errors.fetch_add(1, Ordering::SeqCst);
+            }
+            Response::Batch(responses) => responses.iter().for_each(|response| self.record_response(response)),
+            _ => {}
+        }
+    }
+
+    /// Update gauges and the number of compactions from statistics of the engine.
+    pub fn update_stats(&self, stats: &KvStats) {
+        self.compactions.store(stats.compactions, Ordering::SeqCst);
+        self.live_keys.store(stats.live_keys, Ordering::SeqCst);
+        self.passive_files.store(stats.passive_files, Ordering::SeqCst);
+        self.reclaimable_bytes.store(stats.reclaimable_bytes, Ordering::SeqCst);
+    }
+
+    /// Render metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut text = self.render_requests_prometheus();
+        let metrics = [
+            ("kvs_compactions_total", "counter", "Number of compactions of the engine.", &self.compactions),
+            ("kvs_live_keys", "gauge", "Number of live keys.", &self.live_keys),
+            ("kvs_passive_files", "gauge", "Number of passive datafiles.", &self.passive_files),
+            (
+                "kvs_reclaimable_bytes",
+                "gauge",
+                "Estimated number of bytes reclaimed by compaction.",
+                &self.reclaimable_bytes,
+            ),
+        ];
+        render(&mut text, &metrics);
+        text
+    }
+
+    /// Render only counters of served requests in the Prometheus text exposition format,
+    /// without gauges of the engine.
+    pub fn render_requests_prometheus(&self) -> String {
+        let metrics = [
+            ("kvs_gets_total", "counter", "Number of get requests.", &self.gets),
+            ("kvs_sets_total", "counter", "Number of set requests.", &self.sets),
+            ("kvs_removes_total", "counter", "Number of remove requests.", &self.removes),
+            ("kvs_errors_total", "counter", "Number of error responses.", &self.errors),
+        ];
+        let mut text = String::new();
+        render(&mut text, &metrics);
+        text
+    }
+}
+
+/// Append `metrics` of name, kind, help and value to `text`.
+fn render(text: &mut String, metrics: &[(&str, &str, &str, &AtomicU64)]) {
+    for (name, kind, help, value) in metrics.iter() {
+        // Writing to `String` never fails
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(text, "{} {}", name, value.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,3 @@
+pub use metrics::Metrics;
+
+mod metrics;
@@ -0,0 +1,65 @@
+use std::io::{self, Read, Write};
+
+use super::ProtocolError;
+
+/// Max length of the chunk written by `Client`.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Write `data` as a chunk: little-endian `u32` length followed by the data.
+/// The empty chunk terminates the stream of chunks.
+pub fn write_chunk<W: Write>(mut writer: W, data: &[u8]) -> Result<(), ProtocolError> {
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// Read chunks until the terminating empty chunk and concatenate them.
+/// # Error
+/// It fails if the stream is interrupted before the terminating chunk
+/// or the total length of chunks differs from `len`.
+pub fn read_chunks<R: Read>(mut reader: R, len: u64) -> Result<Vec<u8>, ProtocolError> {
+    let mut data = Vec::new();
+    loop {
+        let mut chunk_len = [0; 4];
+        reader.read_exact(&mut chunk_len)?;
+        let chunk_len = u32::from_le_bytes(chunk_len) as u64;
+        if chunk_len == 0 {
+            break;
+        }
+        if data.len() as u64 + chunk_len > len {
+            return Err(ProtocolError::UnknownError(format!(
+                "Chunks exceed the declared length {}",
+                len
+            )));
+        }
+        reader.by_ref().take(chunk_len).read_to_end(&mut data)?;
+    }
+
+    if data.len() as u64 != len {
+        return Err(ProtocolError::UnknownError(format!(
+            "Received {} bytes of {} declared",
+            data.len(),
+            len
+        )));
+    }
+    Ok(data)
+}
+
+/// Read chunks until the terminating empty chunk and discard them, e.g. for the rejected value.
+/// Returns the total length of chunks.
+pub fn skip_chunks<R: Read>(mut reader: R) -> Result<u64, ProtocolError> {
+    let mut skipped = 0;
+    loop {
+        let mut chunk_len = [0; 4];
+        reader.read_exact(&mut chunk_len)?;
+        let chunk_len = u32::from_le_bytes(chunk_len) as u64;
+        if chunk_len == 0 {
+            return Ok(skipped);
+        }
+        let copied = io::copy(&mut reader.by_ref().take(chunk_len), &mut io::sink())?;
+        if copied != chunk_len {
+            return Err(ProtocolError::Disconnected);
+        }
+        skipped += copied;
+    }
+}
@@ -1,21 +1,44 @@
-use failure::Fail;
+use thiserror::Error;
 use log::error;
 
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum ProtocolError {
-    #[fail(display = "IO Error: {}", _0)]
-    IoError(#[cause] std::io::Error),
+    #[error("IO Error: {0}")]
+    IoError(#[source] std::io::Error),
 
-    #[fail(display = "Serde Error: {}", _0)]
-    SerdeError(#[cause] serde_json::Error),
+    #[error("Serde Error: {0}")]
+    SerdeError(#[source] serde_json::Error),
 
-    #[fail(display = "Unknown Error: {}", _0)]
+    #[error("Disconnected in the middle of request")]
+    Disconnected,
+
+    #[error("Timed out")]
+    Timeout,
+
+    #[error("Frame of {0} bytes is too large")]
+    FrameTooLarge(u64),
+
+    #[error("Compression Error: {0}")]
+    CompressionError(String),
+
+    #[error("Unable to connect after {attempts} attempts: {last}")]
+    ConnectFailed {
+        attempts: u32,
+        #[source]
+        last: Box<ProtocolError>,
+    },
+
+    #[error("Unknown Error: {0}")]
     UnknownError(String),
 }
 
 impl From<std::io::Error> for ProtocolError {
     fn from(err: std::io::Error) -> ProtocolError {
-        let res = ProtocolError::IoError(err);
+        // Reading from the socket with the expired timeout fails with `WouldBlock` on Unix
+        let res = match err.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ProtocolError::Timeout,
+            _ => ProtocolError::IoError(err),
+        };
         error!("{}", res);
         res
     }
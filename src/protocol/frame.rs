@@ -0,0 +1,127 @@
+use std::io::{Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::ProtocolError;
+
+/// Length of the frame prefix: big-endian `u32` length of the payload.
+pub const FRAME_PREFIX_LEN: usize = 4;
+
+/// Max length of the payload of the frame.
+/// Larger values should be sent by `Request::SetStream`.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Min length of the JSON payload compressed by `encode_frame_with`,
+/// compression of smaller payloads saves less than it costs.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Bit of the length prefix set for payloads compressed by LZ4.
+/// Lengths never reach it, so peers which don't compress reject such frames as too large.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// Length of the size of the decompressed payload prepended to compressed bytes.
+const DECOMPRESSED_LEN_PREFIX_LEN: usize = 4;
+
+/// Encode `message` as a frame: the length prefix followed by the JSON payload.
+pub fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>, ProtocolError> {
+    encode_frame_with(message, false)
+}
+
+/// Encode `message` as a frame, the payload is compressed if `compression` is negotiated
+/// for the connection by `Request::Hello` and it is at least `COMPRESSION_THRESHOLD` bytes.
+pub fn encode_frame_with<T: Serialize>(message: &T, compression: bool) -> Result<Vec<u8>, ProtocolError> {
+    let mut payload = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(ProtocolError::FrameTooLarge(payload.len() as u64));
+    }
+    let mut prefix = payload.len() as u32;
+    if compression && payload.len() >= COMPRESSION_THRESHOLD {
+        payload = lz4_flex::compress_prepend_size(&payload);
+        prefix = payload.len() as u32 | COMPRESSED_FLAG;
+    }
+    let mut frame = Vec::with_capacity(FRAME_PREFIX_LEN + payload.len());
+    frame.extend_from_slice(&prefix.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Get the length of the payload from the frame prefix.
+/// Compressed frames are rejected as too large.
+pub fn decode_frame_len(prefix: [u8; FRAME_PREFIX_LEN]) -> Result<usize, ProtocolError> {
+    let len = u32::from_be_bytes(prefix);
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len as u64));
+    }
+    Ok(len as usize)
+}
+
+/// Get the length of the payload from the frame prefix and check if the payload is compressed.
+pub fn decode_frame_prefix(prefix: [u8; FRAME_PREFIX_LEN]) -> Result<(usize, bool), ProtocolError> {
+    let prefix = u32::from_be_bytes(prefix);
+    let is_compressed = prefix & COMPRESSED_FLAG != 0;
+    let len = decode_frame_len((prefix & !COMPRESSED_FLAG).to_be_bytes())?;
+    Ok((len, is_compressed))
+}
+
+/// Decode the message from the payload of the frame.
+pub fn decode_frame<T: DeserializeOwned>(payload: &[u8]) -> Result<T, ProtocolError> {
+    Ok(serde_json::from_slice(payload)?)
+}
+
+/// Decompress the payload of the compressed frame.
+/// # Error
+/// It fails if the payload is malformed or it is decompressed to more than `MAX_FRAME_LEN` bytes.
+pub fn decompress_payload(payload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    if payload.len() < DECOMPRESSED_LEN_PREFIX_LEN {
+        return Err(ProtocolError::CompressionError("Compressed payload is truncated".to_owned()));
+    }
+    let len = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len as u64));
+    }
+    lz4_flex::decompress_size_prepended(payload).map_err(|e| ProtocolError::CompressionError(e.to_string()))
+}
+
+/// Write `message` as a frame to `writer`.
+pub fn write_frame<W: Write, T: Serialize>(writer: W, message: &T) -> Result<(), ProtocolError> {
+    write_frame_with(writer, message, false)
+}
+
+/// Write `message` as a frame to `writer`, compressed as by `encode_frame_with`.
+pub fn write_frame_with<W: Write, T: Serialize>(
+    mut writer: W,
+    message: &T,
+    compression: bool,
+) -> Result<(), ProtocolError> {
+    writer.write_all(&encode_frame_with(message, compression)?)?;
+    Ok(())
+}
+
+/// Read the next frame from `reader` and decode its message, compressed frames are decompressed.
+/// Returns `None` if the stream is over before the frame.
+/// # Error
+/// It returns `ProtocolError::Disconnected` if the stream is over in the middle of the frame.
+pub fn read_frame<R: Read, T: DeserializeOwned>(mut reader: R) -> Result<Option<T>, ProtocolError> {
+    let mut prefix = [0; FRAME_PREFIX_LEN];
+    let mut read = 0;
+    while read < prefix.len() {
+        match reader.read(&mut prefix[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(ProtocolError::Disconnected),
+            n => read += n,
+        }
+    }
+
+    let (len, is_compressed) = decode_frame_prefix(prefix)?;
+    // The buffer grows with received bytes, so the length claimed by the peer is not preallocated
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(ProtocolError::Disconnected);
+    }
+    if is_compressed {
+        payload = decompress_payload(&payload)?;
+    }
+    decode_frame(&payload).map(Some)
+}
@@ -1,7 +1,16 @@
+pub use chunk::{read_chunks, skip_chunks, write_chunk, CHUNK_SIZE};
 pub use error::ProtocolError;
-pub use request::Request;
-pub use response::Response;
+pub use frame::{
+    decode_frame, decode_frame_len, decode_frame_prefix, decompress_payload, encode_frame, encode_frame_with,
+    read_frame, write_frame, write_frame_with, COMPRESSION_THRESHOLD, FRAME_PREFIX_LEN, MAX_FRAME_LEN,
+};
+pub use request::{AdminOp, Request};
+pub use response::{Response, ResponseError};
+pub use transport::{Address, Listener, Stream};
 
+mod chunk;
 mod error;
+mod frame;
 mod request;
 mod response;
+mod transport;
@@ -1,8 +1,66 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Get { key: String },
+    /// Get values of `keys`, the answer is `Response::Values` in the same order.
+    MGet(Vec<String>),
+    /// Check if `key` is present, the answer is `Response::Bool`.
+    Exists { key: String },
     Set { key: String, value: String },
+    /// Set the value only if `key` is absent, the answer is `Response::Bool` telling if it is set.
+    SetNx { key: String, value: String },
+    /// Set the value of `len` bytes sent after the request in chunks.
+    SetStream { key: String, len: u64 },
     Rm { key: String },
+    /// Add `delta` to the integer value of `key`, the new value is returned.
+    Incr { key: String, delta: i64 },
+    /// List keys starting with `prefix` in ascending order, at most `limit` keys if it is set.
+    /// The answer is `Response::Keys`.
+    Scan { prefix: Option<String>, limit: Option<usize> },
+    Compact,
+    /// Check if the server is up, the storage is not touched. The answer is `Response::Pong`.
+    Ping,
+    Stats,
+    /// Check that every live key refers to the record of its value, the answer is `Response::Verify`.
+    Verify,
+    /// Get metrics of the server in the Prometheus text format, the answer is `Response::Text`.
+    Metrics,
+    /// Negotiate capabilities of the connection, it is sent before other requests.
+    /// The answer is `Response::Bool` telling if frames following it are compressed
+    /// as requested by `compression`, it is always sent uncompressed.
+    Hello { compression: bool },
+    /// Requests applied in order, responses are returned by `Response::Batch` in the same order.
+    /// Nested batches and streamed values are rejected by error responses.
+    Batch(Vec<Request>),
+    /// Request with the `id` assigned by the client, its response is `Response::Tagged` with the same `id`,
+    /// so responses of pipelined requests are matched regardless of their order.
+    /// Nested tagged requests are rejected by error responses.
+    Tagged { id: u64, request: Box<Request> },
+    /// Administrative operation applied only if `token` matches the secret configured on the server,
+    /// otherwise the answer is `ResponseError::Unauthorized`. Admin operations are rejected in batches.
+    Admin { token: String, op: AdminOp },
+}
+
+/// Administrative operations of `Request::Admin`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminOp {
+    /// Compact the storage, the answer is `Response::Ok`.
+    Compact,
+    /// Start a new log file of the storage, the answer is `Response::Ok`.
+    Rotate,
+    /// Get statistics of the storage, the answer is `Response::Stats`.
+    Stats,
+    /// Remove all keys of the storage, the answer is `Response::Ok`.
+    Clear,
+}
+
+impl Request {
+    /// Split the tagged request into its id and the request itself, other requests have no id.
+    pub fn untag(self) -> (Option<u64>, Request) {
+        match self {
+            Request::Tagged { id, request } => (Some(id), *request),
+            request => (None, request),
+        }
+    }
 }
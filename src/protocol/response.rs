@@ -1,7 +1,66 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+use crate::{KvError, KvStats, VerifyReport};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Ok(Option<String>),
-    Err(String),
+    Err(ResponseError),
+    Bool(bool),
+    Keys(Vec<String>),
+    /// Values of `Request::MGet` in order of keys, `None` for absent keys.
+    Values(Vec<Option<String>>),
+    Stats(KvStats),
+    Verify(VerifyReport),
+    Text(String),
+    Pong,
+    Batch(Vec<Response>),
+    /// The key to remove is absent, unlike other errors it is expected by clients.
+    NotFound,
+    /// Response to `Request::Tagged` with the same `id`.
+    Tagged { id: u64, response: Box<Response> },
+}
+
+impl Response {
+    /// Tag the response by `id` of the request, responses of untagged requests are untagged.
+    pub fn tag(self, id: Option<u64>) -> Response {
+        match id {
+            Some(id) => Response::Tagged { id, response: Box::new(self) },
+            None => self,
+        }
+    }
+}
+
+/// Error of the request processed by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ResponseError {
+    KeyNotFound,
+    /// The request can't be applied, e.g. the batch is nested.
+    InvalidRequest(String),
+    /// The token of the admin request doesn't match the secret of the server.
+    Unauthorized,
+    /// Any other error described by its message.
+    Other(String),
+}
+
+impl From<KvError> for ResponseError {
+    fn from(err: KvError) -> ResponseError {
+        match err {
+            KvError::KeyNotFound => ResponseError::KeyNotFound,
+            err => ResponseError::Other(format!("{}", err)),
+        }
+    }
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResponseError::KeyNotFound => write!(f, "{}", KvError::KeyNotFound),
+            ResponseError::InvalidRequest(what) => write!(f, "Invalid request: {}", what),
+            ResponseError::Unauthorized => write!(f, "Unauthorized"),
+            ResponseError::Other(what) => write!(f, "{}", what),
+        }
+    }
 }
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Address of the server: TCP socket address or the path of UNIX domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Tcp(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<PathBuf> for Address {
+    fn from(path: PathBuf) -> Address {
+        Address::Unix(path)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Address::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Connection over TCP or UNIX domain socket.
+/// It is read and written like `TcpStream`, also by shared references.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connect to the server at `addr`.
+    /// `timeout` limits connecting over TCP, connecting to the local socket never blocks long.
+    pub fn connect(addr: &Address, timeout: Option<Duration>) -> io::Result<Stream> {
+        match addr {
+            Address::Tcp(addr) => match timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout).map(Stream::Tcp),
+                None => TcpStream::connect(addr).map(Stream::Tcp),
+            },
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Shut down the reading, writing or both halves of the connection, also for its clones.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Get the printable address of the remote side, clients of UNIX domain sockets are unnamed.
+    pub fn peer_addr(&self) -> io::Result<String> {
+        match self {
+            Stream::Tcp(stream) => Ok(stream.peer_addr()?.to_string()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(format!("{:?}", stream.peer_addr()?)),
+        }
+    }
+
+    /// Get the printable address of the local side.
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Stream::Tcp(stream) => Ok(stream.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(format!("{:?}", stream.local_addr()?)),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// Listener of TCP or UNIX domain socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind the listener to `addr`.
+    /// The stale UNIX domain socket left by the previous server is removed,
+    /// i.e. the socket refusing connections. The socket of the running server is kept.
+    /// # Error
+    /// It returns the error of kind `io::ErrorKind::AddrInUse` if the socket accepts connections.
+    pub fn bind(addr: &Address) -> io::Result<Listener> {
+        match addr {
+            Address::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        match UnixStream::connect(path) {
+                            Ok(_) => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::AddrInUse,
+                                    format!("Socket {:?} is used by the running server", path),
+                                ))
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+                            // Binding fails on the kept file
+                            Err(_) => {}
+                        }
+                    }
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Get the address the listener is bound to, e.g. to find out the port chosen by the OS.
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| Address::Unix(path.to_owned()))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Unnamed UNIX domain socket")),
+        }
+    }
+
+    /// Accept the next connection.
+    /// Accepted streams are blocking even if the listener is not.
+    pub fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Stream::Unix(listener.accept()?.0),
+        };
+        match &stream {
+            Stream::Tcp(stream) => stream.set_nonblocking(false)?,
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(false)?,
+        }
+        Ok(stream)
+    }
+}
@@ -0,0 +1,340 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::engine::{AsyncKvsEngine, KvError};
+use crate::metrics::Metrics;
+use crate::protocol::{
+    decode_frame, decode_frame_len, encode_frame, read_chunks, ProtocolError, Request, Response,
+    ResponseError, FRAME_PREFIX_LEN,
+};
+
+/// Size of the buffer for reading from the socket.
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Connection of the client to `AsyncServer`.
+/// Incoming bytes are buffered until the next frame is complete.
+struct Connection {
+    /// Number of the connection, log lines of the connection are prefixed by it.
+    id: u64,
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    /// Read the next request.
+    /// Returns `None` if the client closed the connection between requests.
+    async fn read_request(&mut self) -> Result<Option<Request>, ProtocolError> {
+        if self.buffer.is_empty() && self.fill().await? == 0 {
+            return Ok(None);
+        }
+        let prefix = self.read_exact(FRAME_PREFIX_LEN).await?;
+        let len = decode_frame_len([prefix[0], prefix[1], prefix[2], prefix[3]])?;
+        let payload = self.read_exact(len).await?;
+        decode_frame(&payload).map(Some)
+    }
+
+    /// Read exactly `len` bytes following the request.
+    async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, ProtocolError> {
+        while self.buffer.len() < len {
+            if self.fill().await? == 0 {
+                return Err(ProtocolError::Disconnected);
+            }
+        }
+        Ok(self.buffer.drain(..len).collect())
+    }
+
+    /// Read chunks of the streamed value of `len` bytes.
+    /// Chunks are collected asynchronously and then checked by `read_chunks`.
+    async fn read_chunks(&mut self, len: u64) -> Result<Vec<u8>, ProtocolError> {
+        let mut chunks = Vec::new();
+        let mut received = 0;
+        loop {
+            let prefix = self.read_exact(4).await?;
+            let chunk_len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as u64;
+            chunks.extend_from_slice(&prefix);
+            if chunk_len == 0 {
+                break;
+            }
+            received += chunk_len;
+            if received > len {
+                break; // Rejected by `read_chunks`
+            }
+            chunks.extend(self.read_exact(chunk_len as usize).await?);
+        }
+        read_chunks(chunks.as_slice(), len)
+    }
+
+    /// Read chunks of the rejected value and discard them without buffering the whole value.
+    async fn skip_chunks(&mut self) -> Result<(), ProtocolError> {
+        loop {
+            let prefix = self.read_exact(4).await?;
+            let mut remaining = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+            if remaining == 0 {
+                return Ok(());
+            }
+            while remaining > 0 {
+                if self.buffer.is_empty() && self.fill().await? == 0 {
+                    return Err(ProtocolError::Disconnected);
+                }
+                let skipped = std::cmp::min(remaining, self.buffer.len());
+                self.buffer.drain(..skipped);
+                remaining -= skipped;
+            }
+        }
+    }
+
+    /// Read available bytes from the socket to the buffer.
+    /// Returns the number of read bytes, 0 if the client closed the connection.
+    async fn fill(&mut self) -> Result<usize, ProtocolError> {
+        let mut read_buffer = [0; READ_BUFFER_SIZE];
+        let read = self.stream.read(&mut read_buffer).await?;
+        self.buffer.extend_from_slice(&read_buffer[..read]);
+        Ok(read)
+    }
+
+    async fn send(&mut self, response: &Response) -> Result<(), ProtocolError> {
+        debug!("[conn {}] Send response: {:?}", self.id, response);
+        self.stream.write_all(&encode_frame(response)?).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+async fn handle_connection(
+    id: u64,
+    stream: TcpStream,
+    storage: impl AsyncKvsEngine,
+    metrics: Arc<Metrics>,
+) -> Result<(), ProtocolError> {
+    let remote_addr = stream.peer_addr()?.to_string();
+    debug!("[conn {}] Accept client {}", id, remote_addr);
+
+    let mut connection = Connection {
+        id,
+        stream,
+        buffer: Vec::new(),
+    };
+    // Requests are served one by one until the client closes the connection
+    while let Some(request) = connection.read_request().await? {
+        // The id of the tagged request is echoed in its response
+        let (request_id, request) = request.untag();
+        let response = handle_request(request, &storage, &metrics, &mut connection).await?;
+        connection.send(&response.tag(request_id)).await?;
+    }
+    debug!("[conn {}] Client {} closed the connection", id, remote_addr);
+    Ok(())
+}
+
+async fn handle_request(
+    incoming_request: Request,
+    storage: &impl AsyncKvsEngine,
+    metrics: &Metrics,
+    connection: &mut Connection,
+) -> Result<Response, ProtocolError> {
+    let id = connection.id;
+    debug!("[conn {}] Get request", id);
+    // Other requests are counted by `apply_request`, batches are counted by their items
+    if let Request::SetStream { .. } = incoming_request {
+        metrics.record_request(&incoming_request);
+    }
+    let response = match incoming_request {
+        Request::Hello { compression } => {
+            debug!("[conn {}] Hello, compression: {}", id, compression);
+            // Compression is not supported, frames are never compressed
+            Response::Bool(false)
+        }
+        Request::SetStream { key, len } => {
+            debug!("[conn {}] Set key: {}, streamed value of {} bytes", id, key, len);
+            if let Err(e) = storage.check_size(key.len(), len) {
+                // The value is not buffered, the connection stays usable for the next requests
+                connection.skip_chunks().await?;
+                into_response(id, Err(e))
+            } else {
+                // The value is stored only if it is received completely
+                let value = String::from_utf8(connection.read_chunks(len).await?)
+                    .map_err(|e| ProtocolError::UnknownError(e.to_string()))?;
+                into_response(id, storage.set(key, value).await.map(|_| None))
+            }
+        }
+        Request::Batch(requests) => {
+            debug!("[conn {}] Batch of {} requests", id, requests.len());
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.push(apply_request(id, request, storage, metrics).await);
+            }
+            Response::Batch(responses)
+        }
+        Request::Admin { op, .. } => {
+            // The async server has no admin secret, so admin requests are never authorized
+            warn!("[conn {}] Unauthorized admin operation: {:?}", id, op);
+            Response::Err(ResponseError::Unauthorized)
+        }
+        request => apply_request(id, request, storage, metrics).await,
+    };
+    metrics.record_response(&response);
+    Ok(response)
+}
+
+/// Apply the request to the engine.
+/// Requests followed by data, handshakes and batches are handled by `handle_request`,
+/// they are rejected here as items of a batch like nested tagged requests.
+async fn apply_request(id: u64, request: Request, storage: &impl AsyncKvsEngine, metrics: &Metrics) -> Response {
+    metrics.record_request(&request);
+    match request {
+        Request::Get { key } => {
+            debug!("[conn {}] Get key: {}", id, key);
+            into_response(id, storage.get(key).await)
+        }
+        Request::MGet(keys) => {
+            debug!("[conn {}] Get {} keys", id, keys.len());
+            match storage.get_batch(keys).await {
+                Ok(values) => Response::Values(values),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Exists { key } => {
+            debug!("[conn {}] Check key: {}", id, key);
+            match storage.contains_key(key).await {
+                Ok(exists) => Response::Bool(exists),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Set { key, value } => {
+            debug!("[conn {}] Set key: {}, value: {}", id, key, value);
+            into_response(id, storage.set(key, value).await.map(|_| None))
+        }
+        Request::SetNx { key, value } => {
+            debug!("[conn {}] Set key if absent: {}, value: {}", id, key, value);
+            match storage.set_if_absent(key, value).await {
+                Ok(is_set) => Response::Bool(is_set),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Incr { key, delta } => {
+            debug!("[conn {}] Increment key: {}, delta: {}", id, key, delta);
+            into_response(id, storage.increment(key, delta).await.map(|value| Some(value.to_string())))
+        }
+        Request::Scan { prefix, limit } => {
+            debug!("[conn {}] Scan keys, prefix: {:?}, limit: {:?}", id, prefix, limit);
+            match storage.scan_keys(prefix.unwrap_or_default(), limit).await {
+                Ok(keys) => Response::Keys(keys),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Compact => {
+            debug!("[conn {}] Compact storage", id);
+            into_response(id, storage.compact().await.map(|_| None))
+        }
+        Request::Ping => {
+            debug!("[conn {}] Ping", id);
+            Response::Pong
+        }
+        Request::Stats => {
+            debug!("[conn {}] Get stats", id);
+            match storage.stats().await {
+                Ok(stats) => Response::Stats(stats),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Verify => {
+            debug!("[conn {}] Verify storage", id);
+            match storage.verify().await {
+                Ok(report) => Response::Verify(report),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Metrics => {
+            debug!("[conn {}] Get metrics", id);
+            match storage.stats().await {
+                Ok(stats) => {
+                    metrics.update_stats(&stats);
+                    Response::Text(metrics.render_prometheus())
+                }
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Rm { key } => {
+            debug!("[conn {}] Remove key: {}", id, key);
+            match storage.remove(key).await {
+                Err(KvError::KeyNotFound) => {
+                    debug!("[conn {}] {}", id, KvError::KeyNotFound);
+                    Response::NotFound
+                }
+                result => into_response(id, result.map(|_| None)),
+            }
+        }
+        Request::SetStream { .. } => {
+            Response::Err(ResponseError::InvalidRequest("streamed value in batch".to_owned()))
+        }
+        Request::Hello { .. } => Response::Err(ResponseError::InvalidRequest("hello in batch".to_owned())),
+        Request::Batch(_) => Response::Err(ResponseError::InvalidRequest("nested batch".to_owned())),
+        Request::Tagged { .. } => Response::Err(ResponseError::InvalidRequest("nested tagged request".to_owned())),
+        Request::Admin { .. } => Response::Err(ResponseError::InvalidRequest("admin operation in batch".to_owned())),
+    }
+}
+
+fn into_response(id: u64, result: Result<Option<String>, KvError>) -> Response {
+    match result {
+        Ok(value) => Response::Ok(value),
+        Err(error) => {
+            warn!("[conn {}] KvStore error: {}", id, error);
+            Response::Err(error.into())
+        }
+    }
+}
+
+/// `AsyncServer` serves the same protocol as `Server` on the tokio runtime.
+/// Every connection is handled by a separate task,
+/// operations of the engine are run on the blocking thread pool of tokio.
+///
+/// # Example:
+/// ```rust,no_run
+/// use kvs::{AsyncServer, KvStore, KvsEngine};
+/// let engine = KvStore::open(std::env::current_dir().unwrap()).unwrap();
+/// let server = AsyncServer::new("127.0.0.1:4000".parse().unwrap(), engine);
+/// tokio::runtime::Runtime::new().unwrap().block_on(server.run()).unwrap();
+/// ```
+pub struct AsyncServer<E: AsyncKvsEngine> {
+    addr: SocketAddr,
+    engine: E,
+    metrics: Arc<Metrics>,
+}
+
+impl<E: AsyncKvsEngine> AsyncServer<E> {
+    pub fn new(addr: SocketAddr, engine: E) -> Self {
+        AsyncServer {
+            addr,
+            engine,
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    /// Get metrics of the server, they are updated while the server is running.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Accept connections until the future is dropped.
+    pub async fn run(&self) -> Result<(), ProtocolError> {
+        info!("Async server started on {}", self.addr);
+        let tcp_listener = TcpListener::bind(self.addr).await?;
+        // Connections are numbered in the order of accepting
+        let mut connection_id = 0;
+        loop {
+            let (stream, _) = tcp_listener.accept().await?;
+            connection_id += 1;
+            let id = connection_id;
+            let storage = self.engine.clone();
+            let metrics = self.metrics();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(id, stream, storage, metrics).await {
+                    error!("[conn {}] Error while handling connection: {}", id, e);
+                }
+            });
+        }
+    }
+}
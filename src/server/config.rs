@@ -0,0 +1,119 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::engine::{DurabilityMode, EngineKind, KvError, Result};
+use crate::thread_pool::ThreadPoolKind;
+
+/// Address to listen on if no address is configured.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+/// Number of threads serving clients if it is not configured.
+const DEFAULT_THREADS: u32 = 8;
+
+/// Configuration of the server, it is read from a TOML file and overridden by flags of `kvs-server`.
+/// Unset settings are `None`, accessors resolve them to defaults.
+///
+/// # Example of the file:
+/// ```toml
+/// addr = ["127.0.0.1:4000", "[::1]:4000"]
+/// engine = "sled"
+/// thread_pool = "queue"
+/// threads = 4
+/// durability = "every-100"
+/// records_limit = 1000
+/// max_connections = 256
+/// admin_token_file = "/etc/kvs/admin_token"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on.
+    pub addr: Option<Vec<SocketAddr>>,
+
+    /// Storage engine, it must match the engine file of the storage directory.
+    pub engine: Option<EngineKind>,
+
+    /// Implementation of the thread pool serving clients.
+    pub thread_pool: Option<ThreadPoolKind>,
+
+    /// Number of threads serving clients.
+    pub threads: Option<u32>,
+
+    /// When records are synced to disk, `None` keeps the default mode of the engine.
+    pub durability: Option<DurabilityMode>,
+
+    /// Max number of unused records of `KvStore`, see `KvStoreConfig::records_limit`.
+    pub records_limit: Option<u64>,
+
+    /// Max number of connections served at once, see `Server::with_max_connections`.
+    /// `None` means connections are unlimited.
+    pub max_connections: Option<usize>,
+
+    /// File with the secret authorizing admin requests, see `Server::with_admin_token`.
+    /// The secret is kept in the file, so it never appears in the configuration or in logs.
+    /// `None` means admin requests are rejected.
+    pub admin_token_file: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// Read the configuration from the TOML file.
+    pub fn from_file(path: &Path) -> Result<ServerConfig> {
+        ServerConfig::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the configuration from TOML, unknown settings are rejected.
+    pub fn from_toml(content: &str) -> Result<ServerConfig> {
+        toml::from_str(content).map_err(|e| KvError::InvalidConfig(e.to_string()))
+    }
+
+    /// Override settings by the ones which are set in `overrides`.
+    pub fn overridden_by(self, overrides: ServerConfig) -> ServerConfig {
+        ServerConfig {
+            addr: overrides.addr.or(self.addr),
+            engine: overrides.engine.or(self.engine),
+            thread_pool: overrides.thread_pool.or(self.thread_pool),
+            threads: overrides.threads.or(self.threads),
+            durability: overrides.durability.or(self.durability),
+            records_limit: overrides.records_limit.or(self.records_limit),
+            max_connections: overrides.max_connections.or(self.max_connections),
+            admin_token_file: overrides.admin_token_file.or(self.admin_token_file),
+        }
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        match &self.addr {
+            Some(addrs) => addrs.clone(),
+            None => vec![DEFAULT_ADDRESS.parse().unwrap()],
+        }
+    }
+
+    pub fn engine(&self) -> EngineKind {
+        self.engine.unwrap_or(EngineKind::Kvs)
+    }
+
+    pub fn thread_pool(&self) -> ThreadPoolKind {
+        self.thread_pool.unwrap_or(ThreadPoolKind::Rayon)
+    }
+
+    pub fn threads(&self) -> u32 {
+        self.threads.unwrap_or(DEFAULT_THREADS)
+    }
+
+    /// Read the secret authorizing admin requests from `admin_token_file`, surrounding whitespace is trimmed.
+    /// # Error
+    /// It returns `KvError::InvalidConfig` if the secret is empty.
+    pub fn admin_token(&self) -> Result<Option<String>> {
+        let path = match &self.admin_token_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let admin_token = fs::read_to_string(path)?.trim().to_owned();
+        if admin_token.is_empty() {
+            return Err(KvError::InvalidConfig(format!("Admin token file {} is empty", path.display())));
+        }
+        Ok(Some(admin_token))
+    }
+}
@@ -1,3 +1,9 @@
-pub use server::Server;
+#[cfg(feature = "async")]
+pub use async_server::AsyncServer;
+pub use config::ServerConfig;
+pub use server::{Server, ShutdownHandle};
 
+#[cfg(feature = "async")]
+mod async_server;
+mod config;
 mod server;
@@ -1,116 +1,554 @@
 use std::io;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use log::{debug, info, warn};
-use serde::de::Deserialize;
-use serde_json;
+use log::{debug, error, info, warn};
 
 use crate::engine::KvsEngine;
-use crate::protocol::{ProtocolError, Request, Response};
+use crate::protocol::{
+    read_chunks, read_frame, skip_chunks, write_frame, write_frame_with, Address, AdminOp, Listener, ProtocolError, Request,
+    Response, ResponseError, Stream,
+};
+use crate::metrics::Metrics;
 use crate::KvError;
-use crate::thread_pool::{NaiveThreadPool, ThreadPool, QueueThreadPool};
+use crate::thread_pool::ThreadPool;
+use crate::utils::WaitGroup;
 
-fn handle_connection(stream: &TcpStream, storage: impl KvsEngine) -> Result<(), ProtocolError> {
-    let remote_addr = stream.peer_addr()?.to_string();
-    debug!("Accept client {}", remote_addr);
+/// Interval of checking if the server is stopped while waiting for the next request.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    let tcp_reader = BufReader::new(stream);
-    let tcp_writer = BufWriter::new(stream);
-    let mut deserializer = serde_json::Deserializer::from_reader(tcp_reader);
-    let incoming_request = Request::deserialize(&mut deserializer)?;
+/// Interval of checking if a slot of the connection is released while the server is at the limit of connections.
+const SLOT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-    debug!("Get request");
-    match incoming_request {
+/// Interval of polling nonblocking listeners after all of them had no pending connections.
+/// Idle server doesn't spin the accepting thread, while the shutdown is noticed quickly.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Serve requests of the connection.
+/// Log lines of the connection are prefixed by its `id`, so they are traceable among lines of other connections.
+fn handle_connection(
+    id: u64,
+    stream: &Stream,
+    storage: impl KvsEngine,
+    metrics: &Metrics,
+    shutdown: &ShutdownHandle,
+    admin_token: Option<&str>,
+) -> Result<(), ProtocolError> {
+    let remote_addr = stream.peer_addr()?;
+    debug!("[conn {}] Accept client {}", id, remote_addr);
+
+    let mut tcp_reader = BufReader::new(stream);
+    let mut tcp_writer = BufWriter::new(stream);
+    // Responses are compressed once it is negotiated by `Request::Hello`
+    let mut compression = false;
+    // Requests are served one by one until the client closes the connection or the server is stopped
+    loop {
+        stream.set_read_timeout(Some(IDLE_POLL_INTERVAL))?;
+        let is_closed = loop {
+            if shutdown.is_stopped() {
+                debug!("[conn {}] Close connection of client {} due to shutdown", id, remote_addr);
+                return Ok(());
+            }
+            match tcp_reader.fill_buf() {
+                Ok(buf) => break buf.is_empty(),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        stream.set_read_timeout(None)?;
+        if is_closed {
+            debug!("[conn {}] Client {} closed the connection", id, remote_addr);
+            return Ok(());
+        }
+
+        let incoming_request: Request = match read_frame(&mut tcp_reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(ProtocolError::Disconnected) => {
+                warn!("[conn {}] Client {} disconnected in the middle of request", id, remote_addr);
+                return Err(ProtocolError::Disconnected);
+            }
+            Err(e) => return Err(e),
+        };
+        // The id of the tagged request is echoed in its response
+        let (request_id, incoming_request) = incoming_request.untag();
+        handle_request(
+            id,
+            request_id,
+            incoming_request,
+            &storage,
+            metrics,
+            admin_token,
+            &mut compression,
+            &mut tcp_reader,
+            &mut tcp_writer,
+        )?;
+        tcp_writer.flush()?;
+    }
+}
+
+fn handle_request(
+    id: u64,
+    request_id: Option<u64>,
+    incoming_request: Request,
+    storage: &impl KvsEngine,
+    metrics: &Metrics,
+    admin_token: Option<&str>,
+    compression: &mut bool,
+    tcp_reader: &mut BufReader<&Stream>,
+    tcp_writer: &mut BufWriter<&Stream>,
+) -> Result<(), ProtocolError> {
+    debug!("[conn {}] Get request", id);
+    // Other requests are counted by `apply_request`, batches are counted by their items
+    if let Request::SetStream { .. } = incoming_request {
+        metrics.record_request(&incoming_request);
+    }
+    let response = match incoming_request {
+        Request::Hello { compression: requested } => {
+            debug!("[conn {}] Hello, compression: {}", id, requested);
+            // The answer is uncompressed, frames following it are compressed if requested
+            write_frame(&mut *tcp_writer, &Response::Bool(requested).tag(request_id))?;
+            *compression = requested;
+            return Ok(());
+        }
+        Request::SetStream { key, len } => {
+            debug!("[conn {}] Set key: {}, streamed value of {} bytes", id, key, len);
+            if let Err(e) = storage.check_size(key.len(), len) {
+                // The value is not buffered, the connection stays usable for the next requests
+                skip_chunks(&mut *tcp_reader)?;
+                into_response(id, Err(e))
+            } else {
+                // The value is stored only if it is received completely
+                let value = String::from_utf8(read_chunks(&mut *tcp_reader, len)?)
+                    .map_err(|e| ProtocolError::UnknownError(e.to_string()))?;
+                into_response(id, storage.set(key, value).map(|_| None))
+            }
+        }
+        Request::Batch(requests) => {
+            debug!("[conn {}] Batch of {} requests", id, requests.len());
+            let responses = requests
+                .into_iter()
+                .map(|request| apply_request(id, request, storage, metrics, admin_token))
+                .collect();
+            Response::Batch(responses)
+        }
+        Request::Admin { token, op } => {
+            if is_authorized(admin_token, &token) {
+                apply_admin(id, op, storage)
+            } else {
+                warn!("[conn {}] Unauthorized admin operation: {:?}", id, op);
+                Response::Err(ResponseError::Unauthorized)
+            }
+        }
+        request => apply_request(id, request, storage, metrics, admin_token),
+    };
+    metrics.record_response(&response);
+    debug!("[conn {}] Send response: {:?}", id, response);
+    write_frame_with(&mut *tcp_writer, &response.tag(request_id), *compression)
+}
+
+/// Apply the request to the engine.
+/// Requests followed by data, handshakes and batches are handled by `handle_request`,
+/// they are rejected here as items of a batch like nested tagged requests.
+/// Compaction and stats are admin operations if the server has `admin_token`,
+/// so their plain requests are rejected then, and metrics don't include stats of the storage.
+fn apply_request(
+    id: u64,
+    request: Request,
+    storage: &impl KvsEngine,
+    metrics: &Metrics,
+    admin_token: Option<&str>,
+) -> Response {
+    metrics.record_request(&request);
+    match request {
+        Request::Compact | Request::Stats if admin_token.is_some() => {
+            warn!("[conn {}] Unauthorized {:?}, it is the admin operation", id, request);
+            Response::Err(ResponseError::Unauthorized)
+        }
         Request::Get { key } => {
-            debug!("Get key: {}", key);
-            match storage.get(key) {
-                Ok(value) => {
-                    if value.is_none() {
-                        debug!("{}", KvError::KeyNotFound);
-                    }
-                    send_ok(tcp_writer, value)?;
-                }
-                Err(e) => send_error(tcp_writer, e)?,
+            debug!("[conn {}] Get key: {}", id, key);
+            let value = storage.get(key);
+            if let Ok(None) = value {
+                debug!("[conn {}] {}", id, KvError::KeyNotFound);
+            }
+            into_response(id, value)
+        }
+        Request::MGet(keys) => {
+            debug!("[conn {}] Get {} keys", id, keys.len());
+            match storage.get_batch(keys) {
+                Ok(values) => Response::Values(values),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Exists { key } => {
+            debug!("[conn {}] Check key: {}", id, key);
+            match storage.contains_key(&key) {
+                Ok(exists) => Response::Bool(exists),
+                Err(e) => into_response(id, Err(e)),
             }
         }
         Request::Set { key, value } => {
-            debug!("Set key: {}, value: {}", key, value);
-            match storage.set(key, value) {
-                Ok(_) => send_ok(tcp_writer, None)?,
-                Err(e) => send_error(tcp_writer, e)?,
+            debug!("[conn {}] Set key: {}, value: {}", id, key, value);
+            into_response(id, storage.set(key, value).map(|_| None))
+        }
+        Request::SetNx { key, value } => {
+            debug!("[conn {}] Set key if absent: {}, value: {}", id, key, value);
+            match storage.set_if_absent(key, value) {
+                Ok(is_set) => Response::Bool(is_set),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Incr { key, delta } => {
+            debug!("[conn {}] Increment key: {}, delta: {}", id, key, delta);
+            into_response(id, storage.increment(key, delta).map(|value| Some(value.to_string())))
+        }
+        Request::Scan { prefix, limit } => {
+            debug!("[conn {}] Scan keys, prefix: {:?}, limit: {:?}", id, prefix, limit);
+            match storage.scan_keys(prefix.as_deref().unwrap_or(""), limit) {
+                Ok(keys) => Response::Keys(keys),
+                Err(e) => into_response(id, Err(e)),
             }
         }
+        Request::Compact => {
+            debug!("[conn {}] Compact storage", id);
+            into_response(id, storage.compact().map(|_| None))
+        }
+        Request::Ping => {
+            debug!("[conn {}] Ping", id);
+            Response::Pong
+        }
+        Request::Stats => {
+            debug!("[conn {}] Get stats", id);
+            Response::Stats(storage.stats())
+        }
+        Request::Verify => {
+            debug!("[conn {}] Verify storage", id);
+            match storage.verify() {
+                Ok(report) => Response::Verify(report),
+                Err(e) => into_response(id, Err(e)),
+            }
+        }
+        Request::Metrics if admin_token.is_some() => {
+            // Stats of the storage are admin data, so only counters of requests are exposed
+            debug!("[conn {}] Get request metrics", id);
+            Response::Text(metrics.render_requests_prometheus())
+        }
+        Request::Metrics => {
+            debug!("[conn {}] Get metrics", id);
+            metrics.update_stats(&storage.stats());
+            Response::Text(metrics.render_prometheus())
+        }
         Request::Rm { key } => {
-            debug!("Remove key: {}", key);
+            debug!("[conn {}] Remove key: {}", id, key);
             match storage.remove(key) {
-                Ok(_) => send_ok(tcp_writer, None)?,
-                Err(e) => send_error(tcp_writer, e)?,
+                Err(KvError::KeyNotFound) => {
+                    debug!("[conn {}] {}", id, KvError::KeyNotFound);
+                    Response::NotFound
+                }
+                result => into_response(id, result.map(|_| None)),
             }
         }
+        Request::SetStream { .. } => {
+            Response::Err(ResponseError::InvalidRequest("streamed value in batch".to_owned()))
+        }
+        Request::Hello { .. } => Response::Err(ResponseError::InvalidRequest("hello in batch".to_owned())),
+        Request::Batch(_) => Response::Err(ResponseError::InvalidRequest("nested batch".to_owned())),
+        Request::Tagged { .. } => Response::Err(ResponseError::InvalidRequest("nested tagged request".to_owned())),
+        Request::Admin { .. } => Response::Err(ResponseError::InvalidRequest("admin operation in batch".to_owned())),
     }
-    Ok(())
 }
 
-fn send_error<W: Write>(writer: W, error: KvError) -> Result<(), ProtocolError> {
-    let error_msg = format!("{}", error);
-    warn!("KvStore error: {}", error_msg);
-    let response = Response::Err(error_msg);
-    debug!("Send response: {:?}", response);
-    Ok(serde_json::to_writer(writer, &response)?)
+/// Check `token` of the admin request against the secret of the server.
+/// Admin requests are never authorized if the server has no secret.
+/// Tokens are compared in constant time, so the secret can't be guessed by timings of responses.
+fn is_authorized(admin_token: Option<&str>, token: &str) -> bool {
+    match admin_token {
+        Some(admin_token) if admin_token.len() == token.len() => admin_token
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (expected, actual)| diff | (expected ^ actual))
+            == 0,
+        _ => false,
+    }
+}
+
+/// Apply the authorized admin operation to the engine.
+fn apply_admin(id: u64, op: AdminOp, storage: &impl KvsEngine) -> Response {
+    debug!("[conn {}] Admin operation: {:?}", id, op);
+    match op {
+        AdminOp::Compact => into_response(id, storage.compact().map(|_| None)),
+        AdminOp::Rotate => into_response(id, storage.rotate().map(|_| None)),
+        AdminOp::Stats => Response::Stats(storage.stats()),
+        AdminOp::Clear => into_response(id, storage.clear().map(|_| None)),
+    }
+}
+
+fn into_response(id: u64, result: Result<Option<String>, KvError>) -> Response {
+    match result {
+        Ok(value) => Response::Ok(value),
+        Err(error) => {
+            warn!("[conn {}] KvStore error: {}", id, error);
+            Response::Err(error.into())
+        }
+    }
 }
 
-fn send_ok<W: Write>(writer: W, value: Option<String>) -> Result<(), ProtocolError> {
-    let response = Response::Ok(value);
-    debug!("Send response: {:?}", response);
-    Ok(serde_json::to_writer(writer, &response)?)
+/// Handle for stopping the `Server` from other threads, e.g. from the SIGINT handler.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stopped: Arc<AtomicBool>,
+    tasks: Arc<WaitGroup>,
+    /// Served connections, unlike `tasks` accepting is not counted.
+    connections: Arc<WaitGroup>,
+    /// Number of served connections, they are limited by `Server::with_max_connections`.
+    open_connections: Arc<AtomicUsize>,
+}
+
+impl ShutdownHandle {
+    fn new() -> Self {
+        ShutdownHandle {
+            stopped: Arc::new(AtomicBool::new(false)),
+            tasks: Arc::new(WaitGroup::new()),
+            connections: Arc::new(WaitGroup::new()),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Stop accepting connections and block until in-flight requests are served.
+    /// Idle connections are closed.
+    pub fn shutdown(&self) {
+        debug!("Shutdown server");
+        self.stopped.store(true, Ordering::SeqCst);
+        self.tasks.wait();
+        debug!("Server is stopped");
+    }
+
+    /// Block until all connections accepted so far are closed, the server keeps accepting new ones.
+    /// Connections accepted while waiting are waited too.
+    pub fn quiesce(&self) {
+        debug!("Wait for connections");
+        self.connections.wait();
+        debug!("No connections are served");
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Start the task which is waited by `shutdown`, it is finished by dropping.
+    fn task(&self) -> WaitGroup {
+        WaitGroup::clone(&self.tasks)
+    }
+
+    /// Check if fewer than `max_connections` connections are served, `None` means unlimited.
+    fn has_free_slot(&self, max_connections: Option<usize>) -> bool {
+        max_connections.map_or(true, |max| self.open_connections.load(Ordering::SeqCst) < max)
+    }
+
+    /// Start serving the connection which is waited by `quiesce`, it is finished by dropping the slot.
+    fn connection(&self) -> ConnectionSlot {
+        self.open_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionSlot {
+            open_connections: Arc::clone(&self.open_connections),
+            _connection: WaitGroup::clone(&self.connections),
+        }
+    }
+}
+
+/// Slot of the served connection, it is released by dropping.
+struct ConnectionSlot {
+    open_connections: Arc<AtomicUsize>,
+    _connection: WaitGroup,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 pub struct Server<E: KvsEngine, P: ThreadPool> {
-    addr: SocketAddr,
+    addrs: Vec<Address>,
+    listeners: Vec<Listener>,
     thread_pool: P,
     engine: E,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownHandle,
+    max_connections: Option<usize>,
+    /// Secret authorizing admin requests, they are rejected if it is not set.
+    admin_token: Option<String>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
-    pub fn new(addr: SocketAddr, thread_pool: P, engine: E) -> Self {
-        Server { addr, thread_pool, engine }
+    /// Create the server listening on TCP socket address or on UNIX domain socket.
+    pub fn new(addr: impl Into<Address>, thread_pool: P, engine: E) -> Self {
+        Server::with_addrs(vec![addr.into()], thread_pool, engine)
+    }
+
+    /// Create the server listening on all `addrs`, e.g. on IPv4 and IPv6 ones.
+    /// Connections accepted by every listener are served by the same thread pool and engine.
+    pub fn with_addrs(addrs: Vec<Address>, thread_pool: P, engine: E) -> Self {
+        Server {
+            addrs,
+            listeners: Vec::new(),
+            thread_pool,
+            engine,
+            metrics: Arc::new(Metrics::new()),
+            shutdown: ShutdownHandle::new(),
+            max_connections: None,
+            admin_token: None,
+        }
+    }
+
+    /// Serve at most `max_connections` connections at once, e.g. so a flood of connections doesn't exhaust
+    /// file descriptors. Further connections are not accepted until served ones are closed,
+    /// they wait in the backlog of the listener meanwhile.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Authorize admin requests carrying `admin_token`, e.g. compaction or clearing triggered remotely.
+    /// Plain compaction and stats requests are rejected then, so ordinary clients can't trigger them.
+    /// Without the token admin requests are rejected.
+    pub fn with_admin_token(mut self, admin_token: impl Into<String>) -> Self {
+        self.admin_token = Some(admin_token.into());
+        self
     }
 
+    /// Get the handle for stopping the server from other threads.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Get metrics of the server, they are updated while the server is running.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Stop accepting connections and block until in-flight requests are served.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown()
+    }
+
+    /// Block until all connections accepted so far are closed, see `ShutdownHandle::quiesce`.
+    pub fn quiesce(&self) {
+        self.shutdown.quiesce()
+    }
+
+    /// Bind listeners to addresses of the server, otherwise they are bound by `run`.
+    pub fn bind(&mut self) -> Result<(), ProtocolError> {
+        if self.listeners.is_empty() {
+            self.listeners = self.bind_listeners()?;
+        }
+        Ok(())
+    }
+
+    /// Get addresses the server is bound to, they differ from requested ones by ports chosen by the OS.
+    /// Empty if the server is not bound yet.
+    pub fn local_addrs(&self) -> Result<Vec<Address>, ProtocolError> {
+        let addrs = self
+            .listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<_>>()?;
+        Ok(addrs)
+    }
+
+    fn bind_listeners(&self) -> Result<Vec<Listener>, ProtocolError> {
+        self.addrs
+            .iter()
+            .map(|addr| -> Result<Listener, ProtocolError> {
+                info!("Server started on {}", addr);
+                let listener = Listener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Ok(listener)
+            })
+            .collect()
+    }
+
+    /// Accept connections until the server is stopped by `shutdown`.
+    /// Listeners are polled in turn, so all of them are stopped together.
+    /// Returns after in-flight requests are served, so the process may exit right after it
+    /// even if `shutdown` is called on another thread, e.g. by the SIGINT handler.
+    /// Socket files of UNIX domain sockets are removed on return.
     pub fn run(&self) -> Result<(), ProtocolError> {
-        //flag for the interruption by SIGINT
-        let interrupt = Arc::new(AtomicBool::new(false));
-        let interrupt_clone = interrupt.clone();
-        ctrlc::set_handler(move || {
-            debug!("SIGINT");
-            interrupt_clone.store(true, Ordering::SeqCst);
-        })
-        .expect("Error setting SIGINT handler");
-
-        info!("Server started on {}", self.addr);
-        let tcp_listener = TcpListener::bind(self.addr)?;
-        tcp_listener.set_nonblocking(true)?;
-
-        for stream in tcp_listener.incoming() {
-            if interrupt.load(Ordering::SeqCst) {
+        let bound_listeners;
+        let listeners = if self.listeners.is_empty() {
+            bound_listeners = self.bind_listeners()?;
+            &bound_listeners
+        } else {
+            &self.listeners
+        };
+
+        // Accepting is a task too, so `shutdown` returns after no connections are accepted
+        let accepting = self.shutdown.task();
+        // Connections are numbered in the order of accepting
+        let mut connection_id = 0;
+        // Number of listeners polled in a row without pending connections
+        let mut idle_listeners = 0;
+        for listener in listeners.iter().cycle() {
+            if self.shutdown.is_stopped() {
                 debug!("Stop server");
                 break;
             }
+            if !self.shutdown.has_free_slot(self.max_connections) {
+                thread::sleep(SLOT_POLL_INTERVAL);
+                continue;
+            }
 
-            let stream = match stream {
+            let stream = match listener.accept() {
                 Ok(s) => s,
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(_) => stream?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    idle_listeners += 1;
+                    if idle_listeners >= listeners.len() {
+                        idle_listeners = 0;
+                        thread::sleep(ACCEPT_POLL_INTERVAL);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
             };
+            idle_listeners = 0;
 
+            connection_id += 1;
+            let id = connection_id;
             let storage = self.engine.clone();
+            let metrics = self.metrics();
+            let shutdown = self.shutdown.clone();
+            let task = self.shutdown.task();
+            let connection = self.shutdown.connection();
+            let admin_token = self.admin_token.clone();
             self.thread_pool.spawn(move || {
-                handle_connection(&stream, storage); //todo error handling
+                if let Err(e) = handle_connection(id, &stream, storage, &metrics, &shutdown, admin_token.as_deref()) {
+                    error!("[conn {}] Error while handling connection: {}", id, e);
+                }
+                drop(connection);
+                drop(task);
             });
         }
+        drop(accepting);
+        // Connections are served by the thread pool asynchronously, so they are drained here
+        self.shutdown.tasks.wait();
 
+        #[cfg(unix)]
+        {
+            for addr in &self.addrs {
+                if let Address::Unix(path) = addr {
+                    // The server is already stopped, so the socket file removed by someone else is not an error
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => warn!("Unable to remove socket file {:?}: {}", path, e),
+                        Ok(()) => {}
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,33 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+
+/// Handle of the job spawned by `ThreadPool::spawn_handle` for getting its result.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T: Send + 'static> JobHandle<T> {
+    /// Wrap `job` so its result or panic is sent to the returned handle.
+    /// The wrapped job never panics, it is spawned by `ThreadPool::spawn`.
+    pub fn new<F>(job: F) -> (JobHandle<T>, impl FnOnce() + Send + 'static)
+        where
+            F: FnOnce() -> T + Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        let job = move || {
+            // The handle may be dropped already, then nobody waits for the result
+            let _ = sender.send(catch_unwind(AssertUnwindSafe(job)));
+        };
+        (JobHandle { receiver }, job)
+    }
+
+    /// Block until the job is finished.
+    /// # Error
+    /// It returns the payload of the panic if the job panicked, like `JoinHandle::join`.
+    pub fn join(self) -> thread::Result<T> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("Job is dropped without running")))
+    }
+}
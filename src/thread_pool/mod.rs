@@ -1,11 +1,18 @@
+use std::convert::TryFrom;
+use std::fmt;
 use std::panic::UnwindSafe;
+use std::str::FromStr;
 
+use serde::Deserialize;
+
+mod job_handle;
 mod naive_pool;
 mod queue_pool;
 mod rayon_pool;
 
+pub use job_handle::JobHandle;
 pub use naive_pool::NaiveThreadPool;
-pub use queue_pool::QueueThreadPool;
+pub use queue_pool::{Job, QueueThreadPool};
 pub use rayon_pool::RayonThreadPool;
 
 pub trait ThreadPool {
@@ -14,4 +21,60 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
         where
             F: FnOnce() + Send + 'static;
-}
\ No newline at end of file
+
+    /// Spawn the job and get the handle for waiting its result.
+    fn spawn_handle<F, T>(&self, job: F) -> JobHandle<T>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static
+    {
+        let (handle, job) = JobHandle::new(job);
+        self.spawn(job);
+        handle
+    }
+}
+
+/// Implementation of `ThreadPool`, it is chosen by name in the configuration of the server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ThreadPoolKind {
+    Naive,
+    Queue,
+    Rayon,
+}
+
+impl ThreadPoolKind {
+    /// Names of all thread pools, they are parsed case-insensitively.
+    pub const VARIANTS: &'static [&'static str] = &["naive", "queue", "rayon"];
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ThreadPoolKind, String> {
+        match name.to_lowercase().as_str() {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "queue" => Ok(ThreadPoolKind::Queue),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            _ => Err(format!("Unknown thread pool: {}", name)),
+        }
+    }
+}
+
+impl TryFrom<String> for ThreadPoolKind {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<ThreadPoolKind, String> {
+        name.parse()
+    }
+}
+
+impl fmt::Display for ThreadPoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThreadPoolKind::Naive => write!(f, "naive"),
+            ThreadPoolKind::Queue => write!(f, "queue"),
+            ThreadPoolKind::Rayon => write!(f, "rayon"),
+        }
+    }
+}
@@ -5,85 +5,162 @@ use std::thread::JoinHandle;
 use log::{debug, error};
 
 use crate::thread_pool::ThreadPool;
-use std::panic::{catch_unwind, UnwindSafe};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 
-type Job = Box<dyn FnOnce() + Send>;
+pub type Job = Box<dyn FnOnce() + Send>;
 
+/// Handle of the current thread of worker, it is replaced by respawning.
+type WorkerHandle = Arc<Mutex<Option<JoinHandle<()>>>>;
 
 struct Worker {
     id : u32,
-    handler: JoinHandle<()>,
+    handler: WorkerHandle,
 }
 
 impl Worker {
-    fn new(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
-        let handler = thread::spawn(move || {
+    fn new(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
+        let handler = Arc::new(Mutex::new(None));
+        Worker::spawn(id, receiver, Arc::clone(&handler));
+        Worker {id, handler}
+    }
+
+    /// Spawn the thread of worker.
+    /// If a job panics, the panic is caught and the thread is replaced by the new one,
+    /// so the pool doesn't shrink and no state of the panicked job survives in the thread.
+    /// The thread exits after the queue is disconnected and all pending jobs are taken.
+    fn spawn(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, handler: WorkerHandle) {
+        // Lock the handle until it is stored, so the replacement never is overwritten by it
+        let mut current_handler = handler.lock().unwrap();
+        let thread_handler = Arc::clone(&handler);
+        *current_handler = Some(thread::spawn(move || {
             loop {
                 let job = receiver
                     .lock()
                     .unwrap()
-                    .recv()
-                    .unwrap();
+                    .recv();
                 match job {
-                    Message::New(job) => {
+                    Ok(job) => {
                         debug!("New job for worker #{}", id);
-                        //todo replace catch_unwind to respawning thread
-                        //if let Err(e) = catch_unwind(job) {
-                        //    error!("Panic recovery at worker #{}: {:?}", id, e);
-                        //}
+                        if let Err(e) = catch_unwind(AssertUnwindSafe(job)) {
+                            error!("Panic recovery at worker #{}: {:?}", id, e);
+                            Worker::spawn(id, receiver, thread_handler);
+                            break;
+                        }
                     },
-                    Message::Shutdown => {
+                    Err(mpsc::RecvError) => {
                         debug!("Shutdown worker #{}", id);
                         break;
                     },
                 }
             }
-        });
-        Worker {id, handler}
+        }));
     }
 }
 
-enum Message {
-    New(Job),
-    Shutdown,
+/// Sender of the queue of jobs.
+enum Sender {
+    Unbounded(mpsc::Sender<Job>),
+    /// Sending blocks while the queue is full.
+    Bounded(mpsc::SyncSender<Job>),
+}
+
+impl Sender {
+    fn send(&self, job: Job) {
+        match self {
+            Sender::Unbounded(sender) => sender.send(job).unwrap(),
+            Sender::Bounded(sender) => sender.send(job).unwrap(),
+        }
+    }
 }
 
+/// Pool of workers taking jobs from the shared queue.
+/// Dropping the pool disconnects the queue, so workers finish all pending jobs before exiting.
 pub struct QueueThreadPool {
     workers : Vec<Option<Worker>>,
-    sender: mpsc::Sender<Message>,
+    /// It is `None` only while dropping.
+    sender: Option<Sender>,
 }
 
-impl ThreadPool for QueueThreadPool {
-    fn new(threads_num: u32) -> Self {
-        let (sender, receiver) = mpsc::channel::<Message>();
+impl QueueThreadPool {
+    /// Create the pool with the queue of at most `capacity` pending jobs.
+    /// `spawn` blocks while the queue is full, so the producer of jobs is slowed down
+    /// instead of accumulating unbounded work.
+    pub fn bounded(threads_num: u32, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity);
+        QueueThreadPool::with_receiver(threads_num, Sender::Bounded(sender), receiver)
+    }
+
+    fn with_receiver(threads_num: u32, sender: Sender, receiver: mpsc::Receiver<Job>) -> Self {
         let receiver = Arc::new(Mutex::new(receiver));
         let mut workers = Vec::with_capacity(threads_num as usize);
         for i in 0..threads_num {
             workers.push(Some(Worker::new(i, Arc::clone(&receiver))));
         }
 
-        QueueThreadPool { workers, sender }
+        QueueThreadPool { workers, sender: Some(sender) }
     }
 
+    /// Spawn the job without blocking.
+    /// # Error
+    /// It returns the job back if the queue of the bounded pool is full.
+    pub fn try_spawn<F>(&self, f: F) -> Result<(), Job>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        let job: Job = Box::new(f);
+        match self.sender() {
+            Sender::Unbounded(sender) => {
+                sender.send(job).unwrap();
+                Ok(())
+            }
+            Sender::Bounded(sender) => match sender.try_send(job) {
+                Ok(()) => Ok(()),
+                Err(mpsc::TrySendError::Full(job)) => {
+                    debug!("Queue of jobs is full");
+                    Err(job)
+                }
+                Err(e) => panic!("Unable to spawn job: {}", e),
+            },
+        }
+    }
+
+    fn sender(&self) -> &Sender {
+        self.sender.as_ref().expect("Queue of jobs is disconnected")
+    }
+}
+
+impl ThreadPool for QueueThreadPool {
+    fn new(threads_num: u32) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        QueueThreadPool::with_receiver(threads_num, Sender::Unbounded(sender), receiver)
+    }
+
+    /// Spawn the job, it blocks while the queue of the bounded pool is full.
     fn spawn<F>(&self, f: F)
         where
             F: FnOnce() + Send + 'static
     {
-        self.sender.send(Message::New(Box::new(f))).unwrap();
+        self.sender().send(Box::new(f));
     }
 }
 
 impl Drop for QueueThreadPool {
     fn drop(&mut self) {
         debug!("Shutdown thread pool and {} workers", self.workers.len());
-        for _ in &self.workers {
-            self.sender.send(Message::Shutdown).unwrap();
-        }
+        // Workers exit after taking all pending jobs from the disconnected queue
+        drop(self.sender.take());
 
         for worker in &mut self.workers {
             if let Some(worker) = worker.take() {
                 debug!("Shutdown worker #{}", worker.id);
-                worker.handler.join().unwrap();
+                // The thread may be replaced while joining, the replacement is joined then
+                loop {
+                    let handler = worker.handler.lock().unwrap().take();
+                    match handler {
+                        Some(handler) => handler.join().unwrap(),
+                        None => break,
+                    }
+                }
             }
         }
     }
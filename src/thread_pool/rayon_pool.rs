@@ -16,10 +16,12 @@ impl ThreadPool for RayonThreadPool {
         RayonThreadPool { inner }
     }
 
+    /// Spawn the job without waiting for it.
+    /// A panic of the job aborts the process, `spawn_handle` catches it instead.
     fn spawn<F>(&self, job: F)
         where
             F: FnOnce() + Send + 'static
     {
-        self.inner.install(job);
+        self.inner.spawn(job);
     }
 }
\ No newline at end of file
@@ -0,0 +1,3 @@
+pub use wait_group::WaitGroup;
+
+mod wait_group;
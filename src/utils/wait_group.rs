@@ -0,0 +1,114 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// `WaitGroup` allows to wait until a group of tasks is finished.
+/// Every task holds a clone of the `WaitGroup` and finishes by dropping it,
+/// the original `WaitGroup` waits until all its clones are dropped.
+/// Alternatively the known number of tasks is registered by `add`
+/// and every task finishes by `done` without cloning.
+#[derive(Debug)]
+pub struct WaitGroup {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    counters: Mutex<Counters>,
+    finished: Condvar,
+}
+
+#[derive(Debug)]
+struct Counters {
+    /// Number of alive instances including the original one.
+    instances: usize,
+    /// Number of tasks registered by `add` and not finished by `done`.
+    tasks: usize,
+}
+
+impl Counters {
+    fn is_finished(&self) -> bool {
+        self.instances <= 1 && self.tasks == 0
+    }
+}
+
+impl WaitGroup {
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            inner: Arc::new(Inner {
+                counters: Mutex::new(Counters {
+                    instances: 1,
+                    tasks: 0,
+                }),
+                finished: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Register `n` tasks, each of them must be finished by `done`.
+    pub fn add(&self, n: usize) {
+        self.inner.counters.lock().unwrap().tasks += n;
+    }
+
+    /// Finish one of tasks registered by `add`.
+    /// # Panics
+    /// It panics if there are no registered tasks, as the counter would become negative.
+    pub fn done(&self) {
+        let mut counters = self.inner.counters.lock().unwrap();
+        if counters.tasks == 0 {
+            panic!("WaitGroup::done is called more times than registered by WaitGroup::add");
+        }
+        counters.tasks -= 1;
+        if counters.is_finished() {
+            self.inner.finished.notify_all();
+        }
+    }
+
+    /// Block until all clones of the `WaitGroup` are dropped and all registered tasks are done.
+    /// Note: it must be called on the original `WaitGroup`, a clone would wait for itself forever.
+    pub fn wait(&self) {
+        let mut counters = self.inner.counters.lock().unwrap();
+        while !counters.is_finished() {
+            counters = self.inner.finished.wait(counters).unwrap();
+        }
+    }
+
+    /// Block like `wait`, but no longer than `timeout`.
+    /// Returns `true` if the group is finished before the deadline.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut counters = self.inner.counters.lock().unwrap();
+        while !counters.is_finished() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            counters = self.inner.finished.wait_timeout(counters, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        WaitGroup::new()
+    }
+}
+
+impl Clone for WaitGroup {
+    fn clone(&self) -> Self {
+        self.inner.counters.lock().unwrap().instances += 1;
+        WaitGroup {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl Drop for WaitGroup {
+    fn drop(&mut self) {
+        let mut counters = self.inner.counters.lock().unwrap();
+        counters.instances -= 1;
+        if counters.is_finished() {
+            self.inner.finished.notify_all();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+#![cfg(feature = "async")]
+
+use kvs::protocol::Response;
+use kvs::{AsyncKvsEngine, AsyncServer, Client, KvStore, KvsEngine, Result};
+use std::io::Cursor;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// AsyncKvsEngine should offload operations of KvStore without blocking the runtime
+#[test]
+fn async_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let runtime = Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        AsyncKvsEngine::set(&store, "key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            AsyncKvsEngine::get(&store, "key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(AsyncKvsEngine::increment(&store, "counter".to_owned(), 2).await?, 2);
+        AsyncKvsEngine::remove(&store, "key1".to_owned()).await?;
+        assert_eq!(AsyncKvsEngine::get(&store, "key1".to_owned()).await?, None);
+        Ok(())
+    })
+}
+
+// AsyncServer should serve the protocol of the synchronous Client
+#[test]
+fn async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    // The port is chosen by the OS and released for the server
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        let server = AsyncServer::new(addr, store);
+        Runtime::new().unwrap().block_on(server.run())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = Client::new(addr);
+    let mut session = client.connect().unwrap();
+    for key_id in 0..10 {
+        match session.set(format!("key{}", key_id), "value".to_owned()).unwrap() {
+            Response::Ok(None) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+    match session.get("key5".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    drop(session);
+
+    let value = "0123456789".repeat(100_000);
+    match client
+        .set_stream("stream".to_owned(), Cursor::new(value.clone()), value.len() as u64)
+        .unwrap()
+    {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.get("stream".to_owned()).unwrap() {
+        Response::Ok(Some(received)) => assert!(received == value),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.incr("counter".to_owned(), 3).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "3"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.rm("missing".to_owned()).unwrap() {
+        Response::Err(_) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.stats().unwrap() {
+        Response::Stats(stats) => assert_eq!(stats.live_keys, 12),
+        response => panic!("unexpected response: {:?}", response),
+    }
+}
@@ -1,6 +1,11 @@
 use assert_cmd::prelude::*;
+use kvs::protocol::{read_frame, write_frame, Address, AdminOp, Request, Response, ResponseError};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Server};
+use predicates::prelude::PredicateBooleanExt;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpListener;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -212,6 +217,136 @@ fn cli_wrong_engine() {
     }
 }
 
+// Engine file of the old bare format should be accepted and migrated,
+// the versioned one should be accepted with unknown fields, invalid content should fail gracefully
+#[test]
+fn cli_engine_file_formats() {
+    let run_server = |temp_dir: &TempDir, engine: &str| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(&["--engine", engine, "--addr", "127.0.0.1:0"])
+            .current_dir(temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        assert!(child.try_wait().unwrap().is_none(), "server exited");
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    };
+
+    // Old format
+    let temp_dir = TempDir::new().unwrap();
+    let engine_file = temp_dir.path().join("engine");
+    fs::write(&engine_file, "Kvs").unwrap();
+    run_server(&temp_dir, "kvs");
+    let content = fs::read_to_string(&engine_file).unwrap();
+    assert_eq!(content, r#"{"version":1,"engine":"kvs"}"#);
+
+    // New format written by a future version
+    let temp_dir = TempDir::new().unwrap();
+    let engine_file = temp_dir.path().join("engine");
+    fs::write(&engine_file, r#"{"version":2,"engine":"sled","shards":4}"#).unwrap();
+    run_server(&temp_dir, "sled");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("panicked").not());
+
+    // Invalid content
+    for content in &["rocksdb", r#"{"version":1}"#] {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("engine"), content).unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", "127.0.0.1:0"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("panicked").not());
+    }
+}
+
+// `kvs-server migrate` should refuse the non-empty destination directory
+// and remove the partially migrated destination on error
+#[test]
+fn cli_migrate_dest() {
+    let src_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let migrate = |dest: &std::path::Path| {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["migrate", "--from", "kvs", "--to", "sled", "--dir"])
+            .arg(src_dir.path())
+            .arg("--dest")
+            .arg(dest)
+            .assert()
+            .failure()
+            .stderr(contains("panicked").not());
+    };
+
+    // Files of the destination are kept
+    let foreign_file = dest_dir.path().join("notes.txt");
+    fs::write(&foreign_file, "notes").unwrap();
+    migrate(dest_dir.path());
+    assert_eq!(fs::read_to_string(&foreign_file).unwrap(), "notes");
+
+    // The source storage fails to open after the destination is created
+    fs::write(src_dir.path().join("1.passive"), "garbage").unwrap();
+    let dest = dest_dir.path().join("dest");
+    migrate(&dest);
+    assert!(!dest.exists());
+}
+
+// `kvs-client --output json` should print parseable results distinguishing a miss
+// from the value which looks like the miss, exit codes should stay the same
+#[test]
+fn cli_json_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0).to_string();
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let run_client = |args: &[&str]| {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--output", "json", "--addr", &addr])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).expect("invalid JSON output");
+        (output.status, json)
+    };
+
+    let (status, json) = run_client(&["get", "key1"]);
+    assert!(status.success());
+    assert_eq!(json["ok"], serde_json::Value::Bool(false));
+    assert_eq!(json["error"].as_str(), Some("KeyNotFound"));
+
+    let (status, json) = run_client(&["set", "key1", "Key not found"]);
+    assert!(status.success());
+    assert_eq!(json["ok"], serde_json::Value::Bool(true));
+
+    let (status, json) = run_client(&["get", "key1"]);
+    assert!(status.success());
+    assert_eq!(json["ok"], serde_json::Value::Bool(true));
+    assert_eq!(json["value"].as_str(), Some("Key not found"));
+
+    let (status, json) = run_client(&["rm", "key2"]);
+    assert_eq!(status.code(), Some(1));
+    assert_eq!(json["ok"], serde_json::Value::Bool(false));
+    assert_eq!(json["error"].as_str(), Some("KeyNotFound"));
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +470,96 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+/// Start the server answering the only request by `response` and return its address.
+fn fake_server(response: Response) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _: Option<Request> = read_frame(&mut stream).unwrap();
+        write_frame(&mut stream, &response).unwrap();
+    });
+    addr
+}
+
+// `kvs-client` should exit with the stable code of every kind of failure
+// and print it to stderr as one line
+#[test]
+fn client_exit_codes() {
+    let run_client = |args: &[&str], addr: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", addr, "--logging", "off"])
+            .output()
+            .unwrap()
+    };
+    let assert_failure = |output: std::process::Output, code: i32, name: &str| {
+        assert_eq!(output.status.code(), Some(code));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with(&format!("error: {}: ", name)), "{}", stderr);
+        assert_eq!(stderr.lines().count(), 1);
+    };
+
+    let addr = fake_server(Response::NotFound);
+    assert_failure(run_client(&["rm", "key"], &addr), 1, "key_not_found");
+
+    let unused_addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    assert_failure(run_client(&["get", "key"], &unused_addr), 2, "connection_error");
+
+    let addr = fake_server(Response::Keys(vec![]));
+    assert_failure(run_client(&["get", "key"], &addr), 3, "protocol_error");
+    let addr = fake_server(Response::Err(ResponseError::InvalidRequest("nested batch".to_owned())));
+    assert_failure(run_client(&["set", "key", "value"], &addr), 3, "protocol_error");
+
+    let addr = fake_server(Response::Err(ResponseError::Other("disk is full".to_owned())));
+    assert_failure(run_client(&["set", "key", "value"], &addr), 4, "engine_error");
+}
+
+// `kvs-client admin` should send the admin operation with the token read from the file
+// and exit with the protocol error code if it is rejected
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let token_file = temp_dir.path().join("admin_token");
+    fs::write(&token_file, "secret\n").unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request: Option<Request> = read_frame(&mut stream).unwrap();
+        write_frame(&mut stream, &Response::Ok(None)).unwrap();
+        sender.send(request).unwrap();
+    });
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "rotate", "--token-file"])
+        .arg(&token_file)
+        .args(&["--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    match receiver.recv().unwrap() {
+        Some(Request::Admin { token, op }) => {
+            assert_eq!(token, "secret");
+            assert_eq!(op, AdminOp::Rotate);
+        }
+        request => panic!("unexpected request: {:?}", request),
+    }
+
+    let addr = fake_server(Response::Err(ResponseError::Unauthorized));
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "clear", "--token-file"])
+        .arg(&token_file)
+        .args(&["--addr", &addr, "--logging", "off"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+}
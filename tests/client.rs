@@ -0,0 +1,682 @@
+use assert_cmd::prelude::*;
+use kvs::protocol::{
+    decode_frame, decode_frame_prefix, decompress_payload, encode_frame, encode_frame_with, read_frame, write_frame,
+    Address, ProtocolError, Request, Response, ResponseError, Stream,
+};
+#[cfg(unix)]
+use kvs::protocol::Listener;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{Client, ClientBuilder, KvStore, KvsEngine, Server, ShutdownHandle, TraceEntry};
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Starts the server of `KvStore` in `dir` on the port chosen by the OS
+fn start_server(dir: &Path) -> (Address, ShutdownHandle, JoinHandle<Result<(), ProtocolError>>) {
+    let store = KvStore::open(dir).unwrap();
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+    (addr, shutdown_handle, server_thread)
+}
+
+// Returns the address of the port chosen by the OS that nothing listens on
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+// Client with enabled tracing should record requests and responses in order
+#[test]
+fn client_trace_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let trace_path = temp_dir.path().join("trace.log");
+    let client = ClientBuilder::new(addr.clone())
+        .with_trace_log(&trace_path)
+        .build()
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    client.rm("key2".to_owned()).unwrap();
+    drop(client);
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+
+    let content = fs::read_to_string(&trace_path).expect("unable to read trace log");
+    let entries: Vec<TraceEntry> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("invalid trace entry"))
+        .collect();
+    assert_eq!(entries.len(), 3);
+
+    match &entries[0] {
+        TraceEntry {
+            request: Request::Set { key, value },
+            response: Response::Ok(None),
+        } => assert_eq!((key.as_str(), value.as_str()), ("key1", "value1")),
+        entry => panic!("unexpected trace entry: {:?}", entry),
+    }
+    match &entries[1] {
+        TraceEntry {
+            request: Request::Get { key },
+            response: Response::Ok(Some(value)),
+        } => assert_eq!((key.as_str(), value.as_str()), ("key1", "value1")),
+        entry => panic!("unexpected trace entry: {:?}", entry),
+    }
+    match &entries[2] {
+        TraceEntry {
+            request: Request::Rm { key },
+            response: Response::NotFound,
+        } => assert_eq!(key, "key2"),
+        entry => panic!("unexpected trace entry: {:?}", entry),
+    }
+}
+
+// Large value should be streamed to the server and read back whole,
+// interrupted stream should not store anything
+#[test]
+fn client_set_stream() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    let value = "0123456789".repeat(300_000);
+    let response = client
+        .set_stream("key1".to_owned(), Cursor::new(value.clone()), value.len() as u64)
+        .unwrap();
+    match response {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(Some(received)) => assert!(received == value),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    // Reader fails in the middle of the value
+    let failing_reader = Cursor::new(value.clone())
+        .take(100_000)
+        .chain(FailingReader);
+    assert!(client
+        .set_stream("key2".to_owned(), failing_reader, value.len() as u64)
+        .is_err());
+    match client.get("key2".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+struct FailingReader;
+
+impl Read for FailingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "broken source"))
+    }
+}
+
+// Multiple requests should be served over one connection,
+// disconnection in the middle of request should not affect the server
+#[test]
+fn client_session() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    let mut session = client.connect().unwrap();
+    for i in 0..100 {
+        match session.set(format!("key{}", i), format!("value{}", i)).unwrap() {
+            Response::Ok(None) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+    match session.get("key42".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value42"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match session.rm("key42".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match session.get("key42".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    drop(session);
+
+    // Frame is interrupted in the middle of the payload
+    let mut stream = Stream::connect(&addr, None).unwrap();
+    stream.write_all(&100u32.to_be_bytes()).unwrap();
+    stream.write_all(br#"{"Set":{"key":"key1","#).unwrap();
+    drop(stream);
+
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Server backed by `NaiveThreadPool` should serve concurrent clients
+#[test]
+fn naive_pool_concurrent_clients() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let handles = (0..8)
+        .map(|thread_id| {
+            let client = Client::new(addr.clone());
+            thread::spawn(move || {
+                let mut session = client.connect().unwrap();
+                for i in 0..20 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    session.set(key.clone(), format!("value{}", i)).unwrap();
+                    match session.get(key).unwrap() {
+                        Response::Ok(Some(value)) => assert_eq!(value, format!("value{}", i)),
+                        response => panic!("unexpected response: {:?}", response),
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Several framed requests written at once over one connection should be answered in order
+#[test]
+fn framed_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let requests = vec![
+        Request::Set { key: "key1".to_owned(), value: "value1".to_owned() },
+        Request::Get { key: "key1".to_owned() },
+        Request::Rm { key: "key2".to_owned() },
+        Request::Get { key: "key2".to_owned() },
+    ];
+    let frames = requests
+        .iter()
+        .flat_map(|request| encode_frame(request).unwrap())
+        .collect::<Vec<u8>>();
+    let mut stream = Stream::connect(&addr, None).unwrap();
+    stream.write_all(&frames).unwrap();
+
+    let responses = (0..requests.len())
+        .map(|_| read_frame::<_, Response>(&mut stream).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    match &responses[..] {
+        [
+            Response::Ok(None),
+            Response::Ok(Some(value)),
+            Response::NotFound,
+            Response::Ok(None),
+        ] => assert_eq!(value, "value1"),
+        responses => panic!("unexpected responses: {:?}", responses),
+    }
+
+    // The connection is closed by the client between frames
+    drop(stream);
+    match Client::new(addr.clone()).get("key1".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Requests of the batch should be applied in order, nested batches should be rejected
+#[test]
+fn client_batch() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    let responses = client
+        .batch(vec![
+            Request::Get { key: "key1".to_owned() },
+            Request::Set { key: "key1".to_owned(), value: "value1".to_owned() },
+            Request::Get { key: "key1".to_owned() },
+            Request::Batch(vec![Request::Rm { key: "key1".to_owned() }]),
+            Request::Set { key: "key2".to_owned(), value: "value2".to_owned() },
+            Request::Rm { key: "key3".to_owned() },
+            Request::Exists { key: "key2".to_owned() },
+            Request::Exists { key: "key3".to_owned() },
+        ])
+        .unwrap();
+    match &responses[..] {
+        [
+            Response::Ok(None),
+            Response::Ok(None),
+            Response::Ok(Some(value)),
+            Response::Err(ResponseError::InvalidRequest(_)),
+            Response::Ok(None),
+            Response::NotFound,
+            Response::Bool(true),
+            Response::Bool(false),
+        ] => assert_eq!(value, "value1"),
+        responses => panic!("unexpected responses: {:?}", responses),
+    }
+
+    // The nested batch is not applied
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.get("key2".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value2"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Client with timeouts should fail fast if the server doesn't answer,
+// retries should reconnect on timeouts
+#[test]
+fn client_timeout() {
+    // Connections are queued by the listener but never accepted
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = Client::with_timeout(addr, Duration::from_millis(200), Duration::from_millis(200));
+
+    let start = Instant::now();
+    match client.get("key".to_owned()) {
+        Err(ProtocolError::Timeout) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let start = Instant::now();
+    let res = client.send_with_retries(Request::Get { key: "key".to_owned() }, 2, Duration::from_millis(100));
+    match res {
+        Err(ProtocolError::Timeout) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    // 3 attempts and 2 delays of 100 and 200 milliseconds
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+// Scan should list only keys with the given prefix, at most `limit` of them,
+// also by the `scan` subcommand of kvs-client
+#[test]
+fn client_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    for key in &["user:1", "user:2", "user:3", "group:1", "group:2"] {
+        client.set(key.to_string(), "value".to_owned()).unwrap();
+    }
+
+    match client.scan(Some("user:".to_owned()), None).unwrap() {
+        Response::Keys(keys) => assert_eq!(keys, vec!["user:1", "user:2", "user:3"]),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.scan(Some("user:".to_owned()), Some(2)).unwrap() {
+        Response::Keys(keys) => assert_eq!(keys, vec!["user:1", "user:2"]),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.scan(None, None).unwrap() {
+        Response::Keys(keys) => assert_eq!(keys.len(), 5),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "group:", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("group:1\ngroup:2\n");
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Metrics of the server should count served requests and errors
+#[test]
+fn client_metrics() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    client.get("key3".to_owned()).unwrap();
+    client.rm("key3".to_owned()).unwrap();
+    client
+        .batch(vec![
+            Request::Get { key: "key2".to_owned() },
+            Request::Rm { key: "key2".to_owned() },
+        ])
+        .unwrap();
+
+    let text = match client.metrics().unwrap() {
+        Response::Text(text) => text,
+        response => panic!("unexpected response: {:?}", response),
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.contains(&"# TYPE kvs_gets_total counter"));
+    assert!(lines.contains(&"kvs_gets_total 3"));
+    assert!(lines.contains(&"kvs_sets_total 2"));
+    assert!(lines.contains(&"kvs_removes_total 2"));
+    assert!(lines.contains(&"kvs_errors_total 1"));
+    assert!(lines.contains(&"# TYPE kvs_live_keys gauge"));
+    assert!(lines.contains(&"kvs_live_keys 1"));
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Set and get should be served over UNIX domain socket, also by kvs-client
+#[cfg(unix)]
+#[test]
+fn client_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--socket"])
+        .arg(&socket_path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = Client::new(socket_path.clone());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--socket"])
+        .arg(&socket_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// Binding the UNIX domain socket should replace the stale socket file, but not the socket of the running server
+#[cfg(unix)]
+#[test]
+fn listener_stale_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let addr = Address::Unix(socket_path.clone());
+    let listener = Listener::bind(&addr).unwrap();
+    match Listener::bind(&addr) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::AddrInUse),
+        Ok(_) => panic!("socket of the running listener is replaced"),
+    }
+    Stream::connect(&addr, None).unwrap();
+    listener.accept().unwrap();
+
+    // The socket file is left after dropping the listener
+    drop(listener);
+    assert!(socket_path.exists());
+    let listener = Listener::bind(&addr).unwrap();
+    Stream::connect(&addr, None).unwrap();
+    listener.accept().unwrap();
+}
+
+// Values of multiple keys should be returned by one request in order of keys,
+// also by the `mget` subcommand of kvs-client
+#[test]
+fn client_mget() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+
+    let keys = vec!["key3".to_owned(), "key2".to_owned(), "key1".to_owned(), "key3".to_owned()];
+    match client.mget(keys).unwrap() {
+        Response::Values(values) => assert_eq!(
+            values,
+            vec![Some("value3".to_owned()), None, Some("value1".to_owned()), Some("value3".to_owned())]
+        ),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.mget(Vec::new()).unwrap() {
+        Response::Values(values) => assert!(values.is_empty()),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mget", "key1", "key2", "key3", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue3\n");
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Client should connect by retries with backoff once the server is up, failing fast by default
+#[test]
+fn client_connect_with_backoff() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let client = Client::new(addr);
+    assert!(client.connect().is_err());
+
+    let client_thread = thread::spawn(move || {
+        let client = Client::new(addr);
+        let mut session = client.connect_with_backoff(10, Duration::from_millis(100)).unwrap();
+        match session.set("key".to_owned(), "value".to_owned()).unwrap() {
+            Response::Ok(None) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let server = Server::new(addr, NaiveThreadPool::new(4), store);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+    client_thread.join().unwrap();
+    match client.get("key".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+
+    // 3 attempts and 2 delays of at least 50 and 100 milliseconds
+    let client = Client::new(free_addr());
+    let start = Instant::now();
+    match client.connect_with_backoff(3, Duration::from_millis(100)) {
+        Err(ProtocolError::ConnectFailed { attempts: 3, .. }) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("connected to the absent server"),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+// Large values should round-trip over the connection with negotiated compression in smaller frames,
+// small frames should be sent uncompressed
+#[test]
+fn client_compression() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let value = "compressible value ".repeat(10_000);
+    let client = ClientBuilder::new(addr.clone()).with_compression().build().unwrap();
+    let mut session = client.connect().unwrap();
+    assert!(session.is_compressed());
+    match session.set("key".to_owned(), value.clone()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match session.get("key".to_owned()).unwrap() {
+        Response::Ok(Some(received)) => assert!(received == value),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match session.get("missing".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    drop(session);
+
+    let response = Response::Ok(Some(value.clone()));
+    assert!(encode_frame_with(&response, true).unwrap().len() * 10 < encode_frame(&response).unwrap().len());
+    let small = Response::Ok(Some("value".to_owned()));
+    assert_eq!(encode_frame_with(&small, true).unwrap(), encode_frame(&small).unwrap());
+
+    // The response frame is compressed on the wire after the handshake
+    let mut stream = Stream::connect(&addr, None).unwrap();
+    write_frame(&mut stream, &Request::Hello { compression: true }).unwrap();
+    match read_frame::<_, Response>(&mut stream).unwrap().unwrap() {
+        Response::Bool(true) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    write_frame(&mut stream, &Request::Get { key: "key".to_owned() }).unwrap();
+    let mut prefix = [0; 4];
+    stream.read_exact(&mut prefix).unwrap();
+    let (len, is_compressed) = decode_frame_prefix(prefix).unwrap();
+    assert!(is_compressed);
+    assert!(len * 10 < value.len());
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    match decode_frame(&decompress_payload(&payload).unwrap()).unwrap() {
+        Response::Ok(Some(received)) => assert!(received == value),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    drop(stream);
+
+    // Frames are uncompressed unless compression is requested
+    let client = Client::new(addr);
+    assert!(!client.connect().unwrap().is_compressed());
+    match client.get("key".to_owned()).unwrap() {
+        Response::Ok(Some(received)) => assert!(received == value),
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Pipelines exceeding socket buffers in both directions should not block the client and the server
+#[test]
+fn client_large_pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    // Large values are both sent and received, 25 MB in each direction.
+    // Pipelined requests may be served in any order, so values are read from the key set before
+    let value = "v".repeat(64 * 1024);
+    let client = Client::new(addr);
+    client.set("key".to_owned(), value.clone()).unwrap();
+    let requests = (0..400)
+        .flat_map(|i| {
+            vec![
+                (2 * i, Request::Set { key: format!("key{}", i), value: value.clone() }),
+                (2 * i + 1, Request::Get { key: "key".to_owned() }),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let responses = client.pipeline(requests).unwrap();
+    assert_eq!(responses.len(), 800);
+    for i in 0..400 {
+        match &responses[&(2 * i + 1)] {
+            Response::Ok(Some(received)) => assert!(*received == value),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Responses to pipelined requests should be matched by ids of requests,
+// untagged requests on the same connection should get untagged responses
+#[test]
+fn client_pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown_handle, server_thread) = start_server(temp_dir.path());
+
+    let client = Client::new(addr.clone());
+    let mut session = client.connect().unwrap();
+    let responses = session
+        .pipeline(vec![
+            (7, Request::Set { key: "key".to_owned(), value: "value".to_owned() }),
+            (3, Request::Get { key: "key".to_owned() }),
+            (42, Request::Rm { key: "missing".to_owned() }),
+        ])
+        .unwrap();
+    assert_eq!(responses.len(), 3);
+    match (&responses[&7], &responses[&3], &responses[&42]) {
+        (Response::Ok(None), Response::Ok(Some(value)), Response::NotFound) => assert_eq!(value, "value"),
+        responses => panic!("unexpected responses: {:?}", responses),
+    }
+    match session.get("key".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    assert!(session.pipeline(vec![(1, Request::Ping), (1, Request::Ping)]).is_err());
+    drop(session);
+
+    // Ids are echoed as is, nested tagged requests are rejected
+    let mut stream = Stream::connect(&addr, None).unwrap();
+    let requests = vec![
+        Request::Tagged { id: 20, request: Box::new(Request::Ping) },
+        Request::Get { key: "key".to_owned() },
+        Request::Tagged {
+            id: 10,
+            request: Box::new(Request::Tagged { id: 30, request: Box::new(Request::Ping) }),
+        },
+    ];
+    for request in &requests {
+        write_frame(&mut stream, request).unwrap();
+    }
+    let responses = (0..requests.len())
+        .map(|_| read_frame::<_, Response>(&mut stream).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    match &responses[..] {
+        [
+            Response::Tagged { id: 20, response: pong },
+            Response::Ok(Some(value)),
+            Response::Tagged { id: 10, response: nested },
+        ] => {
+            assert!(matches!(**pong, Response::Pong));
+            assert_eq!(value, "value");
+            assert!(matches!(**nested, Response::Err(ResponseError::InvalidRequest(_))));
+        }
+        responses => panic!("unexpected responses: {:?}", responses),
+    }
+    drop(stream);
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
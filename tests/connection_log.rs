@@ -0,0 +1,85 @@
+use kvs::protocol::{Address, Response};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{Client, KvStore, KvsEngine, Server};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use std::thread;
+use tempfile::TempDir;
+
+/// Logger keeping all log lines in memory.
+/// Only one logger can be set per process, so the test is alone in the file.
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.lines.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    lines: Mutex::new(Vec::new()),
+};
+
+/// Get the connection prefix of the log line, e.g. `[conn 1]`.
+fn connection_prefix(line: &str) -> &str {
+    &line[..line.find(']').unwrap() + 1]
+}
+
+// Log lines of a request should be prefixed by the id of its connection
+#[test]
+fn connection_id_in_logs() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let addrs = vec![Address::Tcp("127.0.0.1:0".parse().unwrap())];
+    let mut server = Server::with_addrs(addrs, NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let local_addrs = server.local_addrs().unwrap();
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    // Every request of the client is sent by a new connection
+    let client = Client::new(local_addrs[0].clone());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    match client.rm("missing".to_owned()).unwrap() {
+        Response::NotFound => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+
+    let lines = LOGGER.lines.lock().unwrap();
+    let accepted = lines
+        .iter()
+        .filter(|line| line.contains("Accept client"))
+        .map(|line| connection_prefix(line))
+        .collect::<Vec<_>>();
+    assert_eq!(accepted, vec!["[conn 1]", "[conn 2]", "[conn 3]"]);
+
+    let rm_line = lines.iter().find(|line| line.contains("Remove key: missing")).unwrap();
+    assert_eq!(connection_prefix(rm_line), "[conn 2]");
+    let lifecycle = lines
+        .iter()
+        .filter(|line| line.starts_with("[conn 2]"))
+        .collect::<Vec<_>>();
+    for expected in &["Accept client", "Get request", "Remove key: missing", "Key not found", "Send response: NotFound"] {
+        assert!(
+            lifecycle.iter().any(|line| line.contains(expected)),
+            "no {:?} in {:?}",
+            expected,
+            lifecycle
+        );
+    }
+    assert!(!lifecycle.iter().any(|line| line.contains("key1") || line.contains("key2")));
+}
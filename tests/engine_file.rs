@@ -0,0 +1,66 @@
+use kvs::{migrate, reconcile_engine_file, EngineKind, KvError, KvStore, KvsEngine, SledEngine, ENGINE_FILE_NAME};
+use std::fs;
+use tempfile::TempDir;
+
+// Should record the engine of the new storage directory and report the other engine
+// as the typed error instead of exiting
+#[test]
+fn engine_mismatch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    reconcile_engine_file(temp_dir.path(), EngineKind::Sled).unwrap();
+    reconcile_engine_file(temp_dir.path(), EngineKind::Sled).unwrap();
+
+    match reconcile_engine_file(temp_dir.path(), EngineKind::Kvs) {
+        Err(KvError::EngineMismatch { existing, requested }) => {
+            assert_eq!(existing, EngineKind::Sled);
+            assert_eq!(requested, EngineKind::Kvs);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+// Should migrate the engine file of the old format and reject invalid content
+#[test]
+fn engine_file_formats() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine_file = temp_dir.path().join(ENGINE_FILE_NAME);
+    fs::write(&engine_file, "Kvs").unwrap();
+    reconcile_engine_file(temp_dir.path(), EngineKind::Kvs).unwrap();
+    assert_eq!(fs::read_to_string(&engine_file).unwrap(), r#"{"version":1,"engine":"kvs"}"#);
+
+    for content in &["rocksdb", r#"{"version":1}"#] {
+        fs::write(&engine_file, content).unwrap();
+        match reconcile_engine_file(temp_dir.path(), EngineKind::Kvs) {
+            Err(KvError::InvalidEngineFile(_)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
+
+// Should copy all keys of the sled storage to the fresh kvs storage and refuse the non-empty one
+#[test]
+fn migrate_sled_to_kvs() {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = SledEngine::open(src_dir.path()).unwrap();
+    for i in 0..100 {
+        src.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    src.remove("key0".to_owned()).unwrap();
+
+    let dst = KvStore::open(dst_dir.path()).unwrap();
+    assert_eq!(migrate(&src, &dst).unwrap(), 99);
+    drop(dst);
+
+    let dst = KvStore::open(dst_dir.path()).unwrap();
+    assert_eq!(dst.len(), 99);
+    assert_eq!(dst.get("key0".to_owned()).unwrap(), None);
+    for i in 1..100 {
+        assert_eq!(dst.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+    }
+
+    match migrate(&src, &dst) {
+        Err(KvError::NotEmpty) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
@@ -1,20 +1,172 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Codec, CompactionStrategy, Compression, DurabilityMode, KvError, KvStore, KvStoreConfig, KvsEngine, LogConfig, Result,
+    SledConfig, SledEngine, WriteOp,
+};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+#[cfg(feature = "memory")]
+use kvs::MemoryEngine;
+
+// Engine-agnostic checks are run against every engine, persistent ones are reopened afterwards
+
+/// Run engine-agnostic checks against the `MemoryEngine`, which never touches disk.
+#[cfg(feature = "memory")]
+macro_rules! memory_engine_tests {
+    ($($name:ident => $check:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() -> Result<()> {
+                $check(&MemoryEngine::open("/nonexistent/path")?)
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "memory")]
+memory_engine_tests! {
+    memory_get_stored_value => check_stored_value,
+    memory_overwrite_value => check_overwritten_value,
+    memory_get_non_existent_value => check_non_existent_value,
+    memory_remove_non_existent_key => check_non_existent_key_removal,
+    memory_remove_key => check_key_removal,
+    memory_concurrent_set => check_concurrent_set,
+    memory_concurrent_get => check_concurrent_get,
+    memory_concurrent_increment => check_concurrent_increment,
+}
+
+/// Set values of `key1` and `key2` and get them back.
+fn check_stored_value(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Overwrite the value of `key1`, `value2` is left.
+fn check_overwritten_value(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.len(), 1);
+    Ok(())
+}
+
+/// Set `key1` and get `None` for `key2`.
+fn check_non_existent_value(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn check_non_existent_key_removal(engine: &impl KvsEngine) -> Result<()> {
+    match engine.remove("key1".to_owned()) {
+        Err(KvError::KeyNotFound) => {}
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    Ok(())
+}
+
+fn check_key_removal(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(engine.remove("key1".to_owned()).is_ok());
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+/// Set `key{i}` to `value{i}` for `i` in `0..1000` by concurrent threads and get them back.
+fn check_concurrent_set(engine: &impl KvsEngine) -> Result<()> {
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let engine = engine.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            engine
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+/// Get `key{i}` set to `value{i}` for `i` in `0..100` by concurrent threads, missing keys are set first.
+fn check_concurrent_get(engine: &impl KvsEngine) -> Result<()> {
+    for i in 0..100 {
+        if engine.get(format!("key{}", i))?.is_none() {
+            engine.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let engine = engine.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    engine.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+/// Increment `counter` by concurrent threads, increments of non-integer values are rejected.
+fn check_concurrent_increment(engine: &impl KvsEngine) -> Result<()> {
+    let threads = 8;
+    let increments = 125;
+    let handles = (0..threads)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..increments {
+                    engine.increment("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("1000".to_owned()));
+
+    assert_eq!(engine.increment("counter".to_owned(), -1001)?, -1);
+    assert_eq!(engine.increment("new".to_owned(), 5)?, 5);
+    engine.set("text".to_owned(), "value".to_owned())?;
+    match engine.increment("text".to_owned(), 1) {
+        Err(KvError::NotAnInteger) => {}
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
-
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    check_stored_value(&store)?;
 
     // Open from disk again and check persistent data
     drop(store);
@@ -30,11 +182,7 @@ fn get_stored_value() -> Result<()> {
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    check_overwritten_value(&store)?;
 
     // Open from disk again and check persistent data
     drop(store);
@@ -51,9 +199,7 @@ fn overwrite_value() -> Result<()> {
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    check_non_existent_value(&store)?;
 
     // Open from disk again and check persistent data
     drop(store);
@@ -67,18 +213,14 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
-    Ok(())
+    check_non_existent_key_removal(&store)
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
-    Ok(())
+    check_key_removal(&store)
 }
 
 // Insert data until total size of the directory decreases.
@@ -131,81 +273,2514 @@ fn compaction() -> Result<()> {
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
-    }
-    barrier.wait();
+    check_concurrent_set(&store)?;
 
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_concurrent_get(&store)?;
+
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    check_concurrent_get(&store)?;
+
+    Ok(())
+}
+
+// Should list all live keys after compaction
+#[test]
+fn keys_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Overwrite every key several times to exceed the records limit and trigger compaction
+    for iter in 0..4 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
     }
+    store.remove("key0".to_owned())?;
+
+    let mut keys: Vec<String> = store.keys().collect();
+    keys.sort();
+    let mut expected: Vec<String> = (1..500).map(|key_id| format!("key{}", key_id)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
 
     Ok(())
 }
 
+// Should list only keys with the given prefix
 #[test]
-fn concurrent_get() -> Result<()> {
+fn scan_prefix() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("user:2".to_owned(), "value2".to_owned())?;
+    store.set("group:1".to_owned(), "value3".to_owned())?;
+
+    let mut keys: Vec<String> = store.scan("user:").collect();
+    keys.sort();
+    assert_eq!(keys, vec!["user:1".to_owned(), "user:2".to_owned()]);
+    assert_eq!(store.scan("unknown").count(), 0);
+
+    Ok(())
+}
+
+// Should restore the configuration of the storage from the manifest
+#[test]
+fn manifest_restored_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_in_compacted: Some(1),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.manifest().last_serial_number, 0);
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
     }
+    drop(store);
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    // Reopen without specifying options, each record is compacted into its own passive file
+    let store = KvStore::open(temp_dir.path())?;
+    let manifest = store.manifest();
+    assert_eq!(manifest.chunk_size, 1);
+    assert_eq!(manifest.first_serial_number, 1);
+    assert_eq!(manifest.last_serial_number, 3);
+    for key_id in 0..3 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
     }
-    for handle in handles {
-        handle.join().unwrap();
+
+    Ok(())
+}
+
+// Should refuse to open the storage with incompatible manifest
+#[test]
+fn manifest_incompatible_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut manifest = store.manifest();
+    drop(store);
+
+    manifest.format_version += 1;
+    let file = File::create(temp_dir.path().join("MANIFEST")).expect("unable to create manifest");
+    serde_json::to_writer(file, &manifest).expect("unable to write manifest");
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Should refuse to open the storage with options conflicting with the manifest
+#[test]
+fn manifest_conflicting_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        codec: Some(Codec::Bincode),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let conflicting_config = KvStoreConfig {
+        codec: Some(Codec::Json),
+        ..KvStoreConfig::default()
+    };
+    match KvStore::open_with_config(temp_dir.path(), conflicting_config) {
+        Err(KvError::ManifestMismatch { field, manifest, config }) => {
+            assert_eq!(field, "codec");
+            assert_eq!(manifest, "Bincode");
+            assert_eq!(config, "Json");
+        }
+        res => panic!("Unexpected result: {:?}", res.map(|_| ())),
     }
 
-    // Open from disk again and check persistent data
+    // Options agreeing with the manifest are accepted
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.manifest().codec, Codec::Bincode);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should trigger compaction after exceeding the configured records limit
+#[test]
+fn configured_records_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    for iter in 0..4 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    // No passive datafiles until compaction
+    assert_eq!(store.manifest().last_serial_number, 0);
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.manifest().last_serial_number, 1);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should not return expired keys and should not keep them after compaction
+#[test]
+fn expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("session1".to_owned(), "value1".to_owned(), Duration::from_millis(50))?;
+    store.set_with_ttl("session2".to_owned(), "value2".to_owned(), Duration::from_millis(50))?;
+    store.set_with_ttl("session3".to_owned(), "value3".to_owned(), Duration::from_secs(3600))?;
+    assert_eq!(store.get("session1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("session1".to_owned())?, None);
+    assert_eq!(store.get("session3".to_owned())?, Some("value3".to_owned()));
+
+    // Compaction while dropping
     drop(store);
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.expect("fail to read directory").path().to_path_buf();
+        if path.is_file() {
+            let content = std::fs::read(&path).expect("unable to read datafile");
+            assert!(!content.windows(8).any(|bytes| bytes == b"session1"));
+            assert!(!content.windows(8).any(|bytes| bytes == b"session2"));
+        }
+    }
+
     let store = KvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    assert_eq!(store.get("session2".to_owned())?, None);
+    assert_eq!(store.get("session3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should keep the expiration time of the key with TTL after incrementing it
+#[test]
+fn increment_expiring_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("counter".to_owned(), "1".to_owned(), Duration::from_millis(100))?;
+    assert_eq!(store.increment("counter".to_owned(), 2)?, 3);
+    assert_eq!(store.get("counter".to_owned())?, Some("3".to_owned()));
+
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get("counter".to_owned())?, None);
+
+    // The expired key is incremented from 0 and has no TTL
+    store.set_with_ttl("counter".to_owned(), "1".to_owned(), Duration::from_millis(0))?;
+    assert_eq!(store.increment("counter".to_owned(), 2)?, 2);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.get("counter".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// Should verify the backup created by compaction and detect its corruption
+#[test]
+fn verify_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backups_dir = TempDir::new().expect("unable to create temporary backups directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set_backups_dir(backups_dir.path());
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
     }
-    for handle in handles {
-        handle.join().unwrap();
+    // Compaction with backup triggered
+    for iter in 0..5 {
+        store.set("key0".to_owned(), format!("{}", iter))?;
+    }
+
+    let backup_dir = std::fs::read_dir(backups_dir.path())?
+        .next()
+        .expect("no backup created")?
+        .path();
+    let report = KvStore::verify_backup(&backup_dir)?;
+    assert!(report.is_ok());
+    assert_eq!(report.keys, 10);
+    assert_eq!(report.records, 15);
+
+    let passive_path = backup_dir.join("1.passive");
+    std::fs::write(&passive_path, "{corrupted")?;
+    let report = KvStore::verify_backup(&backup_dir)?;
+    assert!(!report.is_ok());
+    assert_eq!(report.corrupted, vec![passive_path]);
+
+    Ok(())
+}
+
+// Should read the record of every live key and report exactly the key whose record is corrupted
+#[test]
+fn verify_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..4 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key3".to_owned())?;
+
+    let report = store.verify()?;
+    assert!(report.is_ok());
+    assert_eq!(report.datafiles, 1);
+    assert_eq!(report.keys, 3);
+    assert_eq!(report.records, 3);
+
+    // The record of key2 doesn't match its checksum anymore
+    let content = std::fs::read(&active_path)?;
+    let pos = content
+        .windows(b"value2".len())
+        .position(|window| window == b"value2")
+        .expect("value is not found in the datafile");
+    let mut file = std::fs::OpenOptions::new().write(true).open(&active_path)?;
+    file.seek(SeekFrom::Start(pos as u64))?;
+    file.write_all(b"valueX")?;
+    drop(file);
+
+    let report = store.verify()?;
+    assert!(!report.is_ok());
+    assert_eq!(report.keys, 3);
+    assert_eq!(report.records, 2);
+    assert_eq!(report.inconsistent, vec!["key2".to_owned()]);
+    assert!(report.corrupted.is_empty());
+
+    Ok(())
+}
+
+// Should store records by bincode and read them after reopening
+#[test]
+fn bincode_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        codec: Some(Codec::Bincode),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter % 3), format!("value{}", iter))?;
     }
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // Codec is restored from the manifest
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.manifest().codec, Codec::Bincode);
+    assert_eq!(store.get("key0".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value8".to_owned()));
+
+    Ok(())
+}
+
+// Should load the log written before datafile headers were introduced
+// and keep reading it after switching to another codec
+#[test]
+fn legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.passive"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    std::fs::write(
+        temp_dir.path().join("log.active"),
+        r#"{"Remove":{"key":"key2"}}{"Set":{"key":"key3","value":"value3"}}"#,
+    )?;
+
+    let config = KvStoreConfig {
+        codec: Some(Codec::Bincode),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should read records of version 0 written before versions were introduced
+// and reject datafiles written by a newer version
+#[test]
+fn record_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    // The header of JSON records without checksums and version
+    let mut bytes = vec![0x01];
+    bytes.extend_from_slice(br#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#);
+    std::fs::write(&active_path, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // Version 3 of JSON records
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let mut bytes = vec![0x31];
+    bytes.extend_from_slice(br#"{"Set":{"key":"key1","value":"value1"}}"#);
+    std::fs::write(&active_path, bytes)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::UnsupportedRecordVersion { file, version }) => {
+            assert_eq!(file, active_path);
+            assert_eq!(version, 3);
+        }
+        result => panic!("unexpected result: {:?}", result.map(|_| ()).map_err(|e| e.to_string())),
+    }
+
+    Ok(())
+}
+
+// Should detect the record truncated by a crash and drop it while reopening
+#[test]
+fn truncated_active_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Cut the last record in the middle
+    let len = std::fs::metadata(&active_path)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&active_path)?
+        .set_len(len - 3)?;
+    match store.get("key2".to_owned()) {
+        Err(KvError::CorruptRecord { file, .. }) => assert_eq!(file, active_path),
+        result => panic!("unexpected result: {:?}", result.map_err(|e| e.to_string())),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Crash without compaction
+    std::mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should report the truncated record of the legacy datafile instead of reading past it
+#[test]
+fn truncated_legacy_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let passive_path = temp_dir.path().join("1.passive");
+    std::fs::write(
+        &passive_path,
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    let len = std::fs::metadata(&passive_path)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&passive_path)?
+        .set_len(len - 3)?;
+    match store.get("key2".to_owned()) {
+        Err(KvError::CorruptRecord { file, .. }) => assert_eq!(file, passive_path),
+        result => panic!("unexpected result: {:?}", result.map_err(|e| e.to_string())),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should fail to open the storage with the corrupted passive datafile instead of losing its keys silently
+#[test]
+fn corrupted_passive_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let passive_path = temp_dir.path().join("1.passive");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rotate()?;
+    // Dumped datafiles have no hints, compaction on dropping would write them
+    std::mem::forget(store);
+
+    // Flip the value of the first record, its checksum mismatches
+    let mut bytes = std::fs::read(&passive_path)?;
+    let pos = bytes
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("record is not found");
+    bytes[pos] = b'w';
+    std::fs::write(&passive_path, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::CorruptRecord { file, .. }) => assert_eq!(file, passive_path),
+        result => panic!("unexpected result: {:?}", result.map(|_| ()).map_err(|e| e.to_string())),
+    }
+
+    Ok(())
+}
+
+// Should cut off garbage after the last whole record of the legacy active datafile if repairing is enabled
+#[test]
+fn repair_active_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let records = r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#;
+    std::fs::write(
+        temp_dir.path().join("log.active"),
+        format!("{}{}", records, r#"{"Set":{"key":"key3","val"#),
+    )?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let config = KvStoreConfig {
+        repair_active: true,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // Garbage in the middle of records without checksums is not cut off with records following it
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let mut bytes = records.as_bytes().to_vec();
+    bytes.extend_from_slice(&[0xFF, 0x00, 0x13]);
+    bytes.extend_from_slice(br#"{"Set":{"key":"key3","value":"value3"}}"#);
+    std::fs::write(&active_path, &bytes)?;
+
+    match KvStore::open_with_config(temp_dir.path(), config) {
+        Err(KvError::CorruptRecord { offset, .. }) => assert_eq!(offset, records.len() as u64),
+        result => panic!("unexpected result: {:?}", result.map(|_| ()).map_err(|e| e.to_string())),
+    }
+    assert_eq!(std::fs::read(&active_path)?, bytes);
+
+    Ok(())
+}
+
+// Should open the storage without indexing and index datafiles on demand from the newest
+#[test]
+fn lazy_indexing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        lazy_indexing: true,
+        ..KvStoreConfig::default()
+    };
+    for (serial_number, records) in [
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+        r#"{"Set":{"key":"key2","value":"value3"}}{"Set":{"key":"key3","value":"value3"}}"#,
+        r#"{"Remove":{"key":"key3"}}{"Set":{"key":"key4","value":"value4"}}"#,
+    ]
+    .iter()
+    .enumerate()
+    {
+        let path = temp_dir.path().join(format!("{}.passive", serial_number + 1));
+        std::fs::write(path, records)?;
+    }
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let passive_path = |serial_number| temp_dir.path().join(format!("{}.passive", serial_number));
+    assert_eq!(store.unindexed_datafiles().len(), 4); // 3 passive and active
+
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.unindexed_datafiles(), vec![passive_path(1), passive_path(2)]);
+
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.unindexed_datafiles(), vec![passive_path(1)]);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.unindexed_datafiles(), vec![passive_path(1)]);
+
+    // Written keys are resolved first, so their previous records are counted as unused
+    // and writes are not overridden by older datafiles
+    store.set("key1".to_owned(), "new_value1".to_owned())?;
+    assert!(store.unindexed_datafiles().is_empty());
+    assert_eq!(store.stats().unused_records, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("new_value1".to_owned()));
+
+    let mut keys = store.keys().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec!["key1", "key2", "key4"]);
+    assert!(store.unindexed_datafiles().is_empty());
+    drop(store);
+
+    // Lazy opening of the big storage is faster than indexing
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    std::mem::forget(store);
+
+    let start = Instant::now();
+    let store = KvStore::open(temp_dir.path())?;
+    let eager_open = start.elapsed();
+    std::mem::forget(store);
+
+    let start = Instant::now();
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let lazy_open = start.elapsed();
+    assert!(lazy_open < eager_open);
+    assert_eq!(store.get("key19999".to_owned())?, Some("value".to_owned()));
+    std::mem::forget(store);
+
+    Ok(())
+}
+
+// Readers and writers should not fail while compaction replaces datafiles
+#[test]
+fn concurrent_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 8,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..200 {
+                let key_id = (i + thread_id) % 10;
+                if thread_id % 2 == 0 {
+                    // Overwrites with the same value trigger compaction
+                    store
+                        .set(format!("key{}", key_id), format!("value{}", key_id))
+                        .unwrap();
+                } else {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(store.manifest().last_serial_number > 0);
+
+    Ok(())
+}
+
+// Exactly one of racing threads should swap the counter each round
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.compare_and_swap("counter".to_owned(), Some("0".to_owned()), Some("1".to_owned()))?);
+    assert!(store.compare_and_swap("counter".to_owned(), None, Some("0".to_owned()))?);
+
+    let threads = 8;
+    let rounds = 50;
+    let barrier = Arc::new(Barrier::new(threads));
+    let mut handles = Vec::new();
+    for _ in 0..threads {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        let handle = thread::spawn(move || {
+            let mut wins = Vec::new();
+            for round in 0..rounds {
+                barrier.wait();
+                let swapped = store
+                    .compare_and_swap(
+                        "counter".to_owned(),
+                        Some(round.to_string()),
+                        Some((round + 1).to_string()),
+                    )
+                    .unwrap();
+                wins.push(swapped);
+                barrier.wait();
+            }
+            wins
+        });
+        handles.push(handle);
+    }
+    let wins = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+    for round in 0..rounds {
+        assert_eq!(wins.iter().filter(|wins| wins[round]).count(), 1);
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some(rounds.to_string()));
+
+    assert!(store.compare_and_swap("counter".to_owned(), Some(rounds.to_string()), None)?);
+    assert_eq!(store.get("counter".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should count live keys only, the count should survive compaction
+#[test]
+fn len_after_remove_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert!(store.is_empty());
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(store.len(), 10);
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len(), 8);
+
+    // Compaction triggered
+    for iter in 0..5 {
+        store.set("key2".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.manifest().last_serial_number > 0);
+    assert_eq!(store.len(), 8);
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.len(), 8);
+    assert!(!store.is_empty());
+
+    Ok(())
+}
+
+// Same keys in different trees should not collide
+#[test]
+fn trees_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    let orders = store.open_tree("orders")?;
+
+    store.set("key1".to_owned(), "root".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    orders.set("key1".to_owned(), "order".to_owned())?;
+    orders.set("key2".to_owned(), "order".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert_eq!(orders.get("key1".to_owned())?, Some("order".to_owned()));
+
+    // Dropping one tree does not affect others
+    drop(users);
+    assert_eq!(orders.get("key2".to_owned())?, Some("order".to_owned()));
+    drop(orders);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.open_tree("users")?.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(store.open_tree("orders")?.len(), 2);
+    assert!(store.open_tree("../users").is_err());
+
+    Ok(())
+}
+
+// Should share the tree between its handles and compact it along with the storage only
+#[test]
+fn trees_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let first = store.open_tree("users")?;
+    let second = store.open_tree("users")?;
+
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    first.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(second.stats().unused_records, 1);
+
+    // Dropping the tree handle does not compact the tree
+    drop(first);
+    assert_eq!(second.stats().compactions, 0);
+    assert_eq!(store.open_tree("users")?.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.compact()?;
+    assert_eq!(second.stats().compactions, 1);
+    assert_eq!(second.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(second);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.open_tree("users")?.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should count records and compactions in statistics
+#[test]
+fn stats_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    for iter in 0..4 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.records, 5);
+    assert_eq!(stats.unused_records, 4);
+    assert_eq!(stats.passive_files, 0);
+    assert_eq!(stats.compactions, 0);
+
+    // Compaction triggered
+    store.set("key".to_owned(), "value".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.passive_files, 1);
+    assert_eq!(stats.compactions, 1);
+
+    Ok(())
+}
+
+// Should compact the log on demand without changing stored values
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Nothing to compact
+    store.compact()?;
+    assert_eq!(store.stats().compactions, 1);
+    assert!(store.is_empty());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.stats().unused_records, 2);
+
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.unused_records, 0);
+    assert_eq!(stats.records, 2);
+    assert_eq!(stats.compactions, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should rewrite only passive datafiles by default if the active datafile is limited,
+// records of the active datafile should stay in place, keep their locations and unused ones stay counted
+#[test]
+fn compaction_without_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let config = KvStoreConfig {
+        max_active_bytes: Some(1024 * 1024),
+        ..KvStoreConfig::default()
+    };
+    assert!(!config.dump_on_compaction);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..4 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // Nothing to rewrite, the active datafile is not dumped
+    store.compact()?;
+    assert_eq!(store.stats().passive_files, 0);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+
+    store.rotate()?;
+    store.set("key0".to_owned(), "new value".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key4".to_owned(), "old value".to_owned())?;
+    store.set("key4".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats().unused_records, 3);
+    let active_len = std::fs::metadata(&active_path)?.len();
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.passive_files, 1);
+    assert_eq!(stats.live_keys, 4);
+    // key2 and key3 are rewritten, key0, the removal of key1 and both key4 stay in the active datafile
+    assert_eq!(stats.records, 6);
+    // The overwritten key4 is not reclaimed
+    assert_eq!(stats.unused_records, 1);
+    assert_eq!(std::fs::metadata(&active_path)?.len(), active_len);
+    assert_eq!(store.get("key0".to_owned())?, Some("new value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value".to_owned()));
+
+    // Writes after compaction are appended to the same active datafile
+    store.set("key5".to_owned(), "value".to_owned())?;
+    assert!(std::fs::metadata(&active_path)?.len() > active_len);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should keep locations of keys of both passive and active datafiles consistent after compaction without dump,
+// and export all of them
+#[test]
+fn compaction_without_dump_locations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_active_bytes: Some(1024 * 1024),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..10 {
+        store.set(format!("passive{}", key_id), format!("old value{}", key_id))?;
+        store.set(format!("passive{}", key_id), format!("value{}", key_id))?;
+    }
+    store.rotate()?;
+    for key_id in 0..10 {
+        store.set(format!("active{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    assert_eq!(store.stats().passive_files, 1);
+
+    for key_id in 0..10 {
+        let (value, passive) = store.peek(format!("passive{}", key_id))?.unwrap();
+        assert_eq!(value, format!("value{}", key_id));
+        assert_eq!(passive.serial_number, Some(1));
+        let (value, active) = store.peek(format!("active{}", key_id))?.unwrap();
+        assert_eq!(value, format!("value{}", key_id));
+        assert_eq!(active.serial_number, None);
+        assert_eq!(store.get(format!("passive{}", key_id))?, Some(format!("value{}", key_id)));
+        assert_eq!(store.get(format!("active{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    let mut snapshot = Vec::new();
+    store.export(&mut snapshot)?;
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_store = KvStore::open(other_dir.path())?;
+    other_store.import(snapshot.as_slice())?;
+    assert_eq!(other_store.len(), 20);
+    for key_id in 0..10 {
+        assert_eq!(other_store.get(format!("passive{}", key_id))?, Some(format!("value{}", key_id)));
+        assert_eq!(other_store.get(format!("active{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Unused records left in the active datafile by compaction without dumping should not trigger
+// compaction again, only records made after it should
+#[test]
+fn compaction_trigger_without_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        max_active_bytes: Some(1024 * 1024),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    for iter in 0..20 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    let stats = store.stats();
+    // Triggered by the 5th, 10th, 15th and 20th unused record
+    assert_eq!(stats.compactions, 4);
+    assert_eq!(stats.unused_records, 20);
+    assert_eq!(stats.passive_files, 0);
+    assert_eq!(store.get("key".to_owned())?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+// Should index compacted datafiles by hints the same way as by their records
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        records_in_compacted: Some(3),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    for iter in 0..5 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let hints = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "hint"))
+        .map(|entry| entry.path().to_path_buf())
+        .collect::<Vec<_>>();
+    assert_eq!(hints.len(), 3);
+
+    let contents = |store: &KvStore| -> Result<Vec<(String, Option<String>)>> {
+        let mut keys = store.keys().collect::<Vec<_>>();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| Ok((key.clone(), store.get(key)?)))
+            .collect()
+    };
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let hinted = contents(&store)?;
+    assert_eq!(hinted.len(), 9);
+    assert_eq!(store.get("key1".to_owned())?, Some("4".to_owned()));
+    drop(store);
+
+    // Full reindex without hints
+    for hint in hints {
+        std::fs::remove_file(hint)?;
+    }
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(contents(&store)?, hinted);
+
+    Ok(())
+}
+
+// Should dump the active datafile after exceeding the configured size
+#[test]
+fn max_active_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_active_bytes: Some(1024),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let value = "v".repeat(600);
+
+    // Unique keys never trigger compaction
+    store.set("key0".to_owned(), value.clone())?;
+    assert_eq!(store.stats().passive_files, 0);
+    store.set("key1".to_owned(), value.clone())?;
+    assert_eq!(store.stats().passive_files, 1);
+    for key_id in 2..6 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.passive_files, 3);
+    assert_eq!(stats.compactions, 0);
+
+    for key_id in 0..6 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..6 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Concurrent increments of the same key should not be lost
+#[test]
+fn concurrent_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_concurrent_increment(&store)
+}
+
+// Should remove all keys without reopening
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for iter in 0..5 {
+        store.set("key0".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.stats().passive_files > 0);
+
+    store.clear()?;
+    assert_eq!(store.len(), 0);
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    let stats = store.stats();
+    assert_eq!(stats.records, 0);
+    assert_eq!(stats.unused_records, 0);
+    assert_eq!(stats.passive_files, 0);
+
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Errors should be convertible to boxed standard errors keeping their messages and sources
+#[test]
+fn std_error() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let error: Box<dyn std::error::Error> = Box::new(store.remove("missing".to_owned()).unwrap_err());
+    assert_eq!(error.to_string(), "Key not found");
+    assert!(error.source().is_none());
+
+    let io_error = std::io::Error::new(std::io::ErrorKind::Other, "disk");
+    let error: Box<dyn std::error::Error> = Box::new(KvError::from(io_error));
+    assert_eq!(error.to_string(), "Storage File Error: disk");
+    assert!(error.source().is_some());
+
+    Ok(())
+}
+
+// Should check presence of keys by the index
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+    assert!(!store.contains_key("key3")?);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+
+    Ok(())
+}
+
+// Should remove only keys with the given prefix
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("user:1:name".to_owned(), "value2".to_owned())?;
+    store.set("user:12".to_owned(), "value3".to_owned())?;
+    store.set("user:2".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(store.remove_prefix("user:1:")?, 1);
+    assert_eq!(store.get("user:1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("user:1:name".to_owned())?, None);
+    assert_eq!(store.get("user:12".to_owned())?, Some("value3".to_owned()));
+
+    assert_eq!(store.remove_prefix("user:1")?, 2);
+    assert_eq!(store.remove_prefix("unknown")?, 0);
+    assert_eq!(store.stats().unused_records, 3);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let keys: Vec<String> = store.keys().collect();
+    assert_eq!(keys, vec!["user:2".to_owned()]);
+
+    Ok(())
+}
+
+// Should restore all keys from the exported snapshot
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // Keys of both passive and active datafiles are exported
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    assert!(store.stats().passive_files > 0);
+    for i in 50..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl("expired".to_owned(), "value".to_owned(), Duration::from_millis(0))?;
+
+    let mut snapshot = Vec::new();
+    store.export(&mut snapshot)?;
+
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let imported = KvStore::open(import_dir.path())?;
+    imported.import(snapshot.as_slice())?;
+
+    let mut keys: Vec<String> = imported.keys().collect();
+    keys.sort();
+    let mut expected: Vec<String> = (1..100).map(|i| format!("key{}", i)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+    for key in keys {
+        assert_eq!(imported.get(key.clone())?, store.get(key)?);
+    }
+
+    // Snapshot is imported only into the empty storage
+    match imported.import(snapshot.as_slice()) {
+        Err(KvError::NotEmpty) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Corrupt length of the entry is not allocated upfront
+    let corrupt_dir = TempDir::new().expect("unable to create temporary working directory");
+    let corrupt = KvStore::open(corrupt_dir.path())?;
+    let mut snapshot = u32::MAX.to_le_bytes().to_vec();
+    snapshot.extend_from_slice(br#"{"Set":{"key":"key1","#);
+    assert!(corrupt.import(snapshot.as_slice()).is_err());
+    assert!(corrupt.is_empty());
+
+    Ok(())
+}
+
+// Dropping clones concurrently should not compact the storage, only the last instance should
+#[test]
+fn concurrent_drop_clones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+
+    let barrier = Arc::new(Barrier::new(16));
+    let handles = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                drop(store);
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.stats().compactions, 0);
+    assert_eq!(store.stats().unused_records, 9);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value9".to_owned()));
+    // Only the actual record is left by the compaction on dropping the last instance
+    assert_eq!(store.stats().records, 1);
+
+    Ok(())
+}
+
+// Storages with any number of index shards should behave the same
+#[test]
+fn index_shards() -> Result<()> {
+    for index_shards in vec![1, 3, 64] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig {
+            index_shards,
+            records_limit: 50,
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 40), format!("value{}", i))?;
+        }
+        for i in 0..10 {
+            store.remove(format!("key{}", i))?;
+        }
+        assert_eq!(store.len(), 30);
+        drop(store);
+
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.len(), 30);
+        assert_eq!(store.get("key5".to_owned())?, None);
+        assert_eq!(store.get("key19".to_owned())?, Some("value99".to_owned()));
+        assert_eq!(store.get("key10".to_owned())?, Some("value90".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Closing the last instance should compact the storage and return the error of compaction
+#[test]
+fn close_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let clone = store.clone();
+    clone.close()?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.stats().records, 1);
+
+    // The active datafile can't be dumped to the passive one occupied by the directory
+    store.set("key".to_owned(), "value10".to_owned())?;
+    let last_serial_number: u64 = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("passive".as_ref()))
+        .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+        .max()
+        .unwrap_or(0);
+    std::fs::create_dir(temp_dir.path().join(format!("{}.passive", last_serial_number + 1)))?;
+    assert!(store.close().is_err());
+
+    Ok(())
+}
+
+// Records synced on every write should be readable from the reopened storage
+#[test]
+fn fsync_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        durability: DurabilityMode::FsyncEveryWrite,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.stats().syncs, 3);
+
+    // Simulate the crash: the storage is not compacted on drop
+    std::mem::forget(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Concurrent writers should share syncs by group commit, their writes should be readable from the reopened storage
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        durability: DurabilityMode::FsyncEveryWrite,
+        group_commit: Some(Duration::from_millis(1)),
+        max_active_bytes: Some(4096),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let handles = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    store.set(format!("key{}_{}", thread_id, i), format!("value{}", i)).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // Every write is synced, but writers joining the group share the sync of its leader
+    let syncs = store.stats().syncs;
+    assert!(syncs > 0);
+    assert!(syncs < 8 * 50, "syncs are not shared: {}", syncs);
+
+    // Simulate the crash: the storage is not compacted on drop
+    std::mem::forget(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for thread_id in 0..8 {
+        for i in 0..50 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the total size of passive datafiles in `dir`.
+fn passive_files_len(dir: &std::path::Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("passive".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// Should compress records by gzip and keep reading uncompressed datafiles
+#[test]
+fn gzip_compression() -> Result<()> {
+    let value = "{\"name\": \"value\"}".repeat(100 * 1024 / 17);
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(plain_dir.path())?;
+    store.set("key".to_owned(), value.clone())?;
+    store.compact()?;
+    drop(store);
+
+    let gzip_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compression: Some(Compression::Gzip),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(gzip_dir.path(), config.clone())?;
+    store.set("key".to_owned(), value.clone())?;
+    store.compact()?;
+    drop(store);
+
+    assert!(passive_files_len(gzip_dir.path()) * 10 < passive_files_len(plain_dir.path()));
+
+    // Compression is restored from the manifest, compacted records are decompressed
+    let store = KvStore::open(gzip_dir.path())?;
+    assert_eq!(store.manifest().compression, Compression::Gzip);
+    assert_eq!(store.get("key".to_owned())?, Some(value.clone()));
+    drop(store);
+
+    // Compression of the existing storage is not changed by the config
+    match KvStore::open_with_config(plain_dir.path(), config) {
+        Err(KvError::ManifestMismatch { field, .. }) => assert_eq!(field, "compression"),
+        res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+    }
+    let store = KvStore::open(plain_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Should keep only the newest backups after compactions
+#[test]
+fn max_backups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backups_dir = TempDir::new().expect("unable to create temporary backups directory");
+    let config = KvStoreConfig {
+        max_backups: Some(2),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set_backups_dir(backups_dir.path());
+
+    let list_backups = || -> Vec<String> {
+        let mut backups: Vec<String> = std::fs::read_dir(backups_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        backups.sort();
+        backups
+    };
+
+    // Backups are created faster than the clock ticks, their names still differ
+    let mut created = Vec::new();
+    for iter in 0..5 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+        store.compact()?;
+        let backups = list_backups();
+        assert!(backups.len() <= 2);
+        created.push(backups.last().expect("no backup created").clone());
+    }
+    created.dedup();
+    assert_eq!(created.len(), 5);
+    assert_eq!(list_backups(), created[3..].to_vec());
+
+    Ok(())
+}
+
+// Index built from datafiles in parallel should match the sequential lazy indexing
+// and the written data: newer datafiles override older ones
+#[test]
+fn parallel_reindex() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_active_bytes: Some(256),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let mut expected = std::collections::HashMap::new();
+    for iter in 0..200 {
+        let key = format!("key{}", iter % 37);
+        if iter % 7 == 0 {
+            if expected.remove(&key).is_some() {
+                store.remove(key)?;
+            }
+        } else {
+            let value = format!("value{}", iter);
+            store.set(key.clone(), value.clone())?;
+            expected.insert(key, value);
+        }
+    }
+    assert!(store.stats().passive_files > 10);
+    // Simulate the crash: datafiles are not compacted on drop
+    std::mem::forget(store);
+
+    // Only one writer may open the directory, the storage indexed in parallel is opened for reading
+    let lazy_store = KvStore::open_with_config(
+        temp_dir.path(),
+        KvStoreConfig {
+            lazy_indexing: true,
+            ..config
+        },
+    )?;
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    for key_id in 0..37 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+        assert_eq!(lazy_store.get(key.clone())?, expected.get(&key).cloned());
+    }
+    let mut keys: Vec<String> = store.keys().collect();
+    keys.sort();
+    let mut expected_keys: Vec<String> = expected.keys().cloned().collect();
+    expected_keys.sort();
+    assert_eq!(keys, expected_keys);
+    std::mem::forget(lazy_store);
+
+    Ok(())
+}
+
+// Keys and values over limits should be rejected without changing the storage
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_key_bytes: Some(8),
+        max_value_bytes: Some(16),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key".to_owned(), "v".repeat(16))?;
+
+    match store.set("key".to_owned(), "v".repeat(17)) {
+        Err(KvError::ValueTooLarge { len: 17, max: 16 }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match store.set("k".repeat(9), "value".to_owned()) {
+        Err(KvError::KeyTooLarge { len: 9, max: 8 }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match store.set_with_ttl("key".to_owned(), "v".repeat(17), Duration::from_secs(60)) {
+        Err(KvError::ValueTooLarge { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    assert_eq!(store.get("key".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.get("k".repeat(9))?, None);
+    assert_eq!(store.stats().records, 1);
+
+    Ok(())
+}
+
+/// Get paths and contents of all files in `dir`.
+fn read_files(dir: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| (entry.path().to_owned(), std::fs::read(entry.path()).unwrap()))
+        .collect();
+    files.sort();
+    files
+}
+
+// Read-only storage should serve reads, reject writes and leave the directory untouched
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter % 4), format!("value{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    // Keep unused records in datafiles, so compaction on drop would change them
+    std::mem::forget(store);
+    let files = read_files(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    let other_store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(other_store.get("key3".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    match store.set("key1".to_owned(), "value".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match store.remove("key1".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match store.compact() {
+        Err(KvError::ReadOnly) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+    drop(other_store);
+
+    assert_eq!(read_files(temp_dir.path()), files);
+
+    Ok(())
+}
+
+// Tiered compaction should merge only the newest small passive files,
+// removals in merged files should keep overriding records of older files
+#[test]
+fn tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: u64::MAX,
+        compaction: CompactionStrategy::Tiered {
+            min_files: 3,
+            max_file_bytes: 4096,
+        },
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let value = "v".repeat(100);
+    for i in 0..100 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    // The large passive file is never merged
+    store.compact()?;
+    let large_file = temp_dir.path().join("1.passive");
+    let large_content = std::fs::read(&large_file).expect("unable to read passive file");
+    assert_eq!(store.stats().passive_files, 1);
+
+    store.set("key0".to_owned(), "a".to_owned())?;
+    store.compact()?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.stats().passive_files, 3);
+    assert_eq!(store.stats().compactions, 0);
+    assert_eq!(store.stats().unused_records, 2);
+
+    store.set("key2".to_owned(), "c".to_owned())?;
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.passive_files, 2);
+    assert_eq!(stats.compactions, 1);
+    // Overwritten and removed records of the large file are not reclaimed by merging
+    assert_eq!(stats.unused_records, 3);
+    assert!(stats.compacted_bytes > 0);
+    assert!(stats.compacted_bytes < large_content.len() as u64);
+    assert_eq!(std::fs::read(&large_file).expect("unable to read passive file"), large_content);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("a".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("c".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some(value.clone()));
+        assert_eq!(store.len(), 99);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    check(&store)
+}
+
+// Removed key should be absent from the index rebuilt by compaction
+#[test]
+fn reindex_after_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.len(), 9);
+    assert!(store.keys().all(|key| key != "key3"));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Stale index entry referring to the removal record should be reported as index corruption
+#[test]
+fn index_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    std::mem::forget(store);
+    let removal_offset = std::fs::metadata(&active_path).expect("unable to read datafile").len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    std::mem::forget(store);
+
+    // The stale hint refers to the removal record as to the value of the key
+    let passive_path = temp_dir.path().join("1.passive");
+    std::fs::rename(&active_path, &passive_path).expect("unable to move datafile");
+    let datafile_len = std::fs::metadata(&passive_path).expect("unable to read datafile").len();
+    let hint = format!(
+        r#"{{"datafile_len":{},"entries":[{{"key":"key1","offset":{},"expires_at":null}}]}}"#,
+        datafile_len, removal_offset
+    );
+    std::fs::write(temp_dir.path().join("1.hint"), hint).expect("unable to write hint");
+
+    let store = KvStore::open(temp_dir.path())?;
+    match store.get("key1".to_owned()) {
+        Err(KvError::IndexCorruption { key }) => assert_eq!(key, "key1"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+    std::mem::forget(store);
+    Ok(())
+}
+
+// Exactly one of threads racing to set the absent key should succeed
+#[test]
+fn concurrent_set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(32));
+    let handles: Vec<_> = (0..32)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.set_if_absent("key".to_owned(), format!("value{}", i)).unwrap()
+            })
+        })
+        .collect();
+    let winners: Vec<usize> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .enumerate()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(winners.len(), 1);
+    assert_eq!(store.get("key".to_owned())?, Some(format!("value{}", winners[0])));
+
+    assert!(!store.set_if_absent("key".to_owned(), "other".to_owned())?);
+    store.remove("key".to_owned())?;
+    assert!(store.set_if_absent("key".to_owned(), "other".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// Passive files should be sharded into subdirectories, the flat storage should be moved to shards
+// and back on opening with another layout
+#[test]
+fn passive_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let flat_config = KvStoreConfig {
+        records_in_compacted: Some(1),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), flat_config.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    drop(store);
+    assert!(temp_dir.path().join("1.passive").exists());
+
+    let sharded_config = KvStoreConfig {
+        passive_shards: Some(4),
+        ..flat_config.clone()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), sharded_config)?;
+    for shard in &["00", "01", "02", "03"] {
+        assert!(temp_dir.path().join("passive").join(shard).is_dir());
+    }
+    assert!(!temp_dir.path().join("1.passive").exists());
+    assert!(temp_dir.path().join("passive/01/1.passive").exists());
+    assert!(temp_dir.path().join("log.active").exists());
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    // The layout is restored from the manifest
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.manifest().passive_shards, 4);
+    for i in 0..11 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    let flat_config = KvStoreConfig {
+        passive_shards: Some(0),
+        ..flat_config
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), flat_config)?;
+    assert!(temp_dir.path().join("1.passive").exists());
+    for i in 0..11 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // The relayout interrupted by a crash is finished on opening in the layout of the manifest
+    let shard_dir = temp_dir.path().join("passive").join("01");
+    std::fs::create_dir_all(&shard_dir)?;
+    std::fs::rename(temp_dir.path().join("1.passive"), shard_dir.join("1.passive"))?;
+    std::fs::rename(temp_dir.path().join("1.hint"), shard_dir.join("1.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.manifest().passive_shards, 0);
+    assert!(temp_dir.path().join("1.passive").exists());
+    for i in 0..11 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should keep the last write after explicit flush without syncing on writes
+#[test]
+fn flush_no_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        durability: DurabilityMode::NoSync,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+
+    // Simulate the crash: the storage is not compacted on drop
+    std::mem::forget(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should estimate bytes of overwritten records and reclaim them by compaction
+#[test]
+fn reclaimable_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(store.reclaimable_bytes()?, 0);
+
+    let mut reclaimable = 0;
+    for round in 0..3 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", round))?;
+        }
+        let current = store.reclaimable_bytes()?;
+        assert!(current > reclaimable);
+        reclaimable = current;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.reclaimable_bytes()? > reclaimable);
+    assert_eq!(store.stats().reclaimable_bytes, store.reclaimable_bytes()?);
+
+    // Stats report the estimate of the last compaction without reading datafiles
+    store.compact()?;
+    assert_eq!(store.stats().reclaimable_bytes, 0);
+    assert_eq!(store.reclaimable_bytes()?, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should read values lazily through the live index, so changes and compaction
+// in the middle of the iteration are observed
+#[test]
+fn entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut entries = store.entries();
+    let mut seen = Vec::new();
+    for _ in 0..10 {
+        seen.push(entries.next().unwrap()?);
+    }
+    let pulled = seen.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+    let pending = (0..10000)
+        .map(|i| format!("key{}", i))
+        .filter(|key| !pulled.contains(key))
+        .collect::<Vec<_>>();
+    store.set(pending[0].clone(), "updated".to_owned())?;
+    store.remove(pending[1].clone())?;
+    store.compact()?;
+
+    for entry in entries {
+        let (key, value) = entry?;
+        if key == pending[0] {
+            assert_eq!(value, "updated");
+        } else {
+            assert_eq!(value, key.replace("key", "value"));
+        }
+        seen.push((key, value));
+    }
+    assert_eq!(seen.len(), 9999);
+    assert!(!seen.iter().any(|(key, _)| *key == pending[1]));
+    Ok(())
+}
+
+// Should apply batches atomically: a concurrent reader never observes a partial batch
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "value".to_owned())?;
+    store.write_batch(vec![
+        WriteOp::Set { key: "to".to_owned(), value: "value".to_owned() },
+        WriteOp::Remove { key: "from".to_owned() },
+        WriteOp::Remove { key: "absent".to_owned() },
+    ])?;
+    assert_eq!(store.get("from".to_owned())?, None);
+    assert_eq!(store.get("to".to_owned())?, Some("value".to_owned()));
+
+    store.set("first".to_owned(), "0".to_owned())?;
+    store.set("second".to_owned(), "0".to_owned())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=1000 {
+                store.write_batch(vec![
+                    WriteOp::Set { key: "second".to_owned(), value: i.to_string() },
+                    WriteOp::Set { key: "first".to_owned(), value: i.to_string() },
+                ])?;
+            }
+            Ok(())
+        })
+    };
+    // `first` is read after `second`, so it is never behind unless the batch is partially visible
+    loop {
+        let second: u64 = store.get("second".to_owned())?.unwrap().parse().unwrap();
+        let first: u64 = store.get("first".to_owned())?.unwrap().parse().unwrap();
+        assert!(first >= second, "partial batch: first {}, second {}", first, second);
+        if second == 1000 {
+            break;
+        }
+    }
+    writer.join().unwrap()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("first".to_owned())?, Some("1000".to_owned()));
+    assert_eq!(store.get("second".to_owned())?, Some("1000".to_owned()));
+    assert_eq!(store.get("from".to_owned())?, None);
+    Ok(())
+}
+
+// Should roll back the batch cut off by a crash while reopening
+#[test]
+fn truncated_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let active_path = temp_dir.path().join("log.active");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.write_batch(vec![
+        WriteOp::Set { key: "key1".to_owned(), value: "value1".to_owned() },
+        WriteOp::Set { key: "key2".to_owned(), value: "value2".to_owned() },
+        WriteOp::Remove { key: "key".to_owned() },
+    ])?;
+    // Crash without compaction
+    std::mem::forget(store);
+
+    // Cut the last record of the batch in the middle
+    let len = std::fs::metadata(&active_path)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&active_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should serve repeated reads from the value cache and never return values overwritten, removed
+// or evicted from it
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        cache_capacity: Some(2),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+    store.set("key0".to_owned(), "new_value".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new_value".to_owned()));
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    // The least recently used value is evicted by reading the new one
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (2, 8));
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().cache_misses, 9);
+    Ok(())
+}
+
+// Should keep the empty value distinct from the missing key through reopening and compaction
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("empty".to_owned(), String::new())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.set("key".to_owned(), String::new())?;
+    assert_eq!(store.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(store.get("key".to_owned())?, Some(String::new()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert!(store.contains_key("empty")?);
+    assert_eq!(store.len(), 2);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("empty".to_owned())?, Some(String::new()));
+    store.compact()?;
+    assert_eq!(store.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(store.get("key".to_owned())?, Some(String::new()));
+    store.remove("empty".to_owned())?;
+    assert_eq!(store.get("empty".to_owned())?, None);
+    Ok(())
+}
+
+// SledEngine should store the empty value and return it rather than `None`
+#[test]
+fn sled_empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledEngine::open(temp_dir.path())?;
+    engine.set("empty".to_owned(), String::new())?;
+    assert_eq!(engine.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    assert!(engine.compare_and_swap("empty".to_owned(), Some(String::new()), Some("value".to_owned()))?);
+    assert!(!engine.compare_and_swap("missing".to_owned(), Some(String::new()), Some("value".to_owned()))?);
+
+    drop(engine);
+    let engine = SledEngine::open(temp_dir.path())?;
+    engine.set("empty".to_owned(), String::new())?;
+    assert_eq!(engine.get("empty".to_owned())?, Some(String::new()));
+    Ok(())
+}
+
+// SledEngine opened with periodic flushes should keep writes flushed explicitly or on dropping
+#[test]
+fn sled_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = SledConfig {
+        durability: DurabilityMode::NoSync,
+        cache_capacity: Some(1024 * 1024),
+        flush_every_ms: Some(10),
+    };
+    let engine = SledEngine::open_with(temp_dir.path(), config.clone())?;
+    for iter in 0..100 {
+        engine.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    engine.flush()?;
+    engine.set("key0".to_owned(), "new".to_owned())?;
+
+    drop(engine);
+    let engine = SledEngine::open_with(temp_dir.path(), config)?;
+    assert_eq!(engine.len(), 100);
+    assert_eq!(engine.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Should compact records left by the unclean shutdown on opening, and only if they are reclaimable
+#[test]
+fn compact_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 1_000_000,
+        max_active_bytes: Some(4096),
+        dump_on_compaction: true,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    // Crash without compaction
+    std::mem::forget(store);
+    let inflated_len = passive_files_len(temp_dir.path());
+
+    let config = KvStoreConfig {
+        compact_on_open: true,
+        ..config
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.stats().compactions, 1);
+    assert!(passive_files_len(temp_dir.path()) < inflated_len / 10);
+    for i in 1990..2000 {
+        assert_eq!(store.get(format!("key{}", i % 10))?, Some(format!("value{}", i)));
+    }
+    std::mem::forget(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.stats().compactions, 0);
+    Ok(())
+}
+
+// Removing the absent key should return false without writing anything to the log
+#[test]
+fn remove_if_present() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let records = store.stats().records;
+    let files = read_files(temp_dir.path());
+
+    assert_eq!(store.remove_if_present("missing".to_owned())?, false);
+    assert_eq!(store.remove_if_present("missing".to_owned())?, false);
+    assert_eq!(store.stats().records, records);
+    assert_eq!(read_files(temp_dir.path()), files);
+
+    assert_eq!(store.remove_if_present("key".to_owned())?, true);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.stats().records, records + 1);
+    assert_eq!(store.remove_if_present("key".to_owned())?, false);
+    assert_eq!(store.stats().records, records + 1);
+
+    // The removal is persistent
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// Reading the removed datafile should fail with the error instead of a panic
+#[test]
+fn removed_datafile() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    for entry in WalkDir::new(temp_dir.path()).into_iter().filter_map(|entry| entry.ok()) {
+        if entry.path().extension() == Some("passive".as_ref()) {
+            std::fs::remove_file(entry.path()).unwrap();
+        }
+    }
+    match store.get("key1".to_owned()) {
+        Err(KvError::StorageFileError(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    // The storage is still usable for new records
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Storages with distinct names of files should share the directory without interference
+#[test]
+fn log_file_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 10,
+        ..KvStoreConfig::default()
+    };
+    let other_config = KvStoreConfig {
+        log: LogConfig {
+            active_file_name: "other.active".to_owned(),
+            passive_ext: "other_passive".to_owned(),
+            hint_ext: "other_hint".to_owned(),
+            manifest_file_name: "OTHER_MANIFEST".to_owned(),
+        },
+        ..config.clone()
+    };
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let other_store = KvStore::open_with_config(temp_dir.path(), other_config.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            other_store.set(format!("key{}", key_id), format!("other{}", iter))?;
+        }
+    }
+    other_store.set("other_key".to_owned(), "value".to_owned())?;
+    assert!(store.stats().compactions > 0);
+    assert!(other_store.stats().compactions > 0);
+    assert!(temp_dir.path().join("other.active").exists());
+    assert!(temp_dir.path().join("OTHER_MANIFEST").exists());
+    assert!(temp_dir.path().join("1.other_passive").exists());
+
+    // Clearing one storage leaves the other one intact
+    store.clear()?;
+    drop(store);
+    drop(other_store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let other_store = KvStore::open_with_config(temp_dir.path(), other_config)?;
+    assert_eq!(store.len(), 0);
+    assert_eq!(other_store.len(), 11);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+        assert_eq!(other_store.get(format!("key{}", key_id))?, Some("other19".to_owned()));
+    }
+    Ok(())
+}
+
+// Files and directories which are not named by serial numbers should be ignored and left intact
+#[test]
+fn foreign_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let foreign_files = ["junk.passive", "+1.passive", "1.5.passive", ".hidden", "notes.hint"];
+    for name in &foreign_files {
+        std::fs::write(temp_dir.path().join(name), "junk").unwrap();
+    }
+    std::fs::create_dir(temp_dir.path().join("backup.passive")).unwrap();
+    std::fs::create_dir(temp_dir.path().join("7.passive")).unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().passive_files, 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.stats().passive_files, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let config = KvStoreConfig {
+        passive_shards: Some(4),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.clear()?;
+    assert_eq!(store.len(), 0);
+
+    for name in &foreign_files {
+        assert!(temp_dir.path().join(name).is_file(), "{} is removed", name);
+    }
+    assert!(temp_dir.path().join("backup.passive").is_dir());
+    assert!(temp_dir.path().join("7.passive").is_dir());
+    Ok(())
+}
+
+// Key written by one clone should be read by another clone after the fence,
+// while the log is compacted and dumped concurrently
+#[test]
+fn fence_read_your_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_limit: 4,
+        max_active_bytes: Some(256),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let writer = store.clone();
+    let writer_handle = thread::spawn(move || -> Result<()> {
+        for iter in 0..200 {
+            writer.set(format!("key{}", iter), format!("value{}", iter))?;
+            // Overwrite the previous key to make unused records
+            writer.set(format!("key{}", iter), format!("value{}", iter + 1))?;
+            writer.fence();
+            sender.send(iter).unwrap();
+        }
+        Ok(())
+    });
+    let compactor = store.clone();
+    let compactor_handle = thread::spawn(move || -> Result<()> {
+        for _ in 0..50 {
+            compactor.compact()?;
+        }
+        Ok(())
+    });
+
+    let reader = store.clone();
+    for iter in receiver {
+        assert_eq!(reader.get(format!("key{}", iter))?, Some(format!("value{}", iter + 1)));
+    }
+    writer_handle.join().unwrap()?;
+    compactor_handle.join().unwrap()?;
+
+    Ok(())
+}
+
+// Bulk loading should leave the same state as setting pairs in order,
+// including overwritten keys of the storage and repeated keys of the loaded pairs
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_in_compacted: Some(10),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set("old".to_owned(), "value".to_owned())?;
+    store.remove("old".to_owned())?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+
+    let pairs = (0..1000)
+        .map(|iter| (format!("key{}", iter % 300), format!("value{}", iter)))
+        .collect::<Vec<_>>();
+    store.bulk_load(pairs)?;
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.len(), 301);
+        for key in 0..300 {
+            let last = if key < 100 { 900 + key } else { 600 + key };
+            assert_eq!(store.get(format!("key{}", key))?, Some(format!("value{}", last)));
+        }
+        assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("old".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    store.set("key1".to_owned(), "value901".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // Nothing is loaded if any pair exceeds limits
+    let config = KvStoreConfig {
+        max_value_bytes: Some(8),
+        ..KvStoreConfig::default()
+    };
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let pairs = vec![("key0".to_owned(), "short".to_owned()), ("key1".to_owned(), "too long value".to_owned())];
+    assert!(matches!(store.bulk_load(pairs), Err(KvError::ValueTooLarge { .. })));
+    assert_eq!(store.get("key0".to_owned())?, Some("value900".to_owned()));
+
+    Ok(())
+}
+
+// Bulk loading interrupted by a crash should be dropped before the rename of its staging directory
+// and finished after it, stale temporary files should be removed on opening
+#[test]
+fn bulk_load_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        records_in_compacted: Some(10),
+        ..KvStoreConfig::default()
+    };
+    let pairs = (0..100)
+        .map(|iter| (format!("key{}", iter), format!("value{}", iter)))
+        .collect::<Vec<_>>();
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.bulk_load(pairs)?;
+    assert!(!temp_dir.path().join("bulk_load.tmp").exists());
+    assert!(!temp_dir.path().join("bulk_load.ready").exists());
+    // The crash leaves datafiles as they are
+    std::mem::forget(store);
+
+    // Datafiles of the loading are moved back as if the crash happened before they were moved in place
+    let move_loaded = |dir_name: &str| -> Result<()> {
+        let dir = temp_dir.path().join(dir_name);
+        std::fs::create_dir(&dir)?;
+        for entry in std::fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "passive" || ext == "hint") {
+                std::fs::rename(&path, dir.join(path.file_name().unwrap()))?;
+            }
+        }
+        Ok(())
+    };
+    move_loaded("bulk_load.ready")?;
+    std::fs::write(temp_dir.path().join("11.passive.tmp"), "partial")?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert!(!temp_dir.path().join("bulk_load.ready").exists());
+    assert!(!temp_dir.path().join("11.passive.tmp").exists());
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    std::mem::forget(store);
+
+    // The loading is not committed before the rename of its staging directory
+    move_loaded("bulk_load.tmp")?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(!temp_dir.path().join("bulk_load.tmp").exists());
+    assert_eq!(store.len(), 0);
+    assert_eq!(store.get("key42".to_owned())?, None);
+
+    Ok(())
+}
+
+// Rotation should turn the active datafile into the new passive one while keys are read and written
+#[test]
+fn rotate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    let passive_files = store.stats().passive_files;
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for iter in 100..200 {
+            writer.set(format!("key{}", iter), format!("value{}", iter))?;
+            assert!(writer.get(format!("key{}", iter - 100))?.is_some());
+        }
+        Ok(())
+    });
+    store.rotate()?;
+    handle.join().unwrap()?;
+    assert!(temp_dir.path().join(format!("{}.passive", passive_files + 1)).is_file());
+    assert!(store.stats().passive_files > passive_files);
+
+    // Rotation of the empty active datafile does nothing
+    store.rotate()?;
+    let stats = store.stats();
+    store.rotate()?;
+    assert_eq!(store.stats().passive_files, stats.passive_files);
+
+    for iter in 1..200 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some(format!("value{}", iter)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+
+    Ok(())
+}
+
+// Should report the datafile and the offset of the value, which is kept by the dump of the active datafile
+#[test]
+fn peek() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.peek("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (value, active) = store.peek("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert_eq!(active.serial_number, None);
+    assert!(active.path.is_file());
+    let (_, next) = store.peek("key2".to_owned())?.unwrap();
+    assert!(next.offset > active.offset);
+
+    store.rotate()?;
+    let (value, passive) = store.peek("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert!(passive.serial_number.is_some());
+    assert_eq!(passive.offset, active.offset);
+    assert_eq!(passive.path, temp_dir.path().join(format!("{}.passive", passive.serial_number.unwrap())));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.peek("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should take serial numbers of passive datafiles from their names, the active datafile has none
+#[test]
+fn datafile_serial_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("42.passive"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    std::fs::write(
+        temp_dir.path().join("+42.passive"),
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let (_, passive) = store.peek("key1".to_owned())?.unwrap();
+    assert_eq!(passive.serial_number, Some(42));
+    assert_eq!(passive.path, temp_dir.path().join("42.passive"));
+    let (_, active) = store.peek("key3".to_owned())?.unwrap();
+    assert_eq!(active.serial_number, None);
+    assert_eq!(active.path, temp_dir.path().join("log.active"));
+    assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
@@ -0,0 +1,449 @@
+use kvs::protocol::{Address, AdminOp, Request, Response, ResponseError};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool, ThreadPoolKind};
+use kvs::{Client, DurabilityMode, EngineKind, KvError, KvStore, KvsEngine, Result, Server, ServerConfig};
+use std::net::SocketAddr;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Engine serving every request slowly.
+#[derive(Clone)]
+struct SlowEngine;
+
+const DELAY: Duration = Duration::from_millis(1000);
+
+impl KvsEngine for SlowEngine {
+    fn open(_path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(SlowEngine)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        thread::sleep(DELAY);
+        Ok(Some(key))
+    }
+
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        thread::sleep(DELAY);
+        Ok(())
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        thread::sleep(DELAY);
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, _key: String, _expected: Option<String>, _new: Option<String>) -> Result<bool> {
+        thread::sleep(DELAY);
+        Ok(true)
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+}
+
+/// Engine failing every request as if its storage is broken.
+#[derive(Clone)]
+struct BrokenEngine;
+
+fn broken<T>() -> Result<T> {
+    Err(KvError::ReadOnly)
+}
+
+impl KvsEngine for BrokenEngine {
+    fn open(_path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(BrokenEngine)
+    }
+
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        broken()
+    }
+
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        broken()
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        broken()
+    }
+
+    fn compare_and_swap(&self, _key: String, _expected: Option<String>, _new: Option<String>) -> Result<bool> {
+        broken()
+    }
+
+    fn len(&self) -> usize {
+        panic!("storage is broken")
+    }
+}
+
+// Shutdown should wait for the in-flight request to complete
+#[test]
+fn shutdown_drains_requests() {
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), SlowEngine);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client_thread = thread::spawn(move || Client::new(addr).get("key".to_owned()));
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    shutdown_handle.shutdown();
+    assert!(start.elapsed() >= DELAY / 2);
+
+    match client_thread.join().unwrap().unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "key"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    server_thread.join().unwrap().unwrap();
+}
+
+// Server should return from `run` only after the in-flight request is served
+// even if it is stopped on another thread, e.g. by the SIGINT handler
+#[test]
+fn run_drains_requests() {
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), SlowEngine);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client_thread = thread::spawn(move || Client::new(addr).get("key".to_owned()));
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let handler_thread = thread::spawn(move || shutdown_handle.shutdown());
+    server_thread.join().unwrap().unwrap();
+    assert!(start.elapsed() >= DELAY / 2);
+
+    match client_thread.join().unwrap().unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "key"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    handler_thread.join().unwrap();
+}
+
+// Quiescing should block until all slow connections are served, the server should keep running
+#[test]
+fn quiesce_waits_for_connections() {
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), SlowEngine);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client_threads = (0..3)
+        .map(|client_id| {
+            let client = Client::new(addr.clone());
+            thread::spawn(move || client.get(format!("key{}", client_id)))
+        })
+        .collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    shutdown_handle.quiesce();
+    assert!(start.elapsed() >= DELAY / 2);
+    for (client_id, client_thread) in client_threads.into_iter().enumerate() {
+        match client_thread.join().unwrap().unwrap() {
+            Response::Ok(Some(value)) => assert_eq!(value, format!("key{}", client_id)),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    // Nothing to wait for, new connections are still accepted
+    let start = Instant::now();
+    shutdown_handle.quiesce();
+    assert!(start.elapsed() < DELAY / 2);
+    Client::new(addr).ping().unwrap();
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Connections over the limit should wait until a served connection is closed
+#[test]
+fn max_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), store)
+        .with_max_connections(2);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client = Client::new(addr.clone());
+    let mut first = client.connect().unwrap();
+    let mut second = client.connect().unwrap();
+    for session in vec![&mut first, &mut second] {
+        match session.send(Request::Ping).unwrap() {
+            Response::Pong => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    // The third connection is queued, its request is not served until a slot is released
+    let (sender, receiver) = mpsc::channel();
+    let queued_thread = thread::spawn(move || {
+        let client = Client::new(addr);
+        let response = client.connect().and_then(|mut session| session.send(Request::Ping));
+        sender.send(response.map(|_| ())).unwrap();
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+
+    drop(first);
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    queued_thread.join().unwrap();
+    drop(second);
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Ping should succeed without touching the broken storage
+#[test]
+fn ping_broken_storage() {
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), BrokenEngine);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client = Client::new(addr);
+    client.ping().unwrap();
+    match client.set("key".to_owned(), "value".to_owned()).unwrap() {
+        Response::Err(_) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    client.ping().unwrap();
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Admin operations should be applied with the token of the server only
+#[test]
+fn admin_operations() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), store)
+        .with_admin_token("secret");
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client = Client::new(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // Plain compaction and stats are admin operations of the server having the token
+    for response in vec![client.compact().unwrap(), client.stats().unwrap()] {
+        match response {
+            Response::Err(ResponseError::Unauthorized) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+    match client.batch(vec![Request::Compact]).unwrap().remove(0) {
+        Response::Err(ResponseError::Unauthorized) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    // Metrics expose counters of requests, but not stats of the storage
+    match client.metrics().unwrap() {
+        Response::Text(text) => {
+            assert!(text.lines().any(|line| line == "kvs_sets_total 1"));
+            for name in &["kvs_compactions_total", "kvs_live_keys", "kvs_passive_files", "kvs_reclaimable_bytes"] {
+                assert!(!text.contains(name), "{} is exposed", name);
+            }
+        }
+        response => panic!("unexpected response: {:?}", response),
+    }
+    for token in &["wrong", "", "secret!"] {
+        match client.admin(token.to_string(), AdminOp::Clear).unwrap() {
+            Response::Err(ResponseError::Unauthorized) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+    match client.admin("secret".to_owned(), AdminOp::Stats).unwrap() {
+        Response::Stats(stats) => assert_eq!(stats.live_keys, 1),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.admin("secret".to_owned(), AdminOp::Clear).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Server should stop cleanly even if its socket file is already removed
+#[cfg(unix)]
+#[test]
+fn removed_socket_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::new(Address::Unix(socket_path.clone()), NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    Client::new(socket_path.clone()).ping().unwrap();
+    fs::remove_file(&socket_path).unwrap();
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Idle server should still accept connections and stop promptly on shutdown
+#[test]
+fn idle_server_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::new(Address::Tcp("127.0.0.1:0".parse().unwrap()), NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    thread::sleep(Duration::from_millis(300));
+    let client = Client::new(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let start = Instant::now();
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+// Server should accept connections on all its addresses and serve them by the same engine
+#[test]
+fn multiple_addresses() {
+    let temp_dir = TempDir::new().unwrap();
+    let addrs = vec![
+        Address::Tcp("127.0.0.1:0".parse().unwrap()),
+        Address::Tcp("[::1]:0".parse().unwrap()),
+    ];
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::with_addrs(addrs, NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let local_addrs = server.local_addrs().unwrap();
+    assert_eq!(local_addrs.len(), 2);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let ipv4_client = Client::new(local_addrs[0].clone());
+    let ipv6_client = Client::new(local_addrs[1].clone());
+    ipv4_client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    ipv6_client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    for client in &[&ipv4_client, &ipv6_client] {
+        match client.mget(vec!["key1".to_owned(), "key2".to_owned()]).unwrap() {
+            Response::Values(values) => {
+                assert_eq!(values, vec![Some("value1".to_owned()), Some("value2".to_owned())])
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
+
+// Config should be read from the TOML file and overridden by set settings only
+#[test]
+fn server_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &path,
+        r#"
+addr = ["127.0.0.1:4100", "[::1]:4100"]
+engine = "sled"
+thread_pool = "Queue"
+threads = 4
+durability = "every-100"
+"#,
+    )
+    .unwrap();
+
+    let file_config = ServerConfig::from_file(&path).unwrap();
+    assert_eq!(file_config.addrs().len(), 2);
+    assert_eq!(file_config.engine(), EngineKind::Sled);
+    assert_eq!(file_config.thread_pool(), ThreadPoolKind::Queue);
+    assert_eq!(file_config.threads(), 4);
+    assert_eq!(file_config.durability, Some(DurabilityMode::FsyncEveryN(100)));
+    assert_eq!(file_config.records_limit, None);
+
+    let flags_config = ServerConfig {
+        engine: Some(EngineKind::Kvs),
+        threads: Some(16),
+        records_limit: Some(1000),
+        ..ServerConfig::default()
+    };
+    let config = file_config.overridden_by(flags_config);
+    assert_eq!(config.addrs(), vec!["127.0.0.1:4100".parse().unwrap(), "[::1]:4100".parse().unwrap()]);
+    assert_eq!(config.engine(), EngineKind::Kvs);
+    assert_eq!(config.thread_pool(), ThreadPoolKind::Queue);
+    assert_eq!(config.threads(), 16);
+    assert_eq!(config.durability, Some(DurabilityMode::FsyncEveryN(100)));
+    assert_eq!(config.records_limit, Some(1000));
+
+    // Unset settings are resolved to defaults
+    let config = ServerConfig::from_toml("").unwrap();
+    assert_eq!(config.addrs(), vec!["127.0.0.1:4000".parse::<SocketAddr>().unwrap()]);
+    assert_eq!(config.engine(), EngineKind::Kvs);
+    assert_eq!(config.thread_pool(), ThreadPoolKind::Rayon);
+    assert_eq!(config.threads(), 8);
+    assert_eq!(config.durability, None);
+
+    for content in &["engine = \"rocksdb\"", "durability = \"every-0\"", "threads = \"many\"", "port = 4000"] {
+        match ServerConfig::from_toml(content) {
+            Err(KvError::InvalidConfig(_)) => {}
+            result => panic!("unexpected result for {}: {:?}", content, result),
+        }
+    }
+}
+
+// Client should set, get and remove values of the server bound to an ephemeral port
+#[test]
+fn round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = Server::new("127.0.0.1:0".parse::<SocketAddr>().unwrap(), NaiveThreadPool::new(4), store);
+    server.bind().unwrap();
+    let addr = server.local_addrs().unwrap().remove(0);
+    let shutdown_handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run());
+
+    let client = Client::new(addr);
+    match client.set("key1".to_owned(), "value1".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(Some(value)) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.rm("key1".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.get("key1".to_owned()).unwrap() {
+        Response::Ok(None) => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+    match client.rm("key1".to_owned()).unwrap() {
+        Response::NotFound => {}
+        response => panic!("unexpected response: {:?}", response),
+    }
+
+    shutdown_handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
@@ -0,0 +1,97 @@
+use kvs::{KvError, KvStore, KvsEngine, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+/// Allocator tracking the peak of allocated bytes, so buffering of values is detected.
+/// Only one allocator can be set per process, so the test is alone in the file.
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+const VALUE_LEN: usize = 8 * 1024 * 1024;
+const MAX_OVERHEAD: usize = 1024 * 1024;
+
+/// Reset the peak to the currently allocated bytes and return them.
+fn reset_peak() -> usize {
+    let allocated = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(allocated, Ordering::SeqCst);
+    allocated
+}
+
+// Should round-trip the multi-megabyte value through `set_reader` and `get_writer`
+// without buffering the whole value, also after reopening and compaction
+#[test]
+fn streamed_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // Multibyte characters are split between chunks the value is copied by
+    let value = "streamed value ½ ∑ 🦀 "
+        .chars()
+        .cycle()
+        .scan(0, |len, c| {
+            *len += c.len_utf8();
+            if *len <= VALUE_LEN { Some(c) } else { None }
+        })
+        .collect::<String>();
+    let mut output = Vec::with_capacity(value.len());
+
+    let baseline = reset_peak();
+    store.set_reader("key".to_owned(), Cursor::new(value.as_bytes()), value.len() as u64)?;
+    assert_eq!(store.get_writer("key".to_owned(), &mut output)?, Some(value.len() as u64));
+    let overhead = PEAK.load(Ordering::SeqCst) - baseline;
+    assert!(overhead < MAX_OVERHEAD, "{} bytes are allocated while streaming", overhead);
+    assert!(output == value.as_bytes());
+    assert!(store.get("key".to_owned())? == Some(value.clone()));
+
+    // Reindexing verifies the streamed value without buffering it
+    drop(store);
+    let baseline = reset_peak();
+    let store = KvStore::open(temp_dir.path())?;
+    output.clear();
+    assert_eq!(store.get_writer("key".to_owned(), &mut output)?, Some(value.len() as u64));
+    let overhead = PEAK.load(Ordering::SeqCst) - baseline;
+    assert!(overhead < MAX_OVERHEAD, "{} bytes are allocated while streaming", overhead);
+    assert!(output == value.as_bytes());
+
+    // Short and non-UTF-8 values are not stored, and records written after them are intact
+    let result = store.set_reader("short".to_owned(), Cursor::new("value"), 10);
+    assert!(matches!(result, Err(KvError::StorageFileError(_))));
+    let result = store.set_reader("binary".to_owned(), Cursor::new([0xF0, 0x9F, 0x80]), 3);
+    assert!(matches!(result, Err(KvError::StorageFileError(_))));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("binary".to_owned())?, None);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_writer("short".to_owned(), &mut output)?, None);
+    output.clear();
+    assert_eq!(store.get_writer("key".to_owned(), &mut output)?, Some(value.len() as u64));
+    assert!(output == value.as_bytes());
+
+    Ok(())
+}
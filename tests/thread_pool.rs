@@ -0,0 +1,82 @@
+use kvs::thread_pool::{NaiveThreadPool, QueueThreadPool, RayonThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// Panicking job should not stop the worker from executing next jobs
+#[test]
+fn queue_pool_panic_recovery() {
+    let pool = QueueThreadPool::new(1);
+    let (sender, receiver) = mpsc::channel();
+
+    pool.spawn(|| panic!("job panicked"));
+    pool.spawn(move || sender.send(42).unwrap());
+
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(42));
+    drop(pool);
+}
+
+// Bounded pool should refuse to queue jobs beyond its capacity
+#[test]
+fn bounded_queue_pool_full() {
+    let pool = QueueThreadPool::bounded(1, 2);
+    let (sender, receiver) = mpsc::channel();
+
+    // The worker is busy with the first job, next jobs are queued
+    pool.spawn(|| thread::sleep(Duration::from_millis(500)));
+    thread::sleep(Duration::from_millis(100));
+    for _ in 0..2 {
+        let sender = sender.clone();
+        assert!(pool.try_spawn(move || sender.send(()).unwrap()).is_ok());
+    }
+    let rejected = {
+        let sender = sender.clone();
+        pool.try_spawn(move || sender.send(()).unwrap())
+    };
+    let rejected = rejected.err().expect("job is queued in the full queue");
+
+    // Rejected job is returned intact and the queued ones are executed
+    pool.spawn(rejected);
+    for _ in 0..3 {
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(()));
+    }
+}
+
+// Dropping the pool should wait for all queued jobs
+#[test]
+fn queue_pool_drains_on_drop() {
+    let pool = QueueThreadPool::new(4);
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..100 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    drop(pool);
+
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+}
+
+// Handle of the job should return its result or its panic
+#[test]
+fn spawn_handle() {
+    fn check<P: ThreadPool>() {
+        let pool = P::new(2);
+        let value = pool.spawn_handle(|| (1..=10).sum::<u32>());
+        let panicked = pool.spawn_handle(|| -> u32 { panic!("job panicked") });
+
+        assert_eq!(value.join().unwrap(), 55);
+        let payload = panicked.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job panicked"));
+
+        // The pool is still usable after the panic
+        assert_eq!(pool.spawn_handle(|| 42).join().unwrap(), 42);
+    }
+    check::<NaiveThreadPool>();
+    check::<QueueThreadPool>();
+    check::<RayonThreadPool>();
+}
@@ -0,0 +1,54 @@
+use kvs::WaitGroup;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Tasks registered by add should be finished by done without cloning
+#[test]
+fn add_and_done() {
+    let wait_group = Arc::new(WaitGroup::new());
+    wait_group.add(4);
+    let handles = (0..4)
+        .map(|_| {
+            let wait_group = Arc::clone(&wait_group);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                wait_group.done();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    assert!(wait_group.wait_timeout(Duration::from_secs(5)));
+    wait_group.wait();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// Waiting should give up after the timeout if tasks are not done
+#[test]
+fn wait_timeout() {
+    let wait_group = WaitGroup::new();
+    wait_group.add(1);
+    let task = wait_group.clone();
+
+    let start = Instant::now();
+    assert!(!wait_group.wait_timeout(Duration::from_millis(200)));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // Both the added task and the clone must be finished
+    wait_group.done();
+    assert!(!wait_group.wait_timeout(Duration::from_millis(50)));
+    drop(task);
+    assert!(wait_group.wait_timeout(Duration::from_millis(50)));
+}
+
+// done without registered tasks should not make the counter negative
+#[test]
+#[should_panic]
+fn done_underflow() {
+    let wait_group = WaitGroup::new();
+    wait_group.add(1);
+    wait_group.done();
+    wait_group.done();
+}